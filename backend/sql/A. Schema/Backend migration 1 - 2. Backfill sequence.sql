@@ -0,0 +1 @@
+UPDATE inventory SET sequence = rowid
\ No newline at end of file
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS inventory_sequence ON inventory (sequence)
\ No newline at end of file
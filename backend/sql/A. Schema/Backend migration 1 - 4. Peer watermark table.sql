@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS peer_watermark (
+    peer BLOB PRIMARY KEY NOT NULL,
+    sequence INTEGER NOT NULL
+)
\ No newline at end of file
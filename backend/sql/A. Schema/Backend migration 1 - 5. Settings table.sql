@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY NOT NULL,
+    value NOT NULL
+)
\ No newline at end of file
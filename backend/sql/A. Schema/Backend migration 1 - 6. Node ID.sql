@@ -0,0 +1,4 @@
+INSERT
+    OR IGNORE INTO settings
+VALUES
+    ('node id', randomblob(32))
\ No newline at end of file
@@ -0,0 +1,7 @@
+INSERT
+    OR IGNORE INTO settings
+SELECT
+    'latest sequence',
+    COALESCE(MAX(sequence), 0)
+FROM
+    inventory
\ No newline at end of file
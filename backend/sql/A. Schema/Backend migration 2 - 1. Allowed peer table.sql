@@ -0,0 +1 @@
+CREATE TABLE IF NOT EXISTS allowed_peer (public_key BLOB PRIMARY KEY NOT NULL)
\ No newline at end of file
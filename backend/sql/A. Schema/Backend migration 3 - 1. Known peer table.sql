@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS known_peer (
+    address TEXT PRIMARY KEY NOT NULL,
+    last_seen INTEGER NOT NULL,
+    failures INTEGER NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1 @@
+PRAGMA user_version = 3
\ No newline at end of file
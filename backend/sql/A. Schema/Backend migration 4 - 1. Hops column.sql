@@ -0,0 +1 @@
+ALTER TABLE inventory ADD COLUMN hops INTEGER NOT NULL DEFAULT 0
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE inventory ADD COLUMN copies INTEGER
\ No newline at end of file
@@ -0,0 +1 @@
+PRAGMA user_version = 4
\ No newline at end of file
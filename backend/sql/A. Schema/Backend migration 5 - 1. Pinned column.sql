@@ -0,0 +1 @@
+ALTER TABLE inventory ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0
\ No newline at end of file
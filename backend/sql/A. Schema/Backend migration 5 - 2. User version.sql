@@ -0,0 +1 @@
+PRAGMA user_version = 5
\ No newline at end of file
@@ -0,0 +1 @@
+DELETE FROM allowed_peer WHERE public_key = ?
\ No newline at end of file
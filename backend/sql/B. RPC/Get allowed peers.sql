@@ -0,0 +1 @@
+SELECT public_key FROM allowed_peer
\ No newline at end of file
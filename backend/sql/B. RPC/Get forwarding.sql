@@ -0,0 +1 @@
+SELECT hops, copies FROM inventory WHERE blake2b = ?
\ No newline at end of file
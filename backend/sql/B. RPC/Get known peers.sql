@@ -0,0 +1,8 @@
+SELECT
+    address,
+    last_seen,
+    failures
+FROM
+    known_peer
+ORDER BY
+    last_seen DESC
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT sequence FROM peer_watermark WHERE peer = ?
\ No newline at end of file
@@ -0,0 +1 @@
+SELECT value FROM settings WHERE key = ?
\ No newline at end of file
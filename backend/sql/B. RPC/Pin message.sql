@@ -0,0 +1 @@
+UPDATE inventory SET pinned = 1 WHERE blake2b = ?
\ No newline at end of file
@@ -0,0 +1,5 @@
+DELETE FROM
+    known_peer
+WHERE
+    failures >= ?
+    OR last_seen < ?
\ No newline at end of file
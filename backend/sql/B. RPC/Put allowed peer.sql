@@ -0,0 +1,4 @@
+INSERT
+    OR IGNORE INTO allowed_peer
+VALUES
+    (?)
\ No newline at end of file
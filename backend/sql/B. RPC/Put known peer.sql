@@ -0,0 +1,7 @@
+INSERT INTO
+    known_peer
+VALUES
+    (?, ?, ?) ON CONFLICT(address) DO
+UPDATE
+SET
+    last_seen = max(last_seen, excluded.last_seen)
\ No newline at end of file
@@ -1,4 +1,4 @@
 INSERT
-    OR IGNORE INTO inventory
+    OR IGNORE INTO inventory (blake2b, payload, nonce, expiration_time, sequence, hops, copies)
 VALUES
-    (?, ?, ?, ?)
\ No newline at end of file
+    (?, ?, ?, ?, ?, ?, ?)
\ No newline at end of file
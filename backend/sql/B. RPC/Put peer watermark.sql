@@ -0,0 +1,4 @@
+INSERT
+    OR REPLACE INTO peer_watermark
+VALUES
+    (?, ?)
\ No newline at end of file
@@ -0,0 +1,4 @@
+INSERT
+    OR REPLACE INTO known_peer
+VALUES
+    (?, ?, 0)
\ No newline at end of file
@@ -0,0 +1,4 @@
+INSERT
+    OR REPLACE INTO settings
+VALUES
+    (?, ?)
\ No newline at end of file
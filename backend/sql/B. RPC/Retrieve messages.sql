@@ -1 +1 @@
-SELECT blake2b, payload, nonce, expiration_time FROM inventory
+SELECT blake2b, payload, nonce, expiration_time, sequence, pinned FROM inventory ORDER BY sequence
\ No newline at end of file
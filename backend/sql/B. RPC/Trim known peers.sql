@@ -0,0 +1,13 @@
+DELETE FROM
+    known_peer
+WHERE
+    address NOT IN (
+        SELECT
+            address
+        FROM
+            known_peer
+        ORDER BY
+            last_seen DESC
+        LIMIT
+            ?
+    )
\ No newline at end of file
@@ -0,0 +1 @@
+UPDATE inventory SET copies = ? WHERE blake2b = ?
\ No newline at end of file
@@ -0,0 +1,6 @@
+UPDATE
+    known_peer
+SET
+    failures = failures + 1
+WHERE
+    address = ?
\ No newline at end of file
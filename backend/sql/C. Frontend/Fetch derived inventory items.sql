@@ -0,0 +1,4 @@
+SELECT DISTINCT
+    derived_from
+FROM
+    message_content_derivation_table
\ No newline at end of file
@@ -0,0 +1,163 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AcceptancePolicy {
+    /// In bytes. Zero means that there is no limit.
+    pub max_payload_size: u64,
+    /// In seconds. Zero means that there is no limit.
+    pub max_time_to_live: i64,
+    /// In seconds.
+    pub min_time_to_live: i64,
+}
+
+impl Default for AcceptancePolicy {
+    fn default() -> AcceptancePolicy {
+        AcceptancePolicy {
+            max_payload_size: 8 * 1024 * 1024,
+            max_time_to_live: 30 * 86400,
+            min_time_to_live: 0,
+        }
+    }
+}
+
+impl AcceptancePolicy {
+    pub fn check(
+        &self,
+        payload_size: usize,
+        expiration_time: i64,
+        now: i64,
+    ) -> Result<(), Violation> {
+        self.check_stored(payload_size, expiration_time, now)?;
+        let time_to_live = expiration_time - now;
+        if time_to_live < self.min_time_to_live {
+            return Err(Violation::TimeToLiveTooShort {
+                time_to_live,
+                limit: self.min_time_to_live,
+            });
+        }
+        Ok(())
+    }
+
+    /// Stored messages age towards their expiration time, so the minimum
+    /// time-to-live doesn't apply to them.
+    pub fn check_stored(
+        &self,
+        payload_size: usize,
+        expiration_time: i64,
+        now: i64,
+    ) -> Result<(), Violation> {
+        let payload_size = payload_size as u64;
+        if self.max_payload_size != 0 && payload_size > self.max_payload_size {
+            return Err(Violation::PayloadTooLarge {
+                size: payload_size,
+                limit: self.max_payload_size,
+            });
+        }
+        let time_to_live = expiration_time - now;
+        if self.max_time_to_live != 0 && time_to_live > self.max_time_to_live {
+            return Err(Violation::TimeToLiveTooLong {
+                time_to_live,
+                limit: self.max_time_to_live,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Violation {
+    PayloadTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    TimeToLiveTooLong {
+        time_to_live: i64,
+        limit: i64,
+    },
+    TimeToLiveTooShort {
+        time_to_live: i64,
+        limit: i64,
+    },
+    /// Not part of the policy.
+    QuotaExceeded,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::PayloadTooLarge { size, limit } => write!(
+                f,
+                "Payload is {} bytes large, the limit is {} bytes",
+                size, limit
+            ),
+            Violation::TimeToLiveTooLong {
+                time_to_live,
+                limit,
+            } => write!(
+                f,
+                "Time-to-live is {} seconds, the limit is {} seconds",
+                time_to_live, limit
+            ),
+            Violation::TimeToLiveTooShort {
+                time_to_live,
+                limit,
+            } => write!(
+                f,
+                "Time-to-live is {} seconds, the minimum is {} seconds",
+                time_to_live, limit
+            ),
+            Violation::QuotaExceeded => write!(f, "Storage quota is exhausted"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_are_enforced() {
+        let policy = AcceptancePolicy {
+            max_payload_size: 100,
+            max_time_to_live: 1000,
+            min_time_to_live: 10,
+        };
+        assert_eq!(policy.check(100, 1000, 0), Ok(()));
+        assert_eq!(
+            policy.check(101, 1000, 0),
+            Err(Violation::PayloadTooLarge {
+                size: 101,
+                limit: 100
+            })
+        );
+        assert_eq!(
+            policy.check(100, 1001, 0),
+            Err(Violation::TimeToLiveTooLong {
+                time_to_live: 1001,
+                limit: 1000
+            })
+        );
+        assert_eq!(
+            policy.check(100, 5, 0),
+            Err(Violation::TimeToLiveTooShort {
+                time_to_live: 5,
+                limit: 10
+            })
+        );
+        assert_eq!(policy.check_stored(100, 5, 0), Ok(()));
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let policy = AcceptancePolicy {
+            max_payload_size: 0,
+            max_time_to_live: 0,
+            min_time_to_live: 0,
+        };
+        assert_eq!(
+            policy.check(usize::max_value(), i64::max_value(), 0),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::BTreeSet;
+
+/// Plaintext peers have no static key, so private networks always turn them
+/// away.
+pub struct Allowlist {
+    private_network: bool,
+    public_keys: BTreeSet<Vec<u8>>,
+}
+
+impl Allowlist {
+    pub fn new(private_network: bool, public_keys: Vec<Vec<u8>>) -> Allowlist {
+        Allowlist {
+            private_network,
+            public_keys: public_keys.into_iter().collect(),
+        }
+    }
+
+    pub fn private_network(&self) -> bool {
+        self.private_network
+    }
+
+    pub fn add(&mut self, public_key: Vec<u8>) -> bool {
+        self.public_keys.insert(public_key)
+    }
+
+    pub fn remove(&mut self, public_key: &[u8]) -> bool {
+        self.public_keys.remove(public_key)
+    }
+
+    pub fn list(&self) -> Vec<Vec<u8>> {
+        self.public_keys.iter().cloned().collect()
+    }
+
+    pub fn admits(&self, remote_static_key: Option<&[u8]>) -> bool {
+        if !self.private_network {
+            return true;
+        }
+        match remote_static_key {
+            Some(public_key) => self.public_keys.contains(public_key),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_listed_keys_are_admitted_in_private_networks() {
+        let mut allowlist = Allowlist::new(true, vec![vec![1; 32]]);
+        assert!(allowlist.admits(Some(&[1; 32])));
+        assert!(!allowlist.admits(Some(&[2; 32])));
+        assert!(!allowlist.admits(None));
+
+        assert!(allowlist.add(vec![2; 32]));
+        assert!(!allowlist.add(vec![2; 32]));
+        assert!(allowlist.admits(Some(&[2; 32])));
+        assert!(allowlist.remove(&[1; 32]));
+        assert!(!allowlist.admits(Some(&[1; 32])));
+        assert_eq!(allowlist.list(), vec![vec![2; 32]]);
+
+        let open = Allowlist::new(false, Vec::new());
+        assert!(open.admits(None));
+    }
+}
@@ -0,0 +1,60 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Jittered, so that peers that lost their connection at the same moment don't
+/// reconnect in lockstep.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(300))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    pub fn next_delay(&mut self, rng: &mut impl Rng) -> Duration {
+        let ceiling = self
+            .initial
+            .checked_mul(1 << self.attempt.min(31))
+            .unwrap_or(self.max)
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        let ceiling = ceiling.as_millis() as u64;
+        Duration::from_millis(rng.gen_range(ceiling / 2, ceiling + 1))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_up_to_the_maximum() {
+        let mut rng = rand::thread_rng();
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        let mut ceiling = Duration::from_secs(1);
+        for _ in 0..40 {
+            let delay = backoff.next_delay(&mut rng);
+            assert!(delay >= ceiling / 2 && delay <= ceiling);
+            ceiling = (ceiling * 2).min(Duration::from_secs(60));
+        }
+        backoff.reset();
+        assert!(backoff.next_delay(&mut rng) <= Duration::from_secs(1));
+    }
+}
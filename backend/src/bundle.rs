@@ -0,0 +1,422 @@
+//! A bundle starts with `MAGIC`, a version and the watermark of the export,
+//! followed by the messages and a BLAKE2b checksum of everything before it.
+//! Integers are big-endian.
+use crate::inventory::{get_all_after_counter, get_message, insert_message, Insertion, Message};
+use crate::push_order::PushOrder;
+use crate::session::Context;
+use chrono::Utc;
+use crypto::blake2b::Blake2b;
+use crypto::digest::Digest;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+
+const MAGIC: &[u8] = b"CSBUNDLE";
+const VERSION: u32 = 1;
+const CHECKSUM_BYTES: usize = 32;
+
+#[derive(Debug)]
+pub enum BundleError {
+    Io(io::Error),
+    NotABundle,
+    UnsupportedVersion(u32),
+    ChecksumMismatch,
+    Malformed,
+}
+
+impl From<io::Error> for BundleError {
+    fn from(error: io::Error) -> BundleError {
+        BundleError::Io(error)
+    }
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundleError::Io(error) => write!(f, "{}", error),
+            BundleError::NotABundle => write!(f, "File isn't a bundle"),
+            BundleError::UnsupportedVersion(version) => {
+                write!(f, "Bundle version {} isn't supported", version)
+            }
+            BundleError::ChecksumMismatch => write!(f, "Bundle is corrupted"),
+            BundleError::Malformed => write!(f, "Bundle is malformed"),
+        }
+    }
+}
+
+pub struct Bundle {
+    pub watermark: u64,
+    pub messages: Vec<Message>,
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_BYTES] {
+    let mut hasher = Blake2b::new(CHECKSUM_BYTES);
+    hasher.input(bytes);
+    let mut result = [0u8; CHECKSUM_BYTES];
+    hasher.result(&mut result);
+    result
+}
+
+pub fn encode(bundle: &Bundle) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&VERSION.to_be_bytes());
+    bytes.extend_from_slice(&bundle.watermark.to_be_bytes());
+    bytes.extend_from_slice(&(bundle.messages.len() as u64).to_be_bytes());
+    for message in &bundle.messages {
+        bytes.extend_from_slice(&(message.payload.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&message.payload);
+        bytes.extend_from_slice(&message.nonce.to_be_bytes());
+        bytes.extend_from_slice(&message.expiration_time.to_be_bytes());
+    }
+    let checksum = checksum(&bytes);
+    bytes.extend_from_slice(&checksum);
+    bytes
+}
+
+struct Cursor<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], BundleError> {
+        if self.bytes.len() < length {
+            return Err(BundleError::Malformed);
+        }
+        let (taken, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64, BundleError> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, BundleError> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Bundle, BundleError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(BundleError::NotABundle);
+    }
+    let mut cursor = Cursor {
+        bytes: &bytes[MAGIC.len()..],
+    };
+    let version = u32::from_be_bytes(cursor.take(4)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(BundleError::UnsupportedVersion(version));
+    }
+    if bytes.len() < MAGIC.len() + 4 + CHECKSUM_BYTES {
+        return Err(BundleError::Malformed);
+    }
+    let (contents, expected) = bytes.split_at(bytes.len() - CHECKSUM_BYTES);
+    if checksum(contents)[..] != *expected {
+        return Err(BundleError::ChecksumMismatch);
+    }
+    cursor.bytes = &contents[MAGIC.len() + 4..];
+
+    let watermark = cursor.u64()?;
+    let count = cursor.u64()?;
+    let mut messages = Vec::new();
+    for _ in 0..count {
+        let length = cursor.u64()?;
+        if length > cursor.bytes.len() as u64 {
+            return Err(BundleError::Malformed);
+        }
+        let payload = cursor.take(length as usize)?.to_vec();
+        let nonce = cursor.i64()?;
+        let expiration_time = cursor.i64()?;
+        messages.push(Message {
+            payload,
+            nonce,
+            expiration_time,
+        });
+    }
+    if !cursor.bytes.is_empty() {
+        return Err(BundleError::Malformed);
+    }
+    Ok(Bundle {
+        watermark,
+        messages,
+    })
+}
+
+pub async fn export(context: &Context, path: &str, since: u64) -> Result<Bundle, BundleError> {
+    let (hashes, watermark) = get_all_after_counter(
+        &context.in_memory_tx,
+        since as u128,
+        PushOrder::InsertionOrder,
+    )
+    .await;
+    let mut messages = Vec::new();
+    for hash in hashes {
+        // The message may have expired since the hashes were listed.
+        if let Some(message) = get_message(&context.on_disk_tx, hash).await {
+            messages.push(message);
+        }
+    }
+    let bundle = Bundle {
+        watermark: watermark as u64,
+        messages,
+    };
+    async_std::fs::write(path, encode(&bundle)).await?;
+    Ok(bundle)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct ImportSummary {
+    pub imported: u64,
+    pub duplicate: u64,
+    pub expired: u64,
+    pub rejected: u64,
+    pub refused: u64,
+}
+
+pub async fn import(context: &Context, path: &str) -> Result<ImportSummary, BundleError> {
+    let bundle = decode(&async_std::fs::read(path).await?)?;
+    let now = Utc::now().timestamp();
+    let mut summary = ImportSummary::default();
+    let mut candidates = Vec::new();
+    for message in bundle.messages {
+        if message.expiration_time <= now {
+            summary.expired += 1;
+        } else if context
+            .acceptance_policy
+            .check(message.payload.len(), message.expiration_time, now)
+            .is_err()
+        {
+            summary.rejected += 1;
+        } else {
+            candidates.push(message);
+        }
+    }
+    // A bundle can hold many messages, so their proofs of work are verified
+    // off the executor.
+    let verified = async_std::task::spawn_blocking(move || {
+        candidates
+            .into_iter()
+            .map(|message| {
+                let valid = crate::proof_of_work::verify(
+                    &message.payload,
+                    message.nonce,
+                    message.expiration_time,
+                );
+                (message, valid)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await;
+    for (message, valid) in verified {
+        if !valid {
+            summary.rejected += 1;
+            continue;
+        }
+        match insert_message(&context.on_disk_tx, message).await {
+            Insertion::Inserted => summary.imported += 1,
+            Insertion::Duplicate => summary.duplicate += 1,
+            Insertion::Refused => summary.refused += 1,
+        }
+    }
+    if summary.imported != 0 {
+        context.reconciliation_intent.read().await.broadcast();
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::{message_exists, pin_message};
+    use crate::message_hash::message_hash;
+    use crate::quota::Quota;
+    use std::sync::Arc;
+
+    fn bundle() -> Bundle {
+        Bundle {
+            watermark: 42,
+            messages: vec![
+                Message {
+                    payload: b"first".to_vec(),
+                    nonce: 1,
+                    expiration_time: 1000,
+                },
+                Message {
+                    payload: Vec::new(),
+                    nonce: -1,
+                    expiration_time: 2000,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn bundles_round_trip() {
+        let decoded = decode(&encode(&bundle())).unwrap();
+        assert_eq!(decoded.watermark, 42);
+        assert_eq!(decoded.messages.len(), 2);
+        assert_eq!(decoded.messages[0].payload, b"first");
+        assert_eq!(decoded.messages[1].nonce, -1);
+        assert_eq!(decoded.messages[1].expiration_time, 2000);
+    }
+
+    #[test]
+    fn damaged_bundles_are_refused() {
+        let bytes = encode(&bundle());
+
+        let mut corrupted = bytes.clone();
+        corrupted[MAGIC.len() + 20] ^= 1;
+        assert!(matches!(
+            decode(&corrupted),
+            Err(BundleError::ChecksumMismatch)
+        ));
+
+        let mut future = bytes.clone();
+        future[MAGIC.len() + 3] = 2;
+        assert!(matches!(
+            decode(&future),
+            Err(BundleError::UnsupportedVersion(2))
+        ));
+
+        assert!(matches!(
+            decode(&bytes[..bytes.len() - 1]),
+            Err(BundleError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            decode(b"PK\x03\x04"),
+            Err(BundleError::NotABundle)
+        ));
+    }
+
+    async fn publish(context: &Context, payload: &[u8], time_to_live: i64) -> Message {
+        let expiration_time = Utc::now().timestamp() + time_to_live;
+        let target = crate::proof_of_work::get_expected_target2(payload, expiration_time).unwrap();
+        let cancelled = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let message = Message {
+            payload: payload.to_vec(),
+            nonce: crate::proof_of_work::prove(payload, target, cancelled)
+                .await
+                .unwrap(),
+            expiration_time,
+        };
+        insert_message(&context.on_disk_tx, message.clone()).await;
+        message
+    }
+
+    #[test]
+    fn exports_import_into_another_inventory() {
+        let source = crate::simulation::Node::new().context;
+        // Only holds the message pinned below, so newer messages are refused.
+        let destination = crate::simulation::Node::with_quota(Quota {
+            max_messages: 1,
+            ..Default::default()
+        })
+        .context;
+        let directory = std::env::temp_dir();
+        let path = |name: &str| {
+            directory
+                .join(format!("bundle-{}-{}", std::process::id(), name))
+                .to_str()
+                .unwrap()
+                .to_owned()
+        };
+        async_std::task::block_on(async {
+            let first = publish(&source, b"first", 600).await;
+            let everything = export(&source, &path("everything"), 0).await.unwrap();
+            assert_eq!(everything.messages.len(), 1);
+
+            let second = publish(&source, b"second", 600).await;
+            let newer = export(&source, &path("newer"), everything.watermark)
+                .await
+                .unwrap();
+            assert_eq!(newer.messages.len(), 1);
+            assert_eq!(newer.messages[0].payload, second.payload);
+
+            let summary = import(&destination, &path("everything")).await.unwrap();
+            assert_eq!(
+                summary,
+                ImportSummary {
+                    imported: 1,
+                    ..Default::default()
+                }
+            );
+            let hash = |message: &Message| {
+                Arc::new(message_hash(&message.payload, message.expiration_time).to_vec())
+            };
+            assert!(message_exists(&destination.in_memory_tx, hash(&first)).await);
+            pin_message(&destination.on_disk_tx, hash(&first)).await;
+
+            // The first message comes back as a duplicate, the others never
+            // make it into the inventory.
+            let now = Utc::now().timestamp();
+            let mut damaged = decode(&async_std::fs::read(path("newer")).await.unwrap()).unwrap();
+            damaged.messages.push(first.clone());
+            damaged.messages.push(Message {
+                payload: b"expired".to_vec(),
+                nonce: 0,
+                expiration_time: now - 1,
+            });
+            damaged.messages.push(Message {
+                payload: b"no proof of work".to_vec(),
+                nonce: 0,
+                expiration_time: now + 600,
+            });
+            async_std::fs::write(path("damaged"), encode(&damaged))
+                .await
+                .unwrap();
+            let summary = import(&destination, &path("damaged")).await.unwrap();
+            assert_eq!(
+                summary,
+                ImportSummary {
+                    duplicate: 1,
+                    expired: 1,
+                    rejected: 1,
+                    refused: 1,
+                    ..Default::default()
+                }
+            );
+            assert!(!message_exists(&destination.in_memory_tx, hash(&second)).await);
+
+            for name in &["everything", "newer", "damaged"] {
+                async_std::fs::remove_file(path(name)).await.unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn exports_wait_for_stored_messages_to_load() {
+        let directory = std::env::temp_dir();
+        let path = |name: &str| {
+            directory
+                .join(format!("bundle-{}-{}", std::process::id(), name))
+                .to_str()
+                .unwrap()
+                .to_owned()
+        };
+        let open = || {
+            let connection = rusqlite::Connection::open(path("inventory.sqlite")).unwrap();
+            crate::simulation::Node::open(connection, Quota::default()).context
+        };
+        async_std::task::block_on(async {
+            let expiration_time = Utc::now().timestamp() + 600;
+            let previous_run = open();
+            for byte in 0..100 {
+                let message = Message {
+                    payload: vec![byte; 100],
+                    nonce: 0,
+                    expiration_time,
+                };
+                insert_message(&previous_run.on_disk_tx, message).await;
+            }
+
+            let bundle = export(&open(), &path("stored"), 0).await.unwrap();
+            assert_eq!(bundle.messages.len(), 100);
+            assert_eq!(bundle.watermark, 100);
+
+            for name in &["inventory.sqlite", "stored"] {
+                async_std::fs::remove_file(path(name)).await.unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,211 @@
+use async_compression::futures::bufread::ZstdDecoder;
+use async_compression::futures::write::ZstdEncoder;
+use async_std::io::{self, Read, Write};
+use futures::io::{AsyncReadExt, BufReader, ReadHalf, WriteHalf};
+use futures::task::{Context as TaskContext, Poll};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::pin::Pin;
+use std::rc::Rc;
+
+const ZSTD: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+impl Compression {
+    /// The listening side answers with the same encoding.
+    pub fn offer(self) -> Vec<u8> {
+        match self {
+            Compression::None => Vec::new(),
+            Compression::Zstd => vec![ZSTD],
+        }
+    }
+
+    pub fn choose(self, offer: &[u8]) -> Compression {
+        if self == Compression::Zstd && offer.contains(&ZSTD) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    pub fn accept_answer(self, answer: &[u8]) -> io::Result<Compression> {
+        match answer {
+            [] => Ok(Compression::None),
+            [ZSTD] if self == Compression::Zstd => Ok(Compression::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Peer chose a compression algorithm that wasn't offered",
+            )),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Statistics {
+    links: Cell<u64>,
+    uncompressed_sent: Cell<u64>,
+    compressed_sent: Cell<u64>,
+    uncompressed_received: Cell<u64>,
+    compressed_received: Cell<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatisticsSnapshot {
+    pub links: u64,
+    pub uncompressed_sent: u64,
+    pub compressed_sent: u64,
+    pub uncompressed_received: u64,
+    pub compressed_received: u64,
+}
+
+fn add(cell: &Cell<u64>, bytes: usize) {
+    cell.set(cell.get() + bytes as u64);
+}
+
+impl Statistics {
+    pub fn snapshot(&self) -> StatisticsSnapshot {
+        StatisticsSnapshot {
+            links: self.links.get(),
+            uncompressed_sent: self.uncompressed_sent.get(),
+            compressed_sent: self.compressed_sent.get(),
+            uncompressed_received: self.uncompressed_received.get(),
+            compressed_received: self.compressed_received.get(),
+        }
+    }
+}
+
+struct Counted<T> {
+    inner: T,
+    statistics: Rc<Statistics>,
+}
+
+impl<T: Read + Unpin> Read for Counted<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = result {
+            add(&this.statistics.compressed_received, read);
+        }
+        result
+    }
+}
+
+impl<T: Write + Unpin> Write for Counted<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            add(&this.statistics.compressed_sent, written);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+/// Flushing ends the current zstd block, so every flushed Cap'n Proto message
+/// can be decoded right away.
+pub struct Compressed<T> {
+    reader: ZstdDecoder<BufReader<Counted<ReadHalf<T>>>>,
+    writer: ZstdEncoder<Counted<WriteHalf<T>>>,
+    statistics: Rc<Statistics>,
+}
+
+impl<T: Read + Write> Compressed<T> {
+    pub fn new(stream: T, statistics: Rc<Statistics>) -> Compressed<T> {
+        let (reader, writer) = stream.split();
+        add(&statistics.links, 1);
+        Compressed {
+            reader: ZstdDecoder::new(BufReader::new(Counted {
+                inner: reader,
+                statistics: statistics.clone(),
+            })),
+            writer: ZstdEncoder::new(Counted {
+                inner: writer,
+                statistics: statistics.clone(),
+            }),
+            statistics,
+        }
+    }
+}
+
+impl<T: Read + Write> Read for Compressed<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.reader).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = result {
+            add(&this.statistics.uncompressed_received, read);
+        }
+        result
+    }
+}
+
+impl<T: Read + Write> Write for Compressed<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.writer).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            add(&this.statistics.uncompressed_sent, written);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation() {
+        let offer = Compression::Zstd.offer();
+        assert_eq!(Compression::Zstd.choose(&offer), Compression::Zstd);
+        assert_eq!(Compression::None.choose(&offer), Compression::None);
+        assert_eq!(Compression::Zstd.choose(&[]), Compression::None);
+
+        let answer = Compression::Zstd.offer();
+        assert_eq!(
+            Compression::Zstd.accept_answer(&answer).unwrap(),
+            Compression::Zstd
+        );
+        assert!(Compression::None.accept_answer(&answer).is_err());
+        assert_eq!(
+            Compression::Zstd.accept_answer(&[]).unwrap(),
+            Compression::None
+        );
+    }
+}
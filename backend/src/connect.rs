@@ -1,53 +1,267 @@
-use crate::inventory::{InMemory, OnDisk};
+use crate::backoff::Backoff;
+use crate::connection_registry::Link;
 use crate::log;
-use crate::mpmc_manual_reset_event;
+use crate::noise;
+use crate::peer_exchange;
 use crate::reconcile_client;
 use crate::reconcile_server;
-use async_std::sync::{RwLock, Sender};
+use crate::session::{Context, SessionError};
+use crate::socks5;
+use async_std::io::{Read, Write};
 use futures::executor::LocalSpawner;
+use futures::future::{select, Either};
 use futures::task::LocalSpawn;
-pub fn connect<F1, F2, F3>(
+use futures_intrusive::sync::LocalManualResetEvent;
+use serde::{Deserialize, Serialize};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const STABLE_SESSION: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+pub enum Direction {
+    Forward,
+    /// Only peers that predate bidirectional sessions need it.
+    Reverse,
+}
+
+enum AttemptError {
+    Connection(std::io::Error),
+    Reconcile(SessionError),
+}
+
+/// Addresses without a scheme are TCP addresses.
+#[derive(Debug, PartialEq)]
+pub enum Address {
+    Tcp(String),
+    Unix(String),
+    Quic(String),
+    Udp(String),
+}
+
+impl Address {
+    pub fn parse(address: &str) -> Address {
+        if let Some(path) = address.strip_prefix("unix://") {
+            return Address::Unix(path.to_string());
+        }
+        if let Some(address) = address.strip_prefix("quic://") {
+            return Address::Quic(address.to_string());
+        }
+        if let Some(address) = address.strip_prefix("udp://") {
+            return Address::Udp(address.to_string());
+        }
+        Address::Tcp(
+            address
+                .strip_prefix("tcp://")
+                .unwrap_or(address)
+                .to_string(),
+        )
+    }
+}
+
+async fn attempt(
+    address: &str,
+    operation_id: &str,
+    context: Context,
+    direction: Direction,
+    public_key: Option<&[u8]>,
+    on_connected: impl FnOnce(),
+) -> Result<(), AttemptError> {
+    let on_disk_tx = context.on_disk_tx.clone();
+    let result = dial(
+        address,
+        operation_id,
+        context,
+        direction,
+        public_key,
+        on_connected,
+    )
+    .await;
+    if let Err(AttemptError::Connection(_)) = result {
+        peer_exchange::record_attempt(&on_disk_tx, address, false).await;
+    }
+    result
+}
+
+async fn dial(
+    address: &str,
+    operation_id: &str,
+    context: Context,
+    direction: Direction,
+    public_key: Option<&[u8]>,
+    on_connected: impl FnOnce(),
+) -> Result<(), AttemptError> {
+    log::notice(format!("Connecting to {}", address));
+    match Address::parse(address) {
+        Address::Tcp(socket_address) => {
+            let (stream, peer) = match &context.socks5_proxy {
+                Some(proxy) => {
+                    let (host, port) = socks5::split_host_port(&socket_address)
+                        .map_err(AttemptError::Connection)?;
+                    let mut stream = async_std::net::TcpStream::connect(proxy)
+                        .await
+                        .map_err(AttemptError::Connection)?;
+                    stream.set_nodelay(true).map_err(AttemptError::Connection)?;
+                    socks5::connect(&mut stream, &host, port)
+                        .await
+                        .map_err(AttemptError::Connection)?;
+                    // The peer's IP address is only known to the proxy.
+                    (stream, host)
+                }
+                None => {
+                    let stream = async_std::net::TcpStream::connect(&socket_address)
+                        .await
+                        .map_err(AttemptError::Connection)?;
+                    stream.set_nodelay(true).map_err(AttemptError::Connection)?;
+                    let peer = stream
+                        .peer_addr()
+                        .map_err(AttemptError::Connection)?
+                        .ip()
+                        .to_string();
+                    (stream, peer)
+                }
+            };
+
+            run(
+                stream,
+                peer,
+                address,
+                operation_id,
+                context,
+                direction,
+                public_key,
+                on_connected,
+            )
+            .await
+        }
+        #[cfg(unix)]
+        Address::Unix(path) => {
+            let stream = async_std::os::unix::net::UnixStream::connect(&path)
+                .await
+                .map_err(AttemptError::Connection)?;
+            // Unix socket peers don't have an address, so the socket file
+            // stands in for one.
+            let peer = format!("unix:{}", path);
+            run(
+                stream,
+                peer,
+                address,
+                operation_id,
+                context,
+                direction,
+                public_key,
+                on_connected,
+            )
+            .await
+        }
+        #[cfg(not(unix))]
+        Address::Unix(_) => Err(AttemptError::Connection(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Unix domain sockets aren't supported on this platform",
+        ))),
+        #[cfg(feature = "quic")]
+        Address::Quic(socket_address) => {
+            let (stream, remote) = crate::quic::dial(&socket_address)
+                .await
+                .map_err(AttemptError::Connection)?;
+            run(
+                stream,
+                remote.ip().to_string(),
+                address,
+                operation_id,
+                context,
+                direction,
+                public_key,
+                on_connected,
+            )
+            .await
+        }
+        #[cfg(not(feature = "quic"))]
+        Address::Quic(_) => Err(AttemptError::Connection(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "QUIC support wasn't compiled in",
+        ))),
+        Address::Udp(socket_address) => {
+            let (stream, remote) = crate::udp::dial(socket_address.as_str())
+                .await
+                .map_err(AttemptError::Connection)?;
+            run(
+                stream,
+                remote.ip().to_string(),
+                address,
+                operation_id,
+                context,
+                direction,
+                public_key,
+                on_connected,
+            )
+            .await
+        }
+    }
+}
+
+async fn run<T: Read + Write + Unpin + 'static>(
+    stream: T,
+    peer: String,
+    address: &str,
+    operation_id: &str,
+    context: Context,
+    direction: Direction,
+    public_key: Option<&[u8]>,
+    on_connected: impl FnOnce(),
+) -> Result<(), AttemptError> {
+    let stream = match public_key {
+        Some(public_key) => noise::dial_known_peer(stream, &context, public_key).await,
+        None => noise::dial_peer(stream, &context).await,
+    }
+    .map_err(AttemptError::Connection)?;
+
+    let link = Link {
+        id: Some(operation_id.to_string()),
+        peer: stream.peer_identity(peer),
+        remote_static_key: stream.remote_static_key().map(<[u8]>::to_vec),
+        address: address.to_string(),
+        inbound: false,
+    };
+    peer_exchange::record_attempt(&context.on_disk_tx, address, true).await;
+    on_connected();
+    match direction {
+        Direction::Forward => reconcile_client::reconcile(stream, link, context).await,
+        Direction::Reverse => reconcile_server::init_server(stream, link, context).await,
+    }
+    .map_err(AttemptError::Reconcile)
+}
+
+fn spawn_attempt<F1, F2, F3>(
     address: String,
-    in_memory_tx: Sender<InMemory>,
-    on_disk_tx: Sender<OnDisk>,
+    operation_id: String,
+    context: Context,
     handle: LocalSpawner,
-    reconciliation_intent: std::rc::Rc<RwLock<mpmc_manual_reset_event::MPMCManualResetEvent>>,
+    direction: Direction,
+    public_key: Option<Vec<u8>>,
     on_connection_failed: F1,
     on_reconcile_failed: F2,
     on_connection_severed: F3,
 ) where
     F1: FnOnce(std::io::Error) -> () + 'static,
-    F2: FnOnce(capnp::Error) -> () + 'static,
+    F2: FnOnce(SessionError) -> () + 'static,
     F3: FnOnce() -> () + 'static,
 {
-    let handle1 = handle.clone();
     handle
         .spawn_local_obj(
             Box::new(async move {
-                log::notice(format!("Connecting to {}", address));
-                let stream = match async_std::net::TcpStream::connect(&address).await {
-                    Ok(stream) => stream,
-                    Err(error) => {
-                        on_connection_failed(error);
-                        return;
-                    }
-                };
-
-                if let Err(error) = stream.set_nodelay(true) {
-                    on_connection_failed(error);
-                    return;
-                };
-
-                match reconcile_client::reconcile(
-                    stream,
-                    &in_memory_tx,
-                    &on_disk_tx,
-                    handle1,
-                    reconciliation_intent,
+                match attempt(
+                    &address,
+                    &operation_id,
+                    context,
+                    direction,
+                    public_key.as_deref(),
+                    || {},
                 )
                 .await
                 {
-                    Err(error) => on_reconcile_failed(error),
+                    Err(AttemptError::Connection(error)) => on_connection_failed(error),
+                    Err(AttemptError::Reconcile(error)) => on_reconcile_failed(error),
                     Ok(()) => on_connection_severed(),
                 }
             })
@@ -56,50 +270,350 @@ pub fn connect<F1, F2, F3>(
         .unwrap();
 }
 
+pub fn connect<F1, F2, F3>(
+    address: String,
+    operation_id: String,
+    context: Context,
+    handle: LocalSpawner,
+    on_connection_failed: F1,
+    on_reconcile_failed: F2,
+    on_connection_severed: F3,
+) where
+    F1: FnOnce(std::io::Error) -> () + 'static,
+    F2: FnOnce(SessionError) -> () + 'static,
+    F3: FnOnce() -> () + 'static,
+{
+    spawn_attempt(
+        address,
+        operation_id,
+        context,
+        handle,
+        Direction::Forward,
+        None,
+        on_connection_failed,
+        on_reconcile_failed,
+        on_connection_severed,
+    );
+}
+
+/// The link is dropped if the peer turns out to hold another key.
+pub fn connect_to_announced_peer<F1, F2, F3>(
+    address: String,
+    public_key: Vec<u8>,
+    operation_id: String,
+    context: Context,
+    handle: LocalSpawner,
+    on_connection_failed: F1,
+    on_reconcile_failed: F2,
+    on_connection_severed: F3,
+) where
+    F1: FnOnce(std::io::Error) -> () + 'static,
+    F2: FnOnce(SessionError) -> () + 'static,
+    F3: FnOnce() -> () + 'static,
+{
+    spawn_attempt(
+        address,
+        operation_id,
+        context,
+        handle,
+        Direction::Forward,
+        Some(public_key),
+        on_connection_failed,
+        on_reconcile_failed,
+        on_connection_severed,
+    );
+}
+
 pub fn reverse_connect<F1, F2, F3>(
     address: String,
-    in_memory_tx: Sender<InMemory>,
-    on_disk_tx: Sender<OnDisk>,
+    operation_id: String,
+    context: Context,
     handle: LocalSpawner,
-    reconciliation_intent: std::rc::Rc<RwLock<mpmc_manual_reset_event::MPMCManualResetEvent>>,
     on_connection_failed: F1,
     on_reconcile_failed: F2,
     on_connection_severed: F3,
 ) where
     F1: FnOnce(std::io::Error) -> () + 'static,
-    F2: FnOnce(capnp::Error) -> () + 'static,
+    F2: FnOnce(SessionError) -> () + 'static,
     F3: FnOnce() -> () + 'static,
+{
+    spawn_attempt(
+        address,
+        operation_id,
+        context,
+        handle,
+        Direction::Reverse,
+        None,
+        on_connection_failed,
+        on_reconcile_failed,
+        on_connection_severed,
+    );
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ConnectionState {
+    Connecting { attempt: u32 },
+    Connected,
+    Disconnected { reason: String },
+    Reconnecting { delay_milliseconds: u64 },
+    Cancelled,
+}
+
+pub fn connect_persistently<F>(
+    address: String,
+    operation_id: String,
+    context: Context,
+    handle: LocalSpawner,
+    direction: Direction,
+    cancel: Rc<LocalManualResetEvent>,
+    on_state_change: F,
+) where
+    F: Fn(ConnectionState) -> () + 'static,
 {
     handle
         .spawn_local_obj(
             Box::new(async move {
-                log::notice(format!("Connecting to {}", address));
-                let stream = match async_std::net::TcpStream::connect(&address).await {
-                    Ok(stream) => stream,
-                    Err(error) => {
-                        on_connection_failed(error);
-                        return;
-                    }
-                };
+                let mut backoff = Backoff::default();
+                let mut attempts = 0u32;
+                loop {
+                    attempts = attempts.saturating_add(1);
+                    on_state_change(ConnectionState::Connecting { attempt: attempts });
+                    let started = Instant::now();
+                    let result = match select(
+                        Box::pin(attempt(
+                            &address,
+                            &operation_id,
+                            context.clone(),
+                            direction,
+                            None,
+                            || on_state_change(ConnectionState::Connected),
+                        )),
+                        Box::pin(cancel.wait()),
+                    )
+                    .await
+                    {
+                        Either::Left((result, _)) => result,
+                        Either::Right(_) => break,
+                    };
+                    let reason = match result {
+                        Err(AttemptError::Connection(error)) => format!("{}", error),
+                        Err(AttemptError::Reconcile(error)) => format!("{}", error),
+                        Ok(()) => "Connection severed".to_string(),
+                    };
+                    on_state_change(ConnectionState::Disconnected { reason });
 
-                if let Err(error) = stream.set_nodelay(true) {
-                    on_connection_failed(error);
-                    return;
-                }
-
-                match reconcile_server::init_server(
-                    stream,
-                    in_memory_tx,
-                    on_disk_tx,
-                    reconciliation_intent,
-                )
-                .await
-                {
-                    Err(error) => on_reconcile_failed(error),
-                    Ok(()) => on_connection_severed(),
+                    if started.elapsed() >= STABLE_SESSION {
+                        backoff.reset();
+                    }
+                    let delay = backoff.next_delay(&mut rand::thread_rng());
+                    on_state_change(ConnectionState::Reconnecting {
+                        delay_milliseconds: delay.as_millis() as u64,
+                    });
+                    if let Either::Right(_) = select(
+                        Box::pin(async_std::task::sleep(delay)),
+                        Box::pin(cancel.wait()),
+                    )
+                    .await
+                    {
+                        break;
+                    }
                 }
+                on_state_change(ConnectionState::Cancelled);
             })
             .into(),
         )
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_are_parsed() {
+        assert_eq!(
+            Address::parse("127.0.0.1:8080"),
+            Address::Tcp("127.0.0.1:8080".to_string())
+        );
+        assert_eq!(
+            Address::parse("tcp://127.0.0.1:8080"),
+            Address::Tcp("127.0.0.1:8080".to_string())
+        );
+        assert_eq!(
+            Address::parse("unix:///tmp/contrasleuth.sock"),
+            Address::Unix("/tmp/contrasleuth.sock".to_string())
+        );
+        assert_eq!(
+            Address::parse("quic://127.0.0.1:8080"),
+            Address::Quic("127.0.0.1:8080".to_string())
+        );
+        assert_eq!(
+            Address::parse("udp://127.0.0.1:8080"),
+            Address::Udp("127.0.0.1:8080".to_string())
+        );
+    }
+
+    #[cfg(unix)]
+    fn backend() -> Context {
+        crate::simulation::Node::new().context
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backends_connect_through_a_socket_file() {
+        use async_std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!(
+            "contrasleuth-{}-{}.sock",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let address = format!("unix://{}", path.display());
+        let (server, client) = (backend(), backend());
+
+        let mut exec = futures::executor::LocalPool::new();
+        let spawner = exec.spawner();
+        let listener = exec.run_until(UnixListener::bind(&path)).unwrap();
+        {
+            let server = server.clone();
+            spawner
+                .spawn_local_obj(
+                    Box::new(async move {
+                        let (socket, _) = listener.accept().await.unwrap();
+                        let socket = noise::accept_peer(socket, &server).await.unwrap();
+                        let link = Link {
+                            id: None,
+                            peer: "unix:test".to_string(),
+                            remote_static_key: None,
+                            address: "unix:test".to_string(),
+                            inbound: true,
+                        };
+                        let _ = reconcile_server::init_server(socket, link, server).await;
+                    })
+                    .into(),
+                )
+                .unwrap();
+        }
+        connect(
+            address,
+            "operation".to_string(),
+            client.clone(),
+            spawner,
+            |error| panic!("Connection failed: {}", error),
+            |_| {},
+            || {},
+        );
+
+        exec.run_until(connected(&server, &client));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(all(unix, feature = "quic"))]
+    #[test]
+    fn backends_connect_over_quic() {
+        let (server, client) = (backend(), backend());
+        let endpoint = crate::quic::listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = format!("quic://{}", endpoint.local_addr().unwrap());
+
+        let mut exec = futures::executor::LocalPool::new();
+        let spawner = exec.spawner();
+        {
+            let server = server.clone();
+            spawner
+                .spawn_local_obj(
+                    Box::new(async move {
+                        let connecting = crate::quic::accept(&endpoint).await.unwrap();
+                        let (socket, remote) = crate::quic::establish(connecting).await.unwrap();
+                        let socket = noise::accept_peer(socket, &server).await.unwrap();
+                        let link = Link {
+                            id: None,
+                            peer: remote.ip().to_string(),
+                            remote_static_key: None,
+                            address: remote.to_string(),
+                            inbound: true,
+                        };
+                        let _ = reconcile_server::init_server(socket, link, server).await;
+                    })
+                    .into(),
+                )
+                .unwrap();
+        }
+        connect(
+            address,
+            "operation".to_string(),
+            client.clone(),
+            spawner,
+            |error| panic!("Connection failed: {}", error),
+            |_| {},
+            || {},
+        );
+
+        exec.run_until(connected(&server, &client));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backends_connect_over_udp() {
+        let (server, client) = (backend(), backend());
+        let listener =
+            async_std::task::block_on(crate::udp::listen("127.0.0.1:0".parse().unwrap())).unwrap();
+        let address = format!("udp://{}", listener.local_addr());
+
+        let mut exec = futures::executor::LocalPool::new();
+        let spawner = exec.spawner();
+        {
+            let server = server.clone();
+            spawner
+                .spawn_local_obj(
+                    Box::new(async move {
+                        let (socket, remote) = crate::udp::accept(&listener).await.unwrap();
+                        let socket = noise::accept_peer(socket, &server).await.unwrap();
+                        let link = Link {
+                            id: None,
+                            peer: remote.ip().to_string(),
+                            remote_static_key: None,
+                            address: remote.to_string(),
+                            inbound: true,
+                        };
+                        let _ = reconcile_server::init_server(socket, link, server).await;
+                    })
+                    .into(),
+                )
+                .unwrap();
+        }
+        connect(
+            address,
+            "operation".to_string(),
+            client.clone(),
+            spawner,
+            |error| panic!("Connection failed: {}", error),
+            |_| {},
+            || {},
+        );
+
+        exec.run_until(connected(&server, &client));
+    }
+
+    #[cfg(unix)]
+    async fn connected(server: &Context, client: &Context) {
+        async_std::future::timeout(Duration::from_secs(30), async {
+            loop {
+                let server_connections = server.connections.read().await.list();
+                let client_connections = client.connections.read().await.list();
+                if let ([server_connection], [client_connection]) =
+                    (&server_connections[..], &client_connections[..])
+                {
+                    if server_connection.node_id == *client.node_id
+                        && client_connection.node_id == *server.node_id
+                    {
+                        assert_eq!(client_connection.id, "operation");
+                        break;
+                    }
+                }
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Backends didn't connect");
+    }
+}
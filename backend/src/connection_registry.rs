@@ -0,0 +1,359 @@
+use async_std::io::{self, Read, Write};
+use futures::task::{Context as TaskContext, Poll};
+use futures_intrusive::sync::LocalManualResetEvent;
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::rc::Rc;
+
+pub struct Link {
+    /// Incoming connections are assigned an ID when they are registered.
+    pub id: Option<String>,
+    pub peer: String,
+    /// Set when the peer was authenticated by the Noise handshake.
+    pub remote_static_key: Option<Vec<u8>>,
+    pub address: String,
+    pub inbound: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+pub struct Connection {
+    pub id: String,
+    address: String,
+    inbound: bool,
+    role: Role,
+    started_at: i64,
+    remote_static_key: Option<Vec<u8>>,
+    /// Empty until the handshake completes, and for legacy peers.
+    node_id: RefCell<Vec<u8>>,
+    bytes_sent: Cell<u64>,
+    bytes_received: Cell<u64>,
+    messages_sent: Cell<u64>,
+    messages_received: Cell<u64>,
+    pub disconnect: LocalManualResetEvent,
+    pub duplicate: Cell<bool>,
+}
+
+fn add(cell: &Cell<u64>, amount: usize) {
+    cell.set(cell.get() + amount as u64);
+}
+
+impl Connection {
+    pub fn record_messages_sent(&self, count: usize) {
+        add(&self.messages_sent, count);
+    }
+
+    pub fn record_messages_received(&self, count: usize) {
+        add(&self.messages_received, count);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConnectionInfo {
+    pub id: String,
+    pub address: String,
+    pub inbound: bool,
+    pub role: Role,
+    pub started_at: i64,
+    pub node_id: Vec<u8>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RegistryError {
+    TooManySessions,
+    Duplicate,
+}
+
+pub struct ConnectionRegistry {
+    /// Zero means that there is no limit.
+    max_sessions: usize,
+    next_key: u64,
+    connections: BTreeMap<u64, Rc<Connection>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new(max_sessions: usize) -> ConnectionRegistry {
+        ConnectionRegistry {
+            max_sessions,
+            next_key: 0,
+            connections: BTreeMap::new(),
+        }
+    }
+
+    pub fn register(
+        &mut self,
+        link: &Link,
+        role: Role,
+        now: i64,
+    ) -> Result<(u64, Rc<Connection>), RegistryError> {
+        if self.is_full() {
+            return Err(RegistryError::TooManySessions);
+        }
+        let key = self.next_key;
+        self.next_key += 1;
+        let connection = Rc::new(Connection {
+            id: match &link.id {
+                Some(id) => id.clone(),
+                None => format!("inbound-{}", key),
+            },
+            address: link.address.clone(),
+            inbound: link.inbound,
+            role,
+            started_at: now,
+            remote_static_key: link.remote_static_key.clone(),
+            node_id: RefCell::new(Vec::new()),
+            bytes_sent: Cell::new(0),
+            bytes_received: Cell::new(0),
+            messages_sent: Cell::new(0),
+            messages_received: Cell::new(0),
+            disconnect: LocalManualResetEvent::new(false),
+            duplicate: Cell::new(false),
+        });
+        self.connections.insert(key, connection.clone());
+        Ok((key, connection))
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.max_sessions != 0 && self.connections.len() >= self.max_sessions
+    }
+
+    pub fn is_connected(&self, node_id: &[u8]) -> bool {
+        self.connections
+            .values()
+            .any(|connection| *connection.node_id.borrow() == node_id)
+    }
+
+    pub fn unregister(&mut self, key: u64) {
+        self.connections.remove(&key);
+    }
+
+    /// When both nodes dial each other at the same time, both sides must drop the
+    /// same connection, so the one dialed by the node with the smaller node ID is
+    /// kept. Otherwise the older connection is kept. Node IDs are claimed by the
+    /// peer, so Noise peers are told apart by their static key instead.
+    pub fn identify(
+        &mut self,
+        key: u64,
+        local_node_id: &[u8],
+        remote_node_id: Vec<u8>,
+    ) -> Result<(), RegistryError> {
+        let connection = match self.connections.get(&key) {
+            Some(connection) => connection.clone(),
+            None => return Ok(()),
+        };
+        let existing = self.connections.iter().find(|(other, existing)| {
+            if **other == key || existing.node_id.borrow().is_empty() {
+                return false;
+            }
+            match (&connection.remote_static_key, &existing.remote_static_key) {
+                (Some(remote_static_key), Some(existing_key)) => remote_static_key == existing_key,
+                (None, None) => *existing.node_id.borrow() == remote_node_id,
+                _ => false,
+            }
+        });
+        if let Some((existing_key, existing)) = existing {
+            let keep_new = existing.inbound != connection.inbound
+                && connection.inbound == (remote_node_id.as_slice() < local_node_id);
+            if !keep_new {
+                return Err(RegistryError::Duplicate);
+            }
+            existing.duplicate.set(true);
+            existing.disconnect.set();
+            let existing_key = *existing_key;
+            self.connections.remove(&existing_key);
+        }
+        *connection.node_id.borrow_mut() = remote_node_id;
+        Ok(())
+    }
+
+    pub fn disconnect(&self, id: &str) -> bool {
+        let mut found = false;
+        for connection in self.connections.values() {
+            if connection.id == id {
+                connection.disconnect.set();
+                found = true;
+            }
+        }
+        found
+    }
+
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .values()
+            .map(|connection| ConnectionInfo {
+                id: connection.id.clone(),
+                address: connection.address.clone(),
+                inbound: connection.inbound,
+                role: connection.role,
+                started_at: connection.started_at,
+                node_id: connection.node_id.borrow().clone(),
+                bytes_sent: connection.bytes_sent.get(),
+                bytes_received: connection.bytes_received.get(),
+                messages_sent: connection.messages_sent.get(),
+                messages_received: connection.messages_received.get(),
+            })
+            .collect()
+    }
+}
+
+pub struct Metered<T> {
+    inner: T,
+    connection: Rc<Connection>,
+}
+
+impl<T> Metered<T> {
+    pub fn new(inner: T, connection: Rc<Connection>) -> Metered<T> {
+        Metered { inner, connection }
+    }
+}
+
+impl<T: Read + Unpin> Read for Metered<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = result {
+            add(&this.connection.bytes_received, read);
+        }
+        result
+    }
+}
+
+impl<T: Write + Unpin> Write for Metered<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            add(&this.connection.bytes_sent, written);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(inbound: bool) -> Link {
+        Link {
+            id: None,
+            peer: "10.0.0.1".to_string(),
+            remote_static_key: None,
+            address: "10.0.0.1:8080".to_string(),
+            inbound,
+        }
+    }
+
+    #[test]
+    fn sessions_are_limited() {
+        let mut registry = ConnectionRegistry::new(1);
+        let (key, _) = registry.register(&link(true), Role::Server, 0).unwrap();
+        assert_eq!(
+            registry
+                .register(&link(true), Role::Server, 0)
+                .err()
+                .unwrap(),
+            RegistryError::TooManySessions
+        );
+        registry.unregister(key);
+        assert!(registry.register(&link(true), Role::Server, 0).is_ok());
+    }
+
+    #[test]
+    fn simultaneous_dials_keep_the_same_connection_on_both_sides() {
+        let (small, large) = (vec![1u8; 32], vec![2u8; 32]);
+
+        // The node with the smaller node ID keeps its outgoing connection.
+        let mut registry = ConnectionRegistry::new(0);
+        let (outbound, outbound_connection) =
+            registry.register(&link(false), Role::Client, 0).unwrap();
+        let (inbound, _) = registry.register(&link(true), Role::Server, 0).unwrap();
+        assert!(registry.identify(outbound, &small, large.clone()).is_ok());
+        assert_eq!(
+            registry.identify(inbound, &small, large.clone()),
+            Err(RegistryError::Duplicate)
+        );
+        assert!(!outbound_connection.duplicate.get());
+
+        // The node with the larger node ID keeps its incoming connection, even
+        // when its outgoing connection was identified first.
+        let mut registry = ConnectionRegistry::new(0);
+        let (outbound, outbound_connection) =
+            registry.register(&link(false), Role::Client, 0).unwrap();
+        let (inbound, _) = registry.register(&link(true), Role::Server, 0).unwrap();
+        assert!(registry.identify(outbound, &large, small.clone()).is_ok());
+        assert!(registry.identify(inbound, &large, small.clone()).is_ok());
+        assert!(outbound_connection.duplicate.get());
+        assert_eq!(registry.list().len(), 1);
+    }
+
+    #[test]
+    fn claimed_node_ids_dont_displace_authenticated_peers() {
+        let (local, remote) = (vec![2u8; 32], vec![1u8; 32]);
+        let authenticated = |inbound: bool, remote_static_key: u8| Link {
+            remote_static_key: Some(vec![remote_static_key; 32]),
+            ..link(inbound)
+        };
+        let mut registry = ConnectionRegistry::new(0);
+        let (victim, victim_connection) = registry
+            .register(&authenticated(false, 1), Role::Client, 0)
+            .unwrap();
+        assert!(registry.identify(victim, &local, remote.clone()).is_ok());
+
+        // Neither a plaintext peer nor a peer with another key can claim the
+        // node ID.
+        let (impostor, _) = registry.register(&link(true), Role::Server, 0).unwrap();
+        assert!(registry.identify(impostor, &local, remote.clone()).is_ok());
+        let (impostor, _) = registry
+            .register(&authenticated(true, 3), Role::Server, 0)
+            .unwrap();
+        assert!(registry.identify(impostor, &local, remote.clone()).is_ok());
+        assert!(!victim_connection.duplicate.get());
+
+        // The same key is a duplicate whatever node ID it claims.
+        let (duplicate, _) = registry
+            .register(&authenticated(true, 1), Role::Server, 0)
+            .unwrap();
+        assert_eq!(
+            registry.identify(duplicate, &local, vec![4u8; 32]),
+            Err(RegistryError::Duplicate)
+        );
+        assert!(!victim_connection.duplicate.get());
+    }
+
+    #[test]
+    fn connections_can_be_disconnected_by_id() {
+        let mut registry = ConnectionRegistry::new(0);
+        let mut outgoing = link(false);
+        outgoing.id = Some("operation".to_string());
+        let (_, connection) = registry.register(&outgoing, Role::Client, 0).unwrap();
+        assert!(!registry.disconnect("inbound-0"));
+        assert!(registry.disconnect("operation"));
+        assert!(connection.disconnect.is_set());
+    }
+}
@@ -1,5 +1,7 @@
 use crate::inventory::{
-    get_expiration_time, get_message, get_one_after_counter, InMemory, Mutation, OnDisk,
+    get_expiration_time, get_message, get_one_after_counter, mark_processed, pin_message,
+    set_local_inboxes,
+    InMemory, Mutation, OnDisk,
 };
 use crate::private_box::{decrypt, encrypt};
 use async_std::sync::{channel, Receiver, Sender};
@@ -392,6 +394,35 @@ fn calculate_public_half_id(public_encryption_key: &[u8], public_signing_key: &[
     result.to_vec()
 }
 
+async fn share_inboxes(connection: &Connection, on_disk_tx: &Sender<OnDisk>) {
+    let mut statement = connection
+        .prepare(include_str!("../sql/C. Frontend/Fetch inboxes.sql"))
+        .unwrap();
+    let inbox_ids = statement
+        .query_map(params![], |row| row.get::<_, Vec<u8>>(0))
+        .unwrap()
+        .map(|inbox_id| inbox_id.unwrap())
+        .collect();
+    set_local_inboxes(on_disk_tx, inbox_ids).await;
+}
+
+/// In case the inventory lost track of its pins.
+async fn restore_pins(connection: &Connection, on_disk_tx: &Sender<OnDisk>) {
+    let mut statement = connection
+        .prepare(include_str!(
+            "../sql/C. Frontend/Fetch derived inventory items.sql"
+        ))
+        .unwrap();
+    let hashes: Vec<Vec<u8>> = statement
+        .query_map(params![], |row| row.get(0))
+        .unwrap()
+        .map(|hash| hash.unwrap())
+        .collect();
+    for hash in hashes {
+        pin_message(on_disk_tx, Arc::new(hash)).await;
+    }
+}
+
 /// This task executes blocking DB operations.
 pub async fn derive(
     in_memory_tx: Sender<InMemory>,
@@ -704,6 +735,9 @@ pub async fn derive(
 
     let mut inbox_expiration_time: HashMap<Vec<u8>, i64> = HashMap::new();
 
+    share_inboxes(&connection, &on_disk_tx).await;
+    restore_pins(&connection, &on_disk_tx).await;
+
     let (multiplexed_tx, multiplexed_rx) = channel(1);
     multiplex(multiplexed_tx, mutate_rx, command_rx);
 
@@ -751,6 +785,7 @@ pub async fn derive(
                                                 None => expiration_time,
                                             },
                                         );
+                                        pin_message(&on_disk_tx, hash.clone()).await;
                                         event_tx
                                             .send(Event::Inbox {
                                                 global_id: inbox_id,
@@ -812,6 +847,7 @@ pub async fn derive(
                                             ],
                                         )
                                         .unwrap();
+                                    pin_message(&on_disk_tx, hash.clone()).await;
                                     if current_expiration_time > stored_message_expiration_time {
                                         event_tx
                                             .send(Event::MessageExpirationTimeExtended {
@@ -844,6 +880,7 @@ pub async fn derive(
                                 params![&hash.clone() as &Vec<u8>, &global_id, &inbox_id],
                             )
                             .unwrap();
+                        pin_message(&on_disk_tx, hash.clone()).await;
 
                         event_tx
                             .send(Event::Message {
@@ -855,6 +892,7 @@ pub async fn derive(
                             })
                             .await;
                     }
+                    mark_processed(&on_disk_tx, hash).await;
                 }
                 Mutation::Purge(hash) => {
                     let mut statement = connection
@@ -932,6 +970,7 @@ pub async fn derive(
                             ],
                         )
                         .unwrap();
+                    share_inboxes(&connection, &on_disk_tx).await;
                     id_and_public_half_tx
                         .send((
                             global_id,
@@ -987,6 +1026,7 @@ pub async fn derive(
                         )
                         .unwrap();
                     inbox_expiration_time.remove(&inbox_id);
+                    share_inboxes(&connection, &on_disk_tx).await;
                 }
                 Command::GetPublicHalfEntry { inbox_id, blob_tx } => {
                     let mut statement = connection
@@ -1371,7 +1411,14 @@ mod tests {
 
         std::thread::spawn(move || {
             let connection = Connection::open_in_memory().unwrap();
-            init_inventory(connection, mutate_tx, in_memory_rx, on_disk_rx);
+            init_inventory(
+                connection,
+                mutate_tx,
+                in_memory_rx,
+                on_disk_rx,
+                Default::default(),
+                Default::default(),
+            );
         });
 
         let mut exec = futures::executor::LocalPool::new();
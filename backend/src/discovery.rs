@@ -0,0 +1,351 @@
+use crate::connect::connect_to_announced_peer;
+use crate::inventory::{get_setting, put_setting, OnDisk};
+use crate::log;
+use crate::session::Context;
+use crate::stdio_ipc::{format_struct, Message};
+use async_std::net::UdpSocket;
+use async_std::sync::Sender;
+use futures::executor::LocalSpawner;
+use futures::future::{select, Either};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use sodiumoxide::crypto::sign;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::rc::Rc;
+use std::time::Duration;
+
+pub const DEFAULT_GROUP: &str = "239.255.70.77:7770";
+const PREFIX: &[u8] = b"CSDISC01";
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_DATAGRAM_LENGTH: usize = 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Body {
+    node_id: Vec<u8>,
+    address: Option<SocketAddr>,
+    noise_public_key: Vec<u8>,
+    signing_public_key: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Announcement {
+    pub node_id: Vec<u8>,
+    pub address: Option<SocketAddr>,
+    pub noise_public_key: Vec<u8>,
+    pub signing_public_key: sign::PublicKey,
+}
+
+pub async fn load_signing_keypair(
+    on_disk_tx: &Sender<OnDisk>,
+) -> (sign::PublicKey, sign::SecretKey) {
+    let public = get_setting(on_disk_tx, "discovery public key").await;
+    let secret = get_setting(on_disk_tx, "discovery secret key").await;
+    if let (Some(public), Some(secret)) = (public, secret) {
+        if let (Some(public), Some(secret)) = (
+            sign::PublicKey::from_slice(&public),
+            sign::SecretKey::from_slice(&secret),
+        ) {
+            return (public, secret);
+        }
+    }
+    let (public, secret) = sign::gen_keypair();
+    put_setting(on_disk_tx, "discovery public key", public.0.to_vec()).await;
+    put_setting(on_disk_tx, "discovery secret key", secret.0.to_vec()).await;
+    (public, secret)
+}
+
+pub fn encode(
+    node_id: &[u8],
+    address: Option<SocketAddr>,
+    noise_public_key: &[u8],
+    keypair: &(sign::PublicKey, sign::SecretKey),
+) -> Vec<u8> {
+    let (public, secret) = keypair;
+    let body = serde_json::to_vec(&Body {
+        node_id: node_id.to_vec(),
+        address,
+        noise_public_key: noise_public_key.to_vec(),
+        signing_public_key: public.0.to_vec(),
+    })
+    .unwrap();
+    let mut datagram = PREFIX.to_vec();
+    datagram.extend_from_slice(&sign::sign(&body, secret));
+    datagram
+}
+
+/// An unspecified address is replaced with the address the announcement came
+/// from.
+pub fn decode(datagram: &[u8], source: SocketAddr) -> Option<Announcement> {
+    if !datagram.starts_with(PREFIX) {
+        return None;
+    }
+    let signed = &datagram[PREFIX.len()..];
+    if signed.len() < sign::SIGNATUREBYTES {
+        return None;
+    }
+    let body: Body = serde_json::from_slice(&signed[sign::SIGNATUREBYTES..]).ok()?;
+    let signing_public_key = sign::PublicKey::from_slice(&body.signing_public_key)?;
+    sign::verify(signed, &signing_public_key).ok()?;
+    let address = body.address.map(|mut address| {
+        if address.ip().is_unspecified() {
+            address.set_ip(source.ip());
+        }
+        address
+    });
+    Some(Announcement {
+        node_id: body.node_id,
+        address,
+        noise_public_key: body.noise_public_key,
+        signing_public_key,
+    })
+}
+
+pub fn bind(group: SocketAddrV4, interface: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(SocketAddrV4::new(
+        Ipv4Addr::UNSPECIFIED,
+        group.port(),
+    )))?;
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(UdpSocket::from(socket.into_udp_socket()))
+}
+
+#[derive(Default)]
+pub struct Peers {
+    /// The first key a node ID is announced with is the only one accepted for it
+    /// afterwards, so other nodes can't redirect connections to a known peer.
+    signing_keys: HashMap<Vec<u8>, sign::PublicKey>,
+    dialing: HashSet<Vec<u8>>,
+}
+
+pub enum Sighting {
+    New,
+    Known,
+    Impostor,
+}
+
+impl Peers {
+    pub fn sight(&mut self, announcement: &Announcement) -> Sighting {
+        match self.signing_keys.get(&announcement.node_id) {
+            Some(key) if *key == announcement.signing_public_key => Sighting::Known,
+            Some(_) => Sighting::Impostor,
+            None => {
+                self.signing_keys.insert(
+                    announcement.node_id.clone(),
+                    announcement.signing_public_key,
+                );
+                Sighting::New
+            }
+        }
+    }
+}
+
+async fn announce(socket: &UdpSocket, group: SocketAddrV4, datagram: &[u8]) {
+    if let Err(error) = socket.send_to(datagram, group).await {
+        log::warning(format!(
+            "Failed to send discovery announcement: {:?}",
+            error
+        ));
+    }
+}
+
+/// When both peers accept incoming connections, only the one with the smaller
+/// node ID dials.
+pub async fn run(
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    listen_address: Option<SocketAddr>,
+    keypair: (sign::PublicKey, sign::SecretKey),
+    context: Context,
+    spawner: LocalSpawner,
+) {
+    let datagram = encode(
+        &context.node_id,
+        listen_address,
+        &context.noise_keypair.public,
+        &keypair,
+    );
+    let peers = Rc::new(RefCell::new(Peers::default()));
+    announce(&socket, group, &datagram).await;
+    let mut buffer = [0u8; MAX_DATAGRAM_LENGTH];
+    let mut next_announcement = Box::pin(async_std::task::sleep(ANNOUNCE_INTERVAL));
+    loop {
+        let (length, source) = match select(
+            Box::pin(socket.recv_from(&mut buffer)),
+            &mut next_announcement,
+        )
+        .await
+        {
+            Either::Left((Ok(received), _)) => received,
+            Either::Left((Err(error), _)) => {
+                log::warning(format!(
+                    "Failed to receive discovery announcement: {:?}",
+                    error
+                ));
+                continue;
+            }
+            Either::Right(_) => {
+                announce(&socket, group, &datagram).await;
+                next_announcement = Box::pin(async_std::task::sleep(ANNOUNCE_INTERVAL));
+                continue;
+            }
+        };
+        let announcement = match decode(&buffer[..length], source) {
+            Some(announcement) => announcement,
+            None => continue,
+        };
+        if announcement.node_id == *context.node_id {
+            continue;
+        }
+        match peers.borrow_mut().sight(&announcement) {
+            Sighting::New => log::ipc(format_struct(&Message::PeerDiscovered {
+                node_id: &announcement.node_id,
+                address: announcement.address.map(|address| address.to_string()),
+                public_key: &announcement.noise_public_key,
+            })),
+            Sighting::Known => {}
+            Sighting::Impostor => {
+                log::warning(format!(
+                    "Ignored discovery announcement from {} with a mismatched key",
+                    source
+                ));
+                continue;
+            }
+        }
+
+        let address = match announcement.address {
+            Some(address) => address,
+            None => continue,
+        };
+        if listen_address.is_some() && *context.node_id > announcement.node_id {
+            continue;
+        }
+        if peers.borrow().dialing.contains(&announcement.node_id) {
+            continue;
+        }
+        {
+            let connections = context.connections.read().await;
+            if connections.is_full() || connections.is_connected(&announcement.node_id) {
+                continue;
+            }
+        }
+
+        peers
+            .borrow_mut()
+            .dialing
+            .insert(announcement.node_id.clone());
+        let done = {
+            let peers = peers.clone();
+            let node_id = announcement.node_id.clone();
+            move || {
+                peers.borrow_mut().dialing.remove(&node_id);
+            }
+        };
+        let (done1, done2, done3) = (done.clone(), done.clone(), done);
+        connect_to_announced_peer(
+            address.to_string(),
+            announcement.noise_public_key,
+            format!("discovery-{}", base64::encode(&announcement.node_id)),
+            context.clone(),
+            spawner.clone(),
+            move |error| {
+                log::warning(format!(
+                    "Can't connect to discovered peer {} due to error {:?}",
+                    address, error
+                ));
+                done1();
+            },
+            move |error| {
+                log::warning(format!(
+                    "Error occurred while reconciling with discovered peer {} due to error {:?}",
+                    address, error
+                ));
+                done2();
+            },
+            move || {
+                log::notice(format!("Connection to discovered peer {} severed", address));
+                done3();
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announcements_are_signed() {
+        sodiumoxide::init().unwrap();
+        let keypair = sign::gen_keypair();
+        let source: SocketAddr = "192.168.1.2:7770".parse().unwrap();
+        let datagram = encode(
+            &[1; 32],
+            Some("0.0.0.0:8080".parse().unwrap()),
+            &[2; 32],
+            &keypair,
+        );
+        let announcement = decode(&datagram, source).unwrap();
+        assert_eq!(announcement.node_id, vec![1; 32]);
+        assert_eq!(
+            announcement.address,
+            Some("192.168.1.2:8080".parse().unwrap())
+        );
+        assert_eq!(announcement.signing_public_key, keypair.0);
+
+        let mut tampered = datagram.clone();
+        let last = tampered.len() - 2;
+        tampered[last] ^= 1;
+        assert!(decode(&tampered, source).is_none());
+
+        let mut peers = Peers::default();
+        assert!(matches!(peers.sight(&announcement), Sighting::New));
+        assert!(matches!(peers.sight(&announcement), Sighting::Known));
+        let impostor = decode(
+            &encode(&[1; 32], None, &[2; 32], &sign::gen_keypair()),
+            source,
+        )
+        .unwrap();
+        assert!(matches!(peers.sight(&impostor), Sighting::Impostor));
+    }
+
+    #[test]
+    fn announcements_reach_the_group_over_loopback() {
+        sodiumoxide::init().unwrap();
+        async_std::task::block_on(async {
+            let group: SocketAddrV4 = "239.255.70.77:7771".parse().unwrap();
+            let sender = bind(group, Ipv4Addr::LOCALHOST).unwrap();
+            let receiver = bind(group, Ipv4Addr::LOCALHOST).unwrap();
+            let datagram = encode(
+                &[1; 32],
+                Some("0.0.0.0:8080".parse().unwrap()),
+                &[2; 32],
+                &sign::gen_keypair(),
+            );
+            sender.send_to(&datagram, group).await.unwrap();
+
+            let mut buffer = [0u8; MAX_DATAGRAM_LENGTH];
+            let announcement = async_std::future::timeout(Duration::from_secs(10), async {
+                loop {
+                    let (length, source) = receiver.recv_from(&mut buffer).await.unwrap();
+                    if let Some(announcement) = decode(&buffer[..length], source) {
+                        return announcement;
+                    }
+                }
+            })
+            .await
+            .unwrap();
+            assert_eq!(announcement.node_id, vec![1; 32]);
+            assert_eq!(
+                announcement.address,
+                Some("127.0.0.1:8080".parse().unwrap())
+            );
+        });
+    }
+}
@@ -0,0 +1,155 @@
+//! Forwarding metadata travels next to a message rather than inside its
+//! payload, so it isn't covered by the proof of work and any relay can change
+//! it. Strategies only bound how far honest relays spread messages.
+use crate::reconcile_capnp::message;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Flood,
+    Epidemic {
+        max_hops: u32,
+    },
+    /// Binary spray-and-wait. A node holding a single copy keeps it.
+    SprayAndWait {
+        copies: u32,
+    },
+}
+
+impl Default for Strategy {
+    fn default() -> Strategy {
+        Strategy::Flood
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Forwarding {
+    pub hops: u32,
+    /// `None` for messages originating here and from peers that don't limit
+    /// copies, which are treated as holding the full budget.
+    pub copies: Option<u32>,
+}
+
+impl Forwarding {
+    pub fn origin() -> Forwarding {
+        Forwarding {
+            hops: 0,
+            copies: None,
+        }
+    }
+}
+
+pub fn read(reader: message::Reader) -> Forwarding {
+    Forwarding {
+        hops: reader.get_hops(),
+        copies: match reader.get_copies() {
+            0 => None,
+            copies => Some(copies),
+        },
+    }
+}
+
+pub fn write(forwarding: Forwarding, mut builder: message::Builder) {
+    builder.set_hops(forwarding.hops);
+    builder.set_copies(forwarding.copies.unwrap_or(0));
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub sent: Forwarding,
+    pub kept: Forwarding,
+}
+
+impl Strategy {
+    pub fn forward(&self, held: Forwarding) -> Option<Decision> {
+        let hops = held.hops.saturating_add(1);
+        match *self {
+            Strategy::Flood => Some(Decision {
+                sent: Forwarding { hops, ..held },
+                kept: held,
+            }),
+            Strategy::Epidemic { max_hops } => {
+                if held.hops >= max_hops {
+                    return None;
+                }
+                Some(Decision {
+                    sent: Forwarding { hops, ..held },
+                    kept: held,
+                })
+            }
+            Strategy::SprayAndWait { copies } => {
+                let held_copies = held.copies.unwrap_or(copies);
+                if held_copies <= 1 {
+                    return None;
+                }
+                let given = held_copies / 2;
+                Some(Decision {
+                    sent: Forwarding {
+                        hops,
+                        copies: Some(given),
+                    },
+                    kept: Forwarding {
+                        copies: Some(held_copies - given),
+                        ..held
+                    },
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{Conditions, Network};
+    use std::time::Duration;
+
+    const SETTLE: Duration = Duration::from_secs(30);
+
+    fn network(size: usize, seed: u64, strategy: Strategy) -> Network {
+        let mut network = Network::new(size, seed);
+        for node in &mut network.nodes {
+            node.context.forwarding = strategy;
+        }
+        network
+    }
+
+    #[test]
+    fn epidemic_spreading_stops_at_the_hop_limit() {
+        let mut network = network(8, 4, Strategy::Epidemic { max_hops: 3 });
+        for node in 0..7 {
+            network.connect(node, node + 1, Conditions::default());
+        }
+        let hash = network.publish(0, b"three hops".to_vec());
+        network.run_for(SETTLE);
+        for node in 0..8 {
+            let held = network.forwarding(node, hash.clone());
+            if node <= 3 {
+                assert_eq!(held.unwrap().hops, node as u32);
+            } else {
+                assert_eq!(held, None);
+            }
+        }
+    }
+
+    #[test]
+    fn spraying_never_exceeds_the_copy_budget() {
+        let strategy = Strategy::SprayAndWait { copies: 4 };
+        let mut network = network(6, 5, strategy);
+        for a in 0..6 {
+            for b in a + 1..6 {
+                network.connect(a, b, Conditions::default());
+            }
+        }
+        let hash = network.publish(0, b"four copies".to_vec());
+        network.run_for(SETTLE);
+        let held: Vec<_> = (0..6)
+            .filter_map(|node| network.forwarding(node, hash.clone()))
+            .collect();
+        // Copies handed to a peer that got the message elsewhere meanwhile
+        // are lost, so fewer copies may be left.
+        assert!(held.len() > 1 && held.len() <= 4);
+        let copies: u32 = held.iter().map(|held| held.copies.unwrap()).sum();
+        assert!(copies >= held.len() as u32 && copies <= 4);
+        assert!(held.iter().all(|held| strategy.forward(*held).is_none()));
+    }
+}
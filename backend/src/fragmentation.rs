@@ -0,0 +1,559 @@
+//! Carries a byte stream over transports that only move tiny, unreliable
+//! datagrams. Each datagram is a kind byte, a big-endian sequence number and a
+//! body, followed by a 4-byte BLAKE2b checksum of everything before it.
+use async_std::io::{self, Read, Write};
+use async_std::sync::{Receiver, Sender};
+use async_std::task;
+use crypto::blake2b::Blake2b;
+use crypto::digest::Digest;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::stream::{self, Stream, StreamExt};
+use futures::task::{Context, Poll};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryInto;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+const DATA: u8 = 0;
+const FIN: u8 = 1;
+const ACK: u8 = 2;
+const NACK: u8 = 3;
+const CHECKSUM_BYTES: usize = 4;
+const OVERHEAD: usize = 1 + 4 + CHECKSUM_BYTES;
+const MAX_MISSING: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// In bytes.
+    pub mtu: usize,
+    pub window: usize,
+    pub retransmission_timeout: Duration,
+    pub max_retransmissions: u32,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            mtu: 200,
+            window: 32,
+            retransmission_timeout: Duration::from_secs(1),
+            max_retransmissions: 30,
+        }
+    }
+}
+
+pub trait Timer: Clone {
+    type Sleep: Future<Output = ()>;
+
+    fn now(&self) -> Duration;
+    fn sleep(&self, duration: Duration) -> Self::Sleep;
+}
+
+#[derive(Clone, Copy)]
+pub struct RealTime(Instant);
+
+impl Default for RealTime {
+    fn default() -> RealTime {
+        RealTime(Instant::now())
+    }
+}
+
+impl Timer for RealTime {
+    type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+    fn now(&self) -> Duration {
+        self.0.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) -> Self::Sleep {
+        Box::pin(task::sleep(duration))
+    }
+}
+
+/// Stray datagrams from streams that are already over don't open new ones.
+pub fn opens_stream(bytes: &[u8]) -> bool {
+    matches!(decode(bytes), Some(Datagram::Data { sequence: 0, .. }))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Datagram {
+    Data {
+        sequence: u32,
+        payload: Vec<u8>,
+    },
+    /// Sequenced like data.
+    Fin {
+        sequence: u32,
+    },
+    Ack {
+        next: u32,
+    },
+    Nack {
+        missing: Vec<u32>,
+    },
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_BYTES] {
+    let mut hasher = Blake2b::new(CHECKSUM_BYTES);
+    hasher.input(bytes);
+    let mut result = [0u8; CHECKSUM_BYTES];
+    hasher.result(&mut result);
+    result
+}
+
+fn encode(datagram: &Datagram) -> Vec<u8> {
+    let (kind, sequence, body) = match datagram {
+        Datagram::Data { sequence, payload } => (DATA, *sequence, payload.clone()),
+        Datagram::Fin { sequence } => (FIN, *sequence, Vec::new()),
+        Datagram::Ack { next } => (ACK, *next, Vec::new()),
+        Datagram::Nack { missing } => (
+            NACK,
+            0,
+            missing
+                .iter()
+                .flat_map(|sequence| sequence.to_be_bytes().to_vec())
+                .collect(),
+        ),
+    };
+    let mut bytes = vec![kind];
+    bytes.extend_from_slice(&sequence.to_be_bytes());
+    bytes.extend_from_slice(&body);
+    let checksum = checksum(&bytes);
+    bytes.extend_from_slice(&checksum);
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Option<Datagram> {
+    if bytes.len() < OVERHEAD {
+        return None;
+    }
+    let (contents, expected) = bytes.split_at(bytes.len() - CHECKSUM_BYTES);
+    if checksum(contents)[..] != *expected {
+        return None;
+    }
+    let sequence = u32::from_be_bytes(contents[1..5].try_into().unwrap());
+    let body = &contents[5..];
+    match contents[0] {
+        DATA if !body.is_empty() => Some(Datagram::Data {
+            sequence,
+            payload: body.to_vec(),
+        }),
+        FIN if body.is_empty() => Some(Datagram::Fin { sequence }),
+        ACK if body.is_empty() => Some(Datagram::Ack { next: sequence }),
+        NACK if body.len() % 4 == 0 => Some(Datagram::Nack {
+            missing: body
+                .chunks(4)
+                .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+                .collect(),
+        }),
+        _ => None,
+    }
+}
+
+pub struct Fragmented {
+    writer: UnboundedSender<Vec<u8>>,
+    reader: UnboundedReceiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    offset: usize,
+}
+
+impl Read for Fragmented {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        while this.offset == this.buffer.len() {
+            match Pin::new(&mut this.reader).poll_next(cx) {
+                Poll::Ready(Some(bytes)) => {
+                    this.buffer = bytes;
+                    this.offset = 0;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let length = buf.len().min(this.buffer.len() - this.offset);
+        buf[..length].copy_from_slice(&this.buffer[this.offset..this.offset + length]);
+        this.offset += length;
+        Poll::Ready(Ok(length))
+    }
+}
+
+impl Write for Fragmented {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        match self.writer.unbounded_send(buf.to_vec()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The fragmented transport is closed",
+            ))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        self.writer.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+enum Event {
+    Written(Vec<u8>),
+    WriterClosed,
+    Received(Vec<u8>),
+    TransportClosed,
+    Tick,
+}
+
+struct Arq {
+    config: Config,
+    pending: VecDeque<u8>,
+    next_sequence: u32,
+    /// When each fragment was last sent and how often it was resent.
+    unacknowledged: BTreeMap<u32, (Datagram, Duration, u32)>,
+    writer_closed: bool,
+    fin_sent: bool,
+    expected: u32,
+    out_of_order: BTreeMap<u32, Datagram>,
+    requested: BTreeMap<u32, Duration>,
+    fin_received: bool,
+}
+
+impl Arq {
+    fn new(config: Config) -> Arq {
+        Arq {
+            config,
+            pending: VecDeque::new(),
+            next_sequence: 0,
+            unacknowledged: BTreeMap::new(),
+            writer_closed: false,
+            fin_sent: false,
+            expected: 0,
+            out_of_order: BTreeMap::new(),
+            requested: BTreeMap::new(),
+            fin_received: false,
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.fin_sent && self.unacknowledged.is_empty() && self.fin_received
+    }
+
+    fn fill_window(&mut self, now: Duration, outgoing: &mut Vec<Datagram>) {
+        let max_payload = self.config.mtu - OVERHEAD;
+        while self.unacknowledged.len() < self.config.window && !self.fin_sent {
+            let sequence = self.next_sequence;
+            let datagram = if !self.pending.is_empty() {
+                let length = self.pending.len().min(max_payload);
+                Datagram::Data {
+                    sequence,
+                    payload: self.pending.drain(..length).collect(),
+                }
+            } else if self.writer_closed {
+                self.fin_sent = true;
+                Datagram::Fin { sequence }
+            } else {
+                break;
+            };
+            self.next_sequence += 1;
+            self.unacknowledged
+                .insert(sequence, (datagram.clone(), now, 0));
+            outgoing.push(datagram);
+        }
+    }
+
+    fn retransmit_expired(&mut self, now: Duration, outgoing: &mut Vec<Datagram>) -> bool {
+        for (datagram, sent_at, retransmissions) in self.unacknowledged.values_mut() {
+            if now - *sent_at >= self.config.retransmission_timeout {
+                if *retransmissions == self.config.max_retransmissions {
+                    return false;
+                }
+                *sent_at = now;
+                *retransmissions += 1;
+                outgoing.push(datagram.clone());
+            }
+        }
+        true
+    }
+
+    fn deliver(&mut self, datagram: Datagram, reader: &UnboundedSender<Vec<u8>>) {
+        self.expected += 1;
+        match datagram {
+            // The reader may be gone, in which case the bytes are discarded.
+            Datagram::Data { payload, .. } => {
+                let _ = reader.unbounded_send(payload);
+            }
+            _ => {
+                self.fin_received = true;
+                reader.close_channel();
+            }
+        }
+    }
+
+    fn receive(
+        &mut self,
+        datagram: Datagram,
+        now: Duration,
+        reader: &UnboundedSender<Vec<u8>>,
+        outgoing: &mut Vec<Datagram>,
+    ) {
+        match datagram {
+            Datagram::Ack { next } => {
+                self.unacknowledged = self.unacknowledged.split_off(&next);
+            }
+            Datagram::Nack { missing } => {
+                for sequence in missing {
+                    if let Some((datagram, sent_at, _)) = self.unacknowledged.get_mut(&sequence) {
+                        *sent_at = now;
+                        outgoing.push(datagram.clone());
+                    }
+                }
+            }
+            Datagram::Data { sequence, .. } | Datagram::Fin { sequence } => {
+                if sequence < self.expected {
+                    // The acknowledgement was lost.
+                    outgoing.push(Datagram::Ack {
+                        next: self.expected,
+                    });
+                } else if sequence == self.expected {
+                    self.deliver(datagram, reader);
+                    while let Some(datagram) = self.out_of_order.remove(&self.expected) {
+                        self.deliver(datagram, reader);
+                    }
+                    self.requested = self.requested.split_off(&self.expected);
+                    outgoing.push(Datagram::Ack {
+                        next: self.expected,
+                    });
+                } else if ((sequence - self.expected) as usize) < self.config.window {
+                    self.out_of_order.insert(sequence, datagram);
+                    let limit = MAX_MISSING.min((self.config.mtu - OVERHEAD) / 4);
+                    let timeout = self.config.retransmission_timeout;
+                    let missing: Vec<u32> = (self.expected..sequence)
+                        .filter(|sequence| !self.out_of_order.contains_key(sequence))
+                        .filter(|sequence| match self.requested.get(sequence) {
+                            Some(at) => now - *at >= timeout,
+                            None => true,
+                        })
+                        .take(limit)
+                        .collect();
+                    if !missing.is_empty() {
+                        for sequence in &missing {
+                            self.requested.insert(*sequence, now);
+                        }
+                        outgoing.push(Datagram::Nack { missing });
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Once both directions are closed, the peer is still answered for a while in
+/// case the final acknowledgement was lost.
+async fn drive<T: Timer>(
+    transport_tx: Sender<Vec<u8>>,
+    transport_rx: Receiver<Vec<u8>>,
+    config: Config,
+    clock: T,
+    written: UnboundedReceiver<Vec<u8>>,
+    reader: UnboundedSender<Vec<u8>>,
+) {
+    let written = written
+        .map(Event::Written)
+        .chain(stream::once(futures::future::ready(Event::WriterClosed)));
+    let received = transport_rx
+        .map(Event::Received)
+        .chain(stream::once(futures::future::ready(Event::TransportClosed)));
+    let ticks = Box::pin(stream::unfold(clock.clone(), move |clock| async move {
+        clock.sleep(config.retransmission_timeout / 4).await;
+        Some((Event::Tick, clock))
+    }));
+    let mut events = stream::select(written, stream::select(received, ticks));
+
+    let mut arq = Arq::new(config);
+    let mut outgoing = Vec::new();
+    let mut done_at = None;
+    while let Some(event) = events.next().await {
+        let now = clock.now();
+        match event {
+            Event::Written(bytes) => arq.pending.extend(bytes),
+            Event::WriterClosed => arq.writer_closed = true,
+            Event::Received(bytes) => {
+                if let Some(datagram) = decode(&bytes) {
+                    arq.receive(datagram, now, &reader, &mut outgoing);
+                }
+            }
+            Event::TransportClosed => break,
+            Event::Tick => {
+                if !arq.retransmit_expired(now, &mut outgoing) {
+                    break;
+                }
+            }
+        }
+        arq.fill_window(now, &mut outgoing);
+        for datagram in outgoing.drain(..) {
+            transport_tx.send(encode(&datagram)).await;
+        }
+        match done_at {
+            None if arq.done() => done_at = Some(now),
+            Some(done_at) if now - done_at >= config.retransmission_timeout * 4 => break,
+            _ => {}
+        }
+    }
+    reader.close_channel();
+}
+
+/// The returned future does the work and must be spawned alongside the stream.
+pub fn fragment<T: Timer>(
+    transport_tx: Sender<Vec<u8>>,
+    transport_rx: Receiver<Vec<u8>>,
+    config: Config,
+    clock: T,
+) -> (Fragmented, impl Future<Output = ()>) {
+    assert!(config.mtu > OVERHEAD + 4, "MTU is too small");
+    let (writer, written) = unbounded();
+    let (reader_tx, reader) = unbounded();
+    (
+        Fragmented {
+            writer,
+            reader,
+            buffer: Vec::new(),
+            offset: 0,
+        },
+        drive(
+            transport_tx,
+            transport_rx,
+            config,
+            clock,
+            written,
+            reader_tx,
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::Clock;
+    use async_std::sync::channel;
+    use futures::executor::LocalPool;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::task::LocalSpawnExt;
+
+    #[test]
+    fn damaged_datagrams_are_dropped() {
+        let datagrams = vec![
+            Datagram::Data {
+                sequence: 7,
+                payload: b"fragment".to_vec(),
+            },
+            Datagram::Fin { sequence: 8 },
+            Datagram::Ack { next: 9 },
+            Datagram::Nack {
+                missing: vec![1, 2, 3],
+            },
+        ];
+        for datagram in datagrams {
+            let mut bytes = encode(&datagram);
+            assert_eq!(decode(&bytes), Some(datagram));
+            bytes[2] ^= 1;
+            assert_eq!(decode(&bytes), None);
+        }
+        assert_eq!(decode(&[DATA; 4]), None);
+    }
+
+    async fn lossy_relay(from: Receiver<Vec<u8>>, to: Sender<Vec<u8>>) {
+        let mut count = 0;
+        while let Ok(mut datagram) = from.recv().await {
+            count += 1;
+            if count % 4 == 0 {
+                continue;
+            }
+            if count % 7 == 0 {
+                let middle = datagram.len() / 2;
+                datagram[middle] ^= 0xff;
+            }
+            assert!(datagram.len() <= Config::default().mtu);
+            to.send(datagram).await;
+        }
+    }
+
+    #[test]
+    fn streams_survive_a_lossy_link() {
+        let config = Config {
+            retransmission_timeout: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let (a_out_tx, a_out_rx) = channel(1024);
+        let (a_in_tx, a_in_rx) = channel(1024);
+        let (b_out_tx, b_out_rx) = channel(1024);
+        let (b_in_tx, b_in_rx) = channel(1024);
+        let clock = Clock::default();
+        let (a, a_driver) = fragment(a_out_tx, a_in_rx, config, clock.clone());
+        let (b, b_driver) = fragment(b_out_tx, b_in_rx, config, clock.clone());
+
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+        spawner.spawn_local(a_driver).unwrap();
+        spawner.spawn_local(b_driver).unwrap();
+        spawner.spawn_local(lossy_relay(a_out_rx, b_in_tx)).unwrap();
+        spawner.spawn_local(lossy_relay(b_out_rx, a_in_tx)).unwrap();
+
+        let from_a: Vec<u8> = (0..20_000).map(|index| index as u8).collect();
+        let from_b: Vec<u8> = (0..15_000).map(|index| (index * 7) as u8).collect();
+        let (mut a_reader, mut a_writer) = a.split();
+        let (mut b_reader, mut b_writer) = b.split();
+        let (received_by_a, received_by_b) = clock.run_until(&mut pool, async {
+            let a_side = async {
+                a_writer.write_all(&from_a).await.unwrap();
+                a_writer.close().await.unwrap();
+                let mut received = Vec::new();
+                a_reader.read_to_end(&mut received).await.unwrap();
+                received
+            };
+            let b_side = async {
+                b_writer.write_all(&from_b).await.unwrap();
+                b_writer.close().await.unwrap();
+                let mut received = Vec::new();
+                b_reader.read_to_end(&mut received).await.unwrap();
+                received
+            };
+            futures::future::join(a_side, b_side).await
+        });
+        assert_eq!(received_by_a, from_b);
+        assert_eq!(received_by_b, from_a);
+    }
+
+    #[test]
+    fn streams_close_when_the_peer_stops_answering() {
+        let config = Config {
+            retransmission_timeout: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let (transport_tx, _transport_rx) = channel(1024);
+        let (_peer_tx, peer_rx) = channel(1024);
+        let clock = Clock::default();
+        let (stream, driver) = fragment(transport_tx, peer_rx, config, clock.clone());
+
+        let mut pool = LocalPool::new();
+        pool.spawner().spawn_local(driver).unwrap();
+        let (mut reader, mut writer) = stream.split();
+        let received = clock.run_until(&mut pool, async {
+            writer.write_all(b"anyone there?").await.unwrap();
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        assert!(received.is_empty());
+        assert!(clock.now() >= config.retransmission_timeout * config.max_retransmissions);
+    }
+}
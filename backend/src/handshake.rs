@@ -0,0 +1,230 @@
+use crate::acceptance_policy::AcceptancePolicy;
+use crate::reconcile_capnp::{hello, reconcile as Reconcile};
+use crate::session::SessionError;
+use std::fmt;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Peers that predate the handshake speak version 0.
+pub const MINIMUM_PROTOCOL_VERSION: u32 = 0;
+
+/// Remote exceptions arrive with their description wrapped, so the marker is
+/// searched for rather than compared.
+pub const REJECTION_MARKER: &str = "Handshake rejected: ";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Features {
+    pub batching: bool,
+    pub sketches: bool,
+    pub peer_exchange: bool,
+    pub pull: bool,
+}
+
+impl Features {
+    pub fn intersect(&self, other: &Features) -> Features {
+        Features {
+            batching: self.batching && other.batching,
+            sketches: self.sketches && other.sketches,
+            peer_exchange: self.peer_exchange && other.peer_exchange,
+            pull: self.pull && other.pull,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub minimum_protocol_version: u32,
+    pub features: Features,
+    pub max_payload_size: u64,
+    pub max_time_to_live: i64,
+    pub software_version: String,
+    pub min_time_to_live: i64,
+    pub node_id: Vec<u8>,
+    pub pull_only: bool,
+}
+
+impl Hello {
+    pub fn local(policy: &AcceptancePolicy, node_id: &[u8], pull_only: bool) -> Hello {
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            minimum_protocol_version: MINIMUM_PROTOCOL_VERSION,
+            features: Features {
+                batching: true,
+                sketches: true,
+                peer_exchange: true,
+                pull: true,
+            },
+            max_payload_size: policy.max_payload_size,
+            max_time_to_live: policy.max_time_to_live,
+            software_version: format!("contrasleuth/{}", env!("CARGO_PKG_VERSION")),
+            min_time_to_live: policy.min_time_to_live,
+            node_id: node_id.to_vec(),
+            pull_only,
+        }
+    }
+
+    pub fn legacy() -> Hello {
+        Hello {
+            protocol_version: 0,
+            minimum_protocol_version: 0,
+            features: Features {
+                batching: false,
+                sketches: false,
+                peer_exchange: false,
+                pull: false,
+            },
+            max_payload_size: 0,
+            max_time_to_live: 0,
+            software_version: "unknown".to_string(),
+            min_time_to_live: 0,
+            node_id: Vec::new(),
+            pull_only: false,
+        }
+    }
+
+    pub fn acceptance_policy(&self) -> AcceptancePolicy {
+        AcceptancePolicy {
+            max_payload_size: self.max_payload_size,
+            max_time_to_live: self.max_time_to_live,
+            min_time_to_live: self.min_time_to_live,
+        }
+    }
+
+    pub fn read(reader: hello::Reader) -> capnp::Result<Hello> {
+        let features = reader.get_features()?;
+        Ok(Hello {
+            protocol_version: reader.get_protocol_version(),
+            minimum_protocol_version: reader.get_minimum_protocol_version(),
+            features: Features {
+                batching: features.get_batching(),
+                sketches: features.get_sketches(),
+                peer_exchange: features.get_peer_exchange(),
+                pull: features.get_pull(),
+            },
+            max_payload_size: reader.get_max_payload_size(),
+            max_time_to_live: reader.get_max_time_to_live(),
+            software_version: reader.get_software_version()?.to_string(),
+            min_time_to_live: reader.get_min_time_to_live(),
+            node_id: reader.get_node_id()?.to_vec(),
+            pull_only: reader.get_pull_only(),
+        })
+    }
+
+    pub fn write(&self, mut builder: hello::Builder) {
+        builder.set_protocol_version(self.protocol_version);
+        builder.set_minimum_protocol_version(self.minimum_protocol_version);
+        {
+            let mut features = builder.reborrow().init_features();
+            features.set_batching(self.features.batching);
+            features.set_sketches(self.features.sketches);
+            features.set_peer_exchange(self.features.peer_exchange);
+            features.set_pull(self.features.pull);
+        }
+        builder.set_max_payload_size(self.max_payload_size);
+        builder.set_max_time_to_live(self.max_time_to_live);
+        builder.set_software_version(&self.software_version);
+        builder.set_min_time_to_live(self.min_time_to_live);
+        builder.set_node_id(&self.node_id);
+        builder.set_pull_only(self.pull_only);
+    }
+}
+
+#[derive(Debug)]
+pub enum Rejection {
+    IncompatibleProtocolVersion {
+        local_version: u32,
+        local_minimum_version: u32,
+        remote_version: u32,
+        remote_minimum_version: u32,
+    },
+    RejectedByPeer(String),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rejection::IncompatibleProtocolVersion {
+                local_version,
+                local_minimum_version,
+                remote_version,
+                remote_minimum_version,
+            } => write!(
+                f,
+                "Incompatible protocol version: we speak versions {} to {}, the peer speaks versions {} to {}",
+                local_minimum_version, local_version, remote_minimum_version, remote_version
+            ),
+            Rejection::RejectedByPeer(reason) => write!(f, "Rejected by peer: {}", reason),
+        }
+    }
+}
+
+pub fn check(local: &Hello, remote: &Hello) -> Result<(), Rejection> {
+    if remote.protocol_version < local.minimum_protocol_version
+        || local.protocol_version < remote.minimum_protocol_version
+    {
+        return Err(Rejection::IncompatibleProtocolVersion {
+            local_version: local.protocol_version,
+            local_minimum_version: local.minimum_protocol_version,
+            remote_version: remote.protocol_version,
+            remote_minimum_version: remote.minimum_protocol_version,
+        });
+    }
+    Ok(())
+}
+
+pub async fn handshake(remote: &Reconcile::Client, local: &Hello) -> Result<Hello, SessionError> {
+    let mut request = remote.hello_request();
+    local.write(request.get().init_hello());
+    let response = match request.send().promise.await {
+        Ok(response) => response,
+        Err(error) => {
+            if let capnp::ErrorKind::Unimplemented = error.kind {
+                return Ok(Hello::legacy());
+            }
+            if let Some(index) = error.description.find(REJECTION_MARKER) {
+                let reason = error.description[index + REJECTION_MARKER.len()..].to_string();
+                return Err(SessionError::Rejected(Rejection::RejectedByPeer(reason)));
+            }
+            return Err(SessionError::Rpc(error));
+        }
+    };
+    let hello = Hello::read(response.get()?.get_hello()?)?;
+    if let Err(rejection) = check(local, &hello) {
+        return Err(SessionError::Rejected(rejection));
+    }
+    Ok(hello)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_versions(protocol_version: u32, minimum_protocol_version: u32) -> Hello {
+        Hello {
+            protocol_version,
+            minimum_protocol_version,
+            ..Hello::local(&Default::default(), &[], false)
+        }
+    }
+
+    #[test]
+    fn overlapping_versions_are_compatible() {
+        assert!(check(&with_versions(3, 1), &with_versions(5, 2)).is_ok());
+        assert!(check(&with_versions(1, 0), &Hello::legacy()).is_ok());
+    }
+
+    #[test]
+    fn disjoint_versions_are_rejected() {
+        assert!(check(&with_versions(3, 1), &with_versions(6, 4)).is_err());
+        assert!(check(&with_versions(3, 2), &Hello::legacy()).is_err());
+    }
+
+    #[test]
+    fn features_are_intersected() {
+        let local = Hello::local(&Default::default(), &[], false).features;
+        let negotiated = local.intersect(&Hello::legacy().features);
+        assert_eq!(negotiated, Hello::legacy().features);
+        assert_eq!(local.intersect(&local), local);
+    }
+}
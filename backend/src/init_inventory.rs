@@ -1,17 +1,87 @@
+use crate::acceptance_policy::AcceptancePolicy;
 use crate::inventory::{in_memory, on_disk, populate, purge_expired, InMemory, Mutation, OnDisk};
+use crate::inventory_index::InventoryIndex;
+use crate::quota::Quota;
 use async_std::sync::{Mutex, Receiver, RwLock, Sender};
 use async_std::task;
+use futures::channel::mpsc::unbounded;
 use futures::task::LocalSpawn;
+use futures::StreamExt;
 use rusqlite::{params, Connection};
-use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 
+fn migrate(connection: &Connection) {
+    let user_version: i64 = connection
+        .query_row("PRAGMA user_version", params![], |row| row.get(0))
+        .unwrap();
+    if user_version < 1 {
+        for query in &[
+            include_str!("../sql/A. Schema/Backend migration 1 - 1. Sequence column.sql"),
+            include_str!("../sql/A. Schema/Backend migration 1 - 2. Backfill sequence.sql"),
+            include_str!("../sql/A. Schema/Backend migration 1 - 3. Index sequence.sql"),
+            include_str!("../sql/A. Schema/Backend migration 1 - 4. Peer watermark table.sql"),
+            include_str!("../sql/A. Schema/Backend migration 1 - 5. Settings table.sql"),
+            include_str!("../sql/A. Schema/Backend migration 1 - 6. Node ID.sql"),
+            include_str!("../sql/A. Schema/Backend migration 1 - 7. Latest sequence.sql"),
+            include_str!("../sql/A. Schema/Backend migration 1 - 8. User version.sql"),
+        ] {
+            connection.execute(query, params![]).unwrap();
+        }
+    }
+    if user_version < 2 {
+        for query in &[
+            include_str!("../sql/A. Schema/Backend migration 2 - 1. Allowed peer table.sql"),
+            include_str!("../sql/A. Schema/Backend migration 2 - 2. User version.sql"),
+        ] {
+            connection.execute(query, params![]).unwrap();
+        }
+    }
+    if user_version < 3 {
+        for query in &[
+            include_str!("../sql/A. Schema/Backend migration 3 - 1. Known peer table.sql"),
+            include_str!("../sql/A. Schema/Backend migration 3 - 2. User version.sql"),
+        ] {
+            connection.execute(query, params![]).unwrap();
+        }
+    }
+    if user_version < 4 {
+        for query in &[
+            include_str!("../sql/A. Schema/Backend migration 4 - 1. Hops column.sql"),
+            include_str!("../sql/A. Schema/Backend migration 4 - 2. Copies column.sql"),
+            include_str!("../sql/A. Schema/Backend migration 4 - 3. User version.sql"),
+        ] {
+            connection.execute(query, params![]).unwrap();
+        }
+    }
+    if user_version < 5 {
+        for query in &[
+            include_str!("../sql/A. Schema/Backend migration 5 - 1. Pinned column.sql"),
+            include_str!("../sql/A. Schema/Backend migration 5 - 2. User version.sql"),
+        ] {
+            connection.execute(query, params![]).unwrap();
+        }
+    }
+}
+
+fn latest_sequence(connection: &Connection) -> u128 {
+    let sequence: i64 = connection
+        .query_row(
+            include_str!("../sql/B. RPC/Get setting.sql"),
+            params!["latest sequence"],
+            |row| row.get(0),
+        )
+        .unwrap();
+    sequence as u128
+}
+
 /// This function blocks the thread it runs on.
 pub fn init_inventory(
     connection: Connection,
     mutate_tx: Sender<Mutation>,
     in_memory_rx: Receiver<InMemory>,
     on_disk_rx: Receiver<OnDisk>,
+    acceptance_policy: AcceptancePolicy,
+    quota: Quota,
 ) {
     let connection = Arc::new(connection);
     connection
@@ -20,83 +90,51 @@ pub fn init_inventory(
             params![],
         )
         .unwrap();
+    migrate(&connection);
     let mut exec = futures::executor::LocalPool::new();
     let spawner = exec.spawner();
-    let map_counter_to_hash = Arc::new(RwLock::new(BTreeMap::<u128, Arc<Vec<u8>>>::new()));
-    let map_expiration_time_to_hashes = Arc::new(RwLock::new(BTreeMap::<
-        i64,
-        RwLock<HashSet<Arc<Vec<u8>>>>,
-    >::new()));
-    let map_hash_to_counter = Arc::new(RwLock::new(HashMap::<Arc<Vec<u8>>, u128>::new()));
-    let map_hash_to_expiration_time = Arc::new(RwLock::new(HashMap::<Arc<Vec<u8>>, i64>::new()));
 
-    let counter = Arc::new(Mutex::new(0u128));
+    // The inventory queues its mutations, and they are sent from here in
+    // order. `derive_state` may be waiting on the inventory in the meantime.
+    let (queued_tx, mut queued_rx) = unbounded();
+    spawner
+        .spawn_local_obj(
+            Box::new(async move {
+                while let Some(mutation) = queued_rx.next().await {
+                    mutate_tx.send(mutation).await;
+                }
+            })
+            .into(),
+        )
+        .unwrap();
+
+    let mut index = InventoryIndex::with_eviction(quota.eviction);
+    populate(&mut index, &connection, &queued_tx, &acceptance_policy);
+    let index = Arc::new(RwLock::new(index));
 
-    {
-        let map_counter_to_hash = map_counter_to_hash.clone();
-        let map_expiration_time_to_hashes = map_expiration_time_to_hashes.clone();
-        let map_hash_to_counter = map_hash_to_counter.clone();
-        let map_hash_to_expiration_time = map_hash_to_expiration_time.clone();
-        let connection = connection.clone();
-        let mutate_tx = mutate_tx.clone();
-        let counter = counter.clone();
-        spawner
-            .spawn_local_obj(
-                Box::new(async move {
-                    populate(
-                        &map_counter_to_hash,
-                        &map_expiration_time_to_hashes,
-                        &map_hash_to_counter,
-                        &map_hash_to_expiration_time,
-                        &connection,
-                        &mutate_tx,
-                        &counter,
-                    )
-                    .await;
-                })
-                .into(),
-            )
-            .unwrap();
-    }
+    // Sequence numbers are never reused, so peers' watermarks stay valid
+    // after the message with the latest sequence number expires.
+    let counter = Arc::new(Mutex::new(latest_sequence(&connection)));
 
     {
-        let map_counter_to_hash = map_counter_to_hash.clone();
-        let map_hash_to_counter = map_hash_to_counter.clone();
-        let map_hash_to_expiration_time = map_hash_to_expiration_time.clone();
+        let index = index.clone();
         task::spawn(async move {
-            in_memory(
-                in_memory_rx,
-                &map_counter_to_hash,
-                &map_hash_to_counter,
-                &map_hash_to_expiration_time,
-            )
-            .await;
+            in_memory(in_memory_rx, &index).await;
         });
     }
 
     {
-        let map_counter_to_hash = map_counter_to_hash.clone();
-        let map_expiration_time_to_hashes = map_expiration_time_to_hashes.clone();
-        let map_hash_to_counter = map_hash_to_counter.clone();
-        let map_hash_to_expiration_time = map_hash_to_expiration_time.clone();
+        let index = index.clone();
         let connection = connection.clone();
         {
-            let mutate_tx = mutate_tx.clone();
+            let queued_tx = queued_tx.clone();
 
             spawner
                 .spawn_local_obj(
                     Box::new(async move {
                         loop {
                             use std::time::Duration;
-                            purge_expired(
-                                &map_counter_to_hash,
-                                &map_expiration_time_to_hashes,
-                                &map_hash_to_counter,
-                                &map_hash_to_expiration_time,
-                                &connection,
-                                &mutate_tx,
-                            )
-                            .await;
+                            purge_expired(&index, &connection, &queued_tx).await;
                             task::sleep(Duration::from_secs(1)).await;
                         }
                     })
@@ -112,12 +150,10 @@ pub fn init_inventory(
                 on_disk(
                     on_disk_rx,
                     &counter,
-                    &map_counter_to_hash,
-                    &map_expiration_time_to_hashes,
-                    &map_hash_to_counter,
-                    &map_hash_to_expiration_time,
+                    &index,
+                    &quota,
                     &connection,
-                    &mutate_tx,
+                    &queued_tx,
                 )
                 .await;
             })
@@ -1,28 +1,90 @@
+use crate::acceptance_policy::AcceptancePolicy;
+use crate::forwarding::{Forwarding, Strategy};
+use crate::inventory_index::{Entry, InventoryIndex};
+use crate::log;
 use crate::message_hash::message_hash;
+use crate::peer_exchange::{KnownPeer, MAX_KNOWN_PEERS};
+use crate::proof_of_work;
+use crate::pull::Announcement;
+use crate::push_order::{self, Item, PushOrder};
+use crate::quota::Quota;
+use crate::set_reconciliation::{respond, Range, Response, Summary};
 use async_std::sync::{channel, Mutex, Receiver, RwLock, Sender};
 use chrono::Utc;
-use futures_intrusive::sync::ManualResetEvent;
+use futures::channel::mpsc::UnboundedSender;
 use rusqlite::{params, Connection};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub payload: Vec<u8>,
     pub nonce: i64,
     pub expiration_time: i64,
 }
 
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub size: u64,
+    pub achieved_target: u64,
+}
+
+impl Metadata {
+    fn new(payload: &[u8], nonce: i64) -> Metadata {
+        Metadata {
+            size: payload.len() as u64,
+            achieved_target: proof_of_work::achieved_target(payload, nonce),
+        }
+    }
+}
+
 pub enum InMemory {
     GetOneAfterCounter(u128, Sender<(Arc<Vec<u8>>, u128)>),
     MessageExists(Arc<Vec<u8>>, Sender<bool>),
     GetExpirationTime(Arc<Vec<u8>>, Sender<Option<i64>>),
+    GetCounter(Arc<Vec<u8>>, Sender<Option<u128>>),
+    Snapshot(Sender<(Vec<Arc<Vec<u8>>>, u128)>),
+    RespondToRanges(Vec<(Range, Summary)>, Sender<Vec<Response>>),
+    GetAllAfterCounter(u128, PushOrder, Sender<(Vec<Arc<Vec<u8>>>, u128)>),
+    /// Unknown hashes are dropped.
+    Sort(Vec<Arc<Vec<u8>>>, PushOrder, Sender<Vec<Arc<Vec<u8>>>>),
+    /// Messages inserted after the counter, oldest first.
+    GetAnnouncements(u128, usize, Sender<(Vec<Announcement>, u128)>),
 }
 
 pub enum OnDisk {
     GetMessage(Arc<Vec<u8>>, Sender<Option<Message>>),
-    InsertMessage(Message, Arc<ManualResetEvent>),
+    InsertMessage(Message, Forwarding, Sender<Insertion>),
+    PinMessage(Arc<Vec<u8>>),
+    MarkProcessed(Arc<Vec<u8>>),
+    SetLocalInboxes(Vec<Vec<u8>>),
+    #[cfg(test)]
+    GetForwarding(Arc<Vec<u8>>, Sender<Option<Forwarding>>),
+    /// Records the spray-and-wait copies left in the same step, so that
+    /// concurrent sessions can't hand out the same copies.
+    Forward(Arc<Vec<u8>>, Strategy, Sender<Option<Forwarding>>),
+    GetNodeId(Sender<Vec<u8>>),
+    /// Keyed by the peer's Noise static key.
+    GetPeerWatermark(Vec<u8>, Sender<Option<u128>>),
+    SetPeerWatermark(Vec<u8>, u128),
+    GetSetting(&'static str, Sender<Option<Vec<u8>>>),
+    PutSetting(&'static str, Vec<u8>),
+    GetAllowedPeers(Sender<Vec<Vec<u8>>>),
+    AddAllowedPeer(Vec<u8>),
+    RemoveAllowedPeer(Vec<u8>),
+    GetKnownPeers(Sender<Vec<KnownPeer>>),
+    /// Known peers only have their last-seen time updated.
+    PutKnownPeers(Vec<KnownPeer>),
+    PutReachableKnownPeer(String, i64),
+    AddKnownPeerFailure(String),
+    PruneKnownPeers(u32, i64, Sender<usize>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Insertion {
+    Inserted,
+    Duplicate,
+    Refused,
 }
 
 #[derive(Debug)]
@@ -55,177 +117,382 @@ pub async fn get_expiration_time(tx: &Sender<InMemory>, hash: Arc<Vec<u8>>) -> O
     rx1.recv().await.unwrap()
 }
 
+pub async fn get_counter(tx: &Sender<InMemory>, hash: Arc<Vec<u8>>) -> Option<u128> {
+    let (tx1, rx1) = channel(1);
+    tx.send(InMemory::GetCounter(hash, tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn snapshot(tx: &Sender<InMemory>) -> (Vec<Arc<Vec<u8>>>, u128) {
+    let (tx1, rx1) = channel(1);
+    tx.send(InMemory::Snapshot(tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn respond_to_ranges(
+    tx: &Sender<InMemory>,
+    ranges: Vec<(Range, Summary)>,
+) -> Vec<Response> {
+    let (tx1, rx1) = channel(1);
+    tx.send(InMemory::RespondToRanges(ranges, tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn get_all_after_counter(
+    tx: &Sender<InMemory>,
+    counter: u128,
+    order: PushOrder,
+) -> (Vec<Arc<Vec<u8>>>, u128) {
+    let (tx1, rx1) = channel(1);
+    tx.send(InMemory::GetAllAfterCounter(counter, order, tx1))
+        .await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn sort(
+    tx: &Sender<InMemory>,
+    hashes: Vec<Arc<Vec<u8>>>,
+    order: PushOrder,
+) -> Vec<Arc<Vec<u8>>> {
+    let (tx1, rx1) = channel(1);
+    tx.send(InMemory::Sort(hashes, order, tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn get_announcements(
+    tx: &Sender<InMemory>,
+    counter: u128,
+    limit: usize,
+) -> (Vec<Announcement>, u128) {
+    let (tx1, rx1) = channel(1);
+    tx.send(InMemory::GetAnnouncements(counter, limit, tx1))
+        .await;
+    rx1.recv().await.unwrap()
+}
+
 pub async fn get_message(tx: &Sender<OnDisk>, hash: Arc<Vec<u8>>) -> Option<Message> {
     let (tx1, rx1) = channel(1);
     tx.send(OnDisk::GetMessage(hash, tx1)).await;
     rx1.recv().await.unwrap()
 }
 
-pub async fn insert_message(tx: &Sender<OnDisk>, message: Message) {
-    let event = Arc::new(ManualResetEvent::new(false));
-    tx.send(OnDisk::InsertMessage(message, event.clone())).await;
-    event.wait().await;
+pub async fn insert_message(tx: &Sender<OnDisk>, message: Message) -> Insertion {
+    insert_forwarded_message(tx, message, Forwarding::origin()).await
 }
 
-pub async fn in_memory(
-    rx: Receiver<InMemory>,
-    map_counter_to_hash: &RwLock<BTreeMap<u128, Arc<Vec<u8>>>>,
-    map_hash_to_counter: &RwLock<HashMap<Arc<Vec<u8>>, u128>>,
-    map_hash_to_expiration_time: &RwLock<HashMap<Arc<Vec<u8>>, i64>>,
-) {
+pub async fn insert_forwarded_message(
+    tx: &Sender<OnDisk>,
+    message: Message,
+    forwarding: Forwarding,
+) -> Insertion {
+    let (tx1, rx1) = channel(1);
+    tx.send(OnDisk::InsertMessage(message, forwarding, tx1))
+        .await;
+    rx1.recv().await.unwrap()
+}
+
+#[cfg(test)]
+pub async fn get_forwarding(tx: &Sender<OnDisk>, hash: Arc<Vec<u8>>) -> Option<Forwarding> {
+    let (tx1, rx1) = channel(1);
+    tx.send(OnDisk::GetForwarding(hash, tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn forward(
+    tx: &Sender<OnDisk>,
+    hash: Arc<Vec<u8>>,
+    strategy: Strategy,
+) -> Option<Forwarding> {
+    let (tx1, rx1) = channel(1);
+    tx.send(OnDisk::Forward(hash, strategy, tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn pin_message(tx: &Sender<OnDisk>, hash: Arc<Vec<u8>>) {
+    tx.send(OnDisk::PinMessage(hash)).await;
+}
+
+pub async fn mark_processed(tx: &Sender<OnDisk>, hash: Arc<Vec<u8>>) {
+    tx.send(OnDisk::MarkProcessed(hash)).await;
+}
+
+pub async fn set_local_inboxes(tx: &Sender<OnDisk>, inbox_ids: Vec<Vec<u8>>) {
+    tx.send(OnDisk::SetLocalInboxes(inbox_ids)).await;
+}
+
+pub async fn get_node_id(tx: &Sender<OnDisk>) -> Vec<u8> {
+    let (tx1, rx1) = channel(1);
+    tx.send(OnDisk::GetNodeId(tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn get_peer_watermark(tx: &Sender<OnDisk>, peer: Vec<u8>) -> Option<u128> {
+    let (tx1, rx1) = channel(1);
+    tx.send(OnDisk::GetPeerWatermark(peer, tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn set_peer_watermark(tx: &Sender<OnDisk>, peer: Vec<u8>, sequence: u128) {
+    tx.send(OnDisk::SetPeerWatermark(peer, sequence)).await;
+}
+
+pub async fn get_setting(tx: &Sender<OnDisk>, key: &'static str) -> Option<Vec<u8>> {
+    let (tx1, rx1) = channel(1);
+    tx.send(OnDisk::GetSetting(key, tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn put_setting(tx: &Sender<OnDisk>, key: &'static str, value: Vec<u8>) {
+    tx.send(OnDisk::PutSetting(key, value)).await;
+}
+
+pub async fn get_allowed_peers(tx: &Sender<OnDisk>) -> Vec<Vec<u8>> {
+    let (tx1, rx1) = channel(1);
+    tx.send(OnDisk::GetAllowedPeers(tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn add_allowed_peer(tx: &Sender<OnDisk>, public_key: Vec<u8>) {
+    tx.send(OnDisk::AddAllowedPeer(public_key)).await;
+}
+
+pub async fn remove_allowed_peer(tx: &Sender<OnDisk>, public_key: Vec<u8>) {
+    tx.send(OnDisk::RemoveAllowedPeer(public_key)).await;
+}
+
+pub async fn get_known_peers(tx: &Sender<OnDisk>) -> Vec<KnownPeer> {
+    let (tx1, rx1) = channel(1);
+    tx.send(OnDisk::GetKnownPeers(tx1)).await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn put_known_peers(tx: &Sender<OnDisk>, peers: Vec<KnownPeer>) {
+    tx.send(OnDisk::PutKnownPeers(peers)).await;
+}
+
+pub async fn put_reachable_known_peer(tx: &Sender<OnDisk>, address: String, now: i64) {
+    tx.send(OnDisk::PutReachableKnownPeer(address, now)).await;
+}
+
+pub async fn add_known_peer_failure(tx: &Sender<OnDisk>, address: String) {
+    tx.send(OnDisk::AddKnownPeerFailure(address)).await;
+}
+
+pub async fn prune_known_peers(tx: &Sender<OnDisk>, max_failures: u32, seen_before: i64) -> usize {
+    let (tx1, rx1) = channel(1);
+    tx.send(OnDisk::PruneKnownPeers(max_failures, seen_before, tx1))
+        .await;
+    rx1.recv().await.unwrap()
+}
+
+pub async fn in_memory(rx: Receiver<InMemory>, index: &RwLock<InventoryIndex>) {
     while let Ok(command) = rx.recv().await {
         match command {
             InMemory::GetOneAfterCounter(counter, tx) => {
-                use std::ops::Bound::{Excluded, Unbounded};
-                let to_be_sent = (|| async {
-                    for (&counter, hash) in map_counter_to_hash
-                        .read()
-                        .await
-                        .range((Excluded(counter), Unbounded))
-                    {
-                        return Some((hash.clone(), counter));
-                    }
-
-                    None
-                })()
-                .await;
-
+                let to_be_sent = index
+                    .read()
+                    .await
+                    .after_counter(counter)
+                    .next()
+                    .map(|(counter, hash)| (hash.clone(), counter));
                 if let Some(it) = to_be_sent {
                     tx.send(it).await;
                 }
             }
             InMemory::MessageExists(hash, tx) => {
-                tx.send(map_hash_to_counter.read().await.contains_key(&hash))
-                    .await;
+                tx.send(index.read().await.contains(&hash)).await;
             }
             InMemory::GetExpirationTime(hash, tx) => {
-                tx.send(match map_hash_to_expiration_time.read().await.get(&hash) {
-                    Some(it) => Some(*it),
-                    None => None,
-                })
+                tx.send(
+                    index
+                        .read()
+                        .await
+                        .get(&hash)
+                        .map(|entry| entry.expiration_time),
+                )
                 .await;
             }
+            InMemory::GetCounter(hash, tx) => {
+                tx.send(index.read().await.get(&hash).map(|entry| entry.counter))
+                    .await;
+            }
+            InMemory::Snapshot(tx) => {
+                let index = index.read().await;
+                let latest_counter = index.latest_counter().unwrap_or(0);
+                let hashes = index.hashes().cloned().collect();
+                drop(index);
+                tx.send((hashes, latest_counter)).await;
+            }
+            InMemory::RespondToRanges(ranges, tx) => {
+                let index = index.read().await;
+                let responses = ranges
+                    .iter()
+                    .map(|(range, summary)| respond(index.by_hash(), range, summary))
+                    .collect();
+                drop(index);
+                tx.send(responses).await;
+            }
+            InMemory::GetAllAfterCounter(counter, order, tx) => {
+                let index = index.read().await;
+                let latest_counter = match index.latest_counter() {
+                    Some(latest_counter) => latest_counter.max(counter),
+                    None => counter,
+                };
+                let hashes = index
+                    .after_counter(counter)
+                    .map(|(_, hash)| hash.clone())
+                    .collect();
+                let hashes = sort_items(hashes, order, &index);
+                drop(index);
+                tx.send((hashes, latest_counter)).await;
+            }
+            InMemory::Sort(hashes, order, tx) => {
+                let hashes = sort_items(hashes, order, &*index.read().await);
+                tx.send(hashes).await;
+            }
+            InMemory::GetAnnouncements(counter, limit, tx) => {
+                let index = index.read().await;
+                let mut last_counter = counter;
+                let mut announcements = Vec::new();
+                for (counter, hash) in index.after_counter(counter).take(limit) {
+                    last_counter = counter;
+                    if let Some(entry) = index.get(hash) {
+                        announcements.push(Announcement {
+                            hash: hash.clone(),
+                            size: entry.metadata.size,
+                            expiration_time: entry.expiration_time,
+                        });
+                    }
+                }
+                drop(index);
+                tx.send((announcements, last_counter)).await;
+            }
         }
     }
 }
 
-async fn add_hash(
-    hash: Arc<Vec<u8>>,
-    counter: u128,
-    expiration_time: i64,
-    map_counter_to_hash: &RwLock<BTreeMap<u128, Arc<Vec<u8>>>>,
-    map_expiration_time_to_hashes: &RwLock<BTreeMap<i64, RwLock<HashSet<Arc<Vec<u8>>>>>>,
-    map_hash_to_counter: &RwLock<HashMap<Arc<Vec<u8>>, u128>>,
-    map_hash_to_expiration_time: &RwLock<HashMap<Arc<Vec<u8>>, i64>>,
-) {
-    map_counter_to_hash
-        .write()
-        .await
-        .insert(counter, hash.clone());
-    map_expiration_time_to_hashes
-        .write()
-        .await
-        .entry(expiration_time)
-        .or_insert(RwLock::new(HashSet::new()))
-        .write()
-        .await
-        .insert(hash.clone());
-    map_hash_to_counter
-        .write()
-        .await
-        .insert(hash.clone(), counter);
-    map_hash_to_expiration_time
-        .write()
-        .await
-        .insert(hash.clone(), expiration_time);
-}
-
-pub async fn populate(
-    map_counter_to_hash: &RwLock<BTreeMap<u128, Arc<Vec<u8>>>>,
-    map_expiration_time_to_hashes: &RwLock<BTreeMap<i64, RwLock<HashSet<Arc<Vec<u8>>>>>>,
-    map_hash_to_counter: &RwLock<HashMap<Arc<Vec<u8>>, u128>>,
-    map_hash_to_expiration_time: &RwLock<HashMap<Arc<Vec<u8>>, i64>>,
+fn sort_items(
+    hashes: Vec<Arc<Vec<u8>>>,
+    order: PushOrder,
+    index: &InventoryIndex,
+) -> Vec<Arc<Vec<u8>>> {
+    let mut items: Vec<_> = hashes
+        .into_iter()
+        .filter_map(|hash| {
+            let entry = index.get(&hash)?;
+            let item = Item {
+                counter: entry.counter,
+                expiration_time: entry.expiration_time,
+                size: entry.metadata.size,
+                achieved_target: entry.metadata.achieved_target,
+            };
+            Some((hash, item))
+        })
+        .collect();
+    push_order::sort(&mut items, order, Utc::now().timestamp());
+    items.into_iter().map(|(hash, _)| hash).collect()
+}
+
+/// Runs before the other tasks of the inventory start, so that nothing sees a
+/// partial inventory.
+pub fn populate(
+    index: &mut InventoryIndex,
     connection: &Connection,
-    mutate_tx: &Sender<Mutation>,
-    counter: &Mutex<u128>,
+    mutate_tx: &UnboundedSender<Mutation>,
+    policy: &AcceptancePolicy,
 ) {
-    let mut counter = counter.lock().await;
+    let now = Utc::now().timestamp();
     let mut statement = connection
         .prepare(include_str!("../sql/B. RPC/Retrieve messages.sql"))
         .unwrap();
     let mut rows = statement.query(params![]).unwrap();
+    // Messages stored before the policy got stricter.
+    let mut violating = Vec::new();
     while let Some(row) = rows.next().unwrap() {
-        *counter += 1;
         let hash: Vec<u8> = row.get(0).unwrap();
-        let hash = Arc::new(hash);
+        let payload: Vec<u8> = row.get(1).unwrap();
+        let nonce: i64 = row.get(2).unwrap();
         let expiration_time: i64 = row.get(3).unwrap();
-        add_hash(
+        let sequence: i64 = row.get(4).unwrap();
+        let pinned: bool = row.get(5).unwrap();
+        if let Err(violation) = policy.check_stored(payload.len(), expiration_time, now) {
+            log::notice(format!(
+                "Deleting a stored message that violates the acceptance policy: {}",
+                violation
+            ));
+            violating.push(hash);
+            continue;
+        }
+        let hash = Arc::new(hash);
+        index.insert(
             hash.clone(),
-            *counter,
-            expiration_time,
-            &map_counter_to_hash,
-            &map_expiration_time_to_hashes,
-            &map_hash_to_counter,
-            &map_hash_to_expiration_time,
-        )
-        .await;
-        mutate_tx.send(Mutation::Insert(hash.clone())).await;
+            Entry {
+                counter: sequence as u128,
+                expiration_time,
+                metadata: Metadata::new(&payload, nonce),
+                pinned,
+                // Every stored message is handed to `derive_state` again.
+                pending: true,
+            },
+        );
+        let _ = mutate_tx.unbounded_send(Mutation::Insert(hash));
+    }
+    for hash in violating {
+        connection
+            .execute(
+                include_str!("../sql/B. RPC/Delete message.sql"),
+                params![hash],
+            )
+            .unwrap();
+    }
+}
+
+fn delete(hashes: &[Arc<Vec<u8>>], connection: &Connection) {
+    for hash in hashes {
+        connection
+            .execute(
+                include_str!("../sql/B. RPC/Delete message.sql"),
+                params![hash as &Vec<u8>],
+            )
+            .unwrap();
     }
 }
 
 pub async fn purge_expired(
-    map_counter_to_hash: &RwLock<BTreeMap<u128, Arc<Vec<u8>>>>,
-    map_expiration_time_to_hashes: &RwLock<BTreeMap<i64, RwLock<HashSet<Arc<Vec<u8>>>>>>,
-    map_hash_to_counter: &RwLock<HashMap<Arc<Vec<u8>>, u128>>,
-    map_hash_to_expiration_time: &RwLock<HashMap<Arc<Vec<u8>>, i64>>,
+    index: &RwLock<InventoryIndex>,
     connection: &Connection,
-    mutate_tx: &Sender<Mutation>,
+    mutate_tx: &UnboundedSender<Mutation>,
 ) {
-    let now = Utc::now().timestamp();
-    let mut map_counter_to_hash = map_counter_to_hash.write().await;
-    let mut map_expiration_time_to_hashes = map_expiration_time_to_hashes.write().await;
-    let mut map_hash_to_counter = map_hash_to_counter.write().await;
-    let mut map_hash_to_expiration_time = map_hash_to_expiration_time.write().await;
-    let mut expiration_times = Vec::new();
-    for (time, hashes) in map_expiration_time_to_hashes.range(..=now) {
-        {
-            let hashes = hashes.read().await;
-            for hash in hashes.iter() {
-                let hash = hash.clone();
-                match map_hash_to_counter.get(&hash) {
-                    None => continue,
-                    Some(counter) => {
-                        map_counter_to_hash.remove(&counter);
-                        map_hash_to_expiration_time.remove(&hash);
-                        map_hash_to_counter.remove(&hash);
-                        connection
-                            .execute(
-                                include_str!("../sql/B. RPC/Delete message.sql"),
-                                params![&hash as &Vec<u8>],
-                            )
-                            .unwrap();
-                        mutate_tx.send(Mutation::Purge(hash)).await;
-                    }
-                }
-            }
-        }
-        expiration_times.push(*time);
-    }
-    for expiration_time in expiration_times {
-        map_expiration_time_to_hashes.remove(&expiration_time);
+    let expired = index.write().await.remove_expired(Utc::now().timestamp());
+    delete(&expired, connection);
+    for hash in expired {
+        let _ = mutate_tx.unbounded_send(Mutation::Purge(hash));
     }
 }
 
-/// This task executes blocking DB operations.
+fn read_forwarding(connection: &Connection, hash: &[u8]) -> Option<Forwarding> {
+    let mut statement = connection
+        .prepare(include_str!("../sql/B. RPC/Get forwarding.sql"))
+        .unwrap();
+    let mut rows = statement.query(params![hash]).unwrap();
+    let row = rows.next().unwrap()?;
+    let hops: i64 = row.get(0).unwrap();
+    let copies: Option<i64> = row.get(1).unwrap();
+    Some(Forwarding {
+        hops: hops as u32,
+        copies: copies.map(|copies| copies as u32),
+    })
+}
+
+/// This task executes blocking DB operations. Mutations are queued rather than
+/// sent, as `derive_state` may be waiting on this task.
 pub async fn on_disk(
     rx: Receiver<OnDisk>,
     counter: &Mutex<u128>,
-    map_counter_to_hash: &RwLock<BTreeMap<u128, Arc<Vec<u8>>>>,
-    map_expiration_time_to_hashes: &RwLock<BTreeMap<i64, RwLock<HashSet<Arc<Vec<u8>>>>>>,
-    map_hash_to_counter: &RwLock<HashMap<Arc<Vec<u8>>, u128>>,
-    map_hash_to_expiration_time: &RwLock<HashMap<Arc<Vec<u8>>, i64>>,
+    index: &RwLock<InventoryIndex>,
+    quota: &Quota,
     connection: &Connection,
-    mutate_tx: &Sender<Mutation>,
+    mutate_tx: &UnboundedSender<Mutation>,
 ) {
     // It is better to execute SQLite operations sequentially. SQLite locks the database
     // during an operation, so there is nothing gained from spawning dedicated tasks for
@@ -258,28 +525,257 @@ pub async fn on_disk(
                     nonce,
                     expiration_time,
                 },
-                event,
+                forwarding,
+                tx,
             ) => {
-                *counter.lock().await += 1;
                 let hash = Arc::new(message_hash(&payload, expiration_time).to_vec());
-                add_hash(
+                let mut counter = counter.lock().await;
+                let mut index = index.write().await;
+                if index.contains(&hash) {
+                    tx.send(Insertion::Duplicate).await;
+                    continue;
+                }
+                let evicted = match quota.make_room(&index, Some(payload.len() as u64)) {
+                    Some(evicted) => evicted,
+                    None => {
+                        log::warning("Refusing a message because the storage quota is exhausted");
+                        tx.send(Insertion::Refused).await;
+                        continue;
+                    }
+                };
+                for hash in &evicted {
+                    index.remove(hash);
+                }
+                *counter += 1;
+                index.insert(
                     hash.clone(),
-                    *counter.lock().await,
-                    expiration_time,
-                    &map_counter_to_hash,
-                    &map_expiration_time_to_hashes,
-                    &map_hash_to_counter,
-                    &map_hash_to_expiration_time,
-                )
-                .await;
+                    Entry {
+                        counter: *counter,
+                        expiration_time,
+                        metadata: Metadata::new(&payload, nonce),
+                        pinned: false,
+                        pending: true,
+                    },
+                );
+                drop(index);
+                delete(&evicted, connection);
+                for hash in evicted {
+                    let _ = mutate_tx.unbounded_send(Mutation::Purge(hash));
+                }
                 connection
                     .execute(
                         include_str!("../sql/B. RPC/Put message.sql"),
-                        params![&hash as &Vec<u8>, payload, nonce, expiration_time],
+                        params![
+                            &hash as &Vec<u8>,
+                            payload,
+                            nonce,
+                            expiration_time,
+                            *counter as i64,
+                            forwarding.hops as i64,
+                            forwarding.copies.map(|copies| copies as i64)
+                        ],
+                    )
+                    .unwrap();
+                connection
+                    .execute(
+                        include_str!("../sql/B. RPC/Put setting.sql"),
+                        params!["latest sequence", *counter as i64],
+                    )
+                    .unwrap();
+                drop(counter);
+                let _ = mutate_tx.unbounded_send(Mutation::Insert(hash));
+                tx.send(Insertion::Inserted).await;
+            }
+            OnDisk::PinMessage(hash) => {
+                if index.write().await.pin(&hash) {
+                    connection
+                        .execute(
+                            include_str!("../sql/B. RPC/Pin message.sql"),
+                            params![&hash as &Vec<u8>],
+                        )
+                        .unwrap();
+                }
+            }
+            OnDisk::MarkProcessed(hash) => {
+                // The quota may have been lowered since the messages were
+                // stored, and stored messages only become evictable here.
+                let evicted = {
+                    let mut index = index.write().await;
+                    if !index.mark_processed(&hash) {
+                        continue;
+                    }
+                    let evicted = quota.make_room(&index, None).unwrap_or_default();
+                    for hash in &evicted {
+                        index.remove(hash);
+                    }
+                    evicted
+                };
+                delete(&evicted, connection);
+                for hash in evicted {
+                    let _ = mutate_tx.unbounded_send(Mutation::Purge(hash));
+                }
+            }
+            OnDisk::SetLocalInboxes(inbox_ids) => index
+                .write()
+                .await
+                .set_local_inboxes(inbox_ids, Utc::now().timestamp()),
+            #[cfg(test)]
+            OnDisk::GetForwarding(hash, tx) => {
+                tx.send(read_forwarding(connection, &hash)).await;
+            }
+            OnDisk::Forward(hash, strategy, tx) => {
+                let decision = read_forwarding(connection, &hash)
+                    .and_then(|held| Some((held, strategy.forward(held)?)));
+                // Copies are handed over when the message is offered. Copies
+                // lost with a failed request aren't recovered, so the budget
+                // is never exceeded.
+                if let Some((held, decision)) = &decision {
+                    if decision.kept != *held {
+                        if let Some(copies) = decision.kept.copies {
+                            connection
+                                .execute(
+                                    include_str!("../sql/B. RPC/Update copies.sql"),
+                                    params![copies as i64, &hash as &Vec<u8>],
+                                )
+                                .unwrap();
+                        }
+                    }
+                }
+                tx.send(decision.map(|(_, decision)| decision.sent)).await;
+            }
+            OnDisk::GetNodeId(tx) => {
+                let node_id: Vec<u8> = connection
+                    .query_row(
+                        include_str!("../sql/B. RPC/Get setting.sql"),
+                        params!["node id"],
+                        |row| row.get(0),
+                    )
+                    .unwrap();
+                tx.send(node_id).await;
+            }
+            OnDisk::GetPeerWatermark(peer, tx) => {
+                let mut statement = connection
+                    .prepare(include_str!("../sql/B. RPC/Get peer watermark.sql"))
+                    .unwrap();
+                let mut rows = statement.query(params![peer]).unwrap();
+                match rows.next().unwrap() {
+                    Some(row) => {
+                        let sequence: i64 = row.get(0).unwrap();
+                        tx.send(Some(sequence as u128)).await;
+                    }
+                    None => tx.send(None).await,
+                }
+            }
+            OnDisk::SetPeerWatermark(peer, sequence) => {
+                connection
+                    .execute(
+                        include_str!("../sql/B. RPC/Put peer watermark.sql"),
+                        params![peer, sequence as i64],
+                    )
+                    .unwrap();
+            }
+            OnDisk::GetSetting(key, tx) => {
+                let mut statement = connection
+                    .prepare(include_str!("../sql/B. RPC/Get setting.sql"))
+                    .unwrap();
+                let mut rows = statement.query(params![key]).unwrap();
+                match rows.next().unwrap() {
+                    Some(row) => tx.send(Some(row.get(0).unwrap())).await,
+                    None => tx.send(None).await,
+                }
+            }
+            OnDisk::PutSetting(key, value) => {
+                connection
+                    .execute(
+                        include_str!("../sql/B. RPC/Put setting.sql"),
+                        params![key, value],
+                    )
+                    .unwrap();
+            }
+            OnDisk::GetAllowedPeers(tx) => {
+                let mut statement = connection
+                    .prepare(include_str!("../sql/B. RPC/Get allowed peers.sql"))
+                    .unwrap();
+                let public_keys: Vec<Vec<u8>> = statement
+                    .query_map(params![], |row| row.get::<_, Vec<u8>>(0))
+                    .unwrap()
+                    .map(|public_key| public_key.unwrap())
+                    .collect();
+                tx.send(public_keys).await;
+            }
+            OnDisk::AddAllowedPeer(public_key) => {
+                connection
+                    .execute(
+                        include_str!("../sql/B. RPC/Put allowed peer.sql"),
+                        params![public_key],
+                    )
+                    .unwrap();
+            }
+            OnDisk::RemoveAllowedPeer(public_key) => {
+                connection
+                    .execute(
+                        include_str!("../sql/B. RPC/Delete allowed peer.sql"),
+                        params![public_key],
+                    )
+                    .unwrap();
+            }
+            OnDisk::GetKnownPeers(tx) => {
+                let mut statement = connection
+                    .prepare(include_str!("../sql/B. RPC/Get known peers.sql"))
+                    .unwrap();
+                let peers: Vec<KnownPeer> = statement
+                    .query_map(params![], |row| {
+                        Ok(KnownPeer {
+                            address: row.get(0)?,
+                            last_seen: row.get(1)?,
+                            failures: row.get::<_, i64>(2)? as u32,
+                        })
+                    })
+                    .unwrap()
+                    .map(|peer| peer.unwrap())
+                    .collect();
+                tx.send(peers).await;
+            }
+            OnDisk::PutKnownPeers(peers) => {
+                for peer in peers {
+                    connection
+                        .execute(
+                            include_str!("../sql/B. RPC/Put known peer.sql"),
+                            params![peer.address, peer.last_seen, peer.failures as i64],
+                        )
+                        .unwrap();
+                }
+                connection
+                    .execute(
+                        include_str!("../sql/B. RPC/Trim known peers.sql"),
+                        params![MAX_KNOWN_PEERS as i64],
+                    )
+                    .unwrap();
+            }
+            OnDisk::PutReachableKnownPeer(address, now) => {
+                connection
+                    .execute(
+                        include_str!("../sql/B. RPC/Put reachable known peer.sql"),
+                        params![address, now],
+                    )
+                    .unwrap();
+            }
+            OnDisk::AddKnownPeerFailure(address) => {
+                connection
+                    .execute(
+                        include_str!("../sql/B. RPC/Update known peer failures.sql"),
+                        params![address],
+                    )
+                    .unwrap();
+            }
+            OnDisk::PruneKnownPeers(max_failures, seen_before, tx) => {
+                let deleted = connection
+                    .execute(
+                        include_str!("../sql/B. RPC/Prune known peers.sql"),
+                        params![max_failures as i64, seen_before],
                     )
                     .unwrap();
-                mutate_tx.send(Mutation::Insert(hash)).await;
-                event.set();
+                tx.send(deleted).await;
             }
         }
     }
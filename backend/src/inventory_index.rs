@@ -0,0 +1,286 @@
+use crate::inventory::Metadata;
+use crate::quota::{Eviction, EvictionOrder};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub counter: u128,
+    pub expiration_time: i64,
+    pub metadata: Metadata,
+    pub pinned: bool,
+    /// Set until `derive_state` has checked the message. Pending messages are exempt
+    /// from eviction too.
+    pub pending: bool,
+}
+
+impl Entry {
+    fn evictable(&self) -> bool {
+        !self.pinned && !self.pending
+    }
+}
+
+#[derive(Default)]
+pub struct InventoryIndex {
+    by_hash: BTreeMap<Arc<Vec<u8>>, Entry>,
+    by_counter: BTreeMap<u128, Arc<Vec<u8>>>,
+    by_expiration_time: BTreeSet<(i64, Arc<Vec<u8>>)>,
+    /// Only holds the messages that may be evicted.
+    eviction_order: EvictionOrder,
+    bytes: u64,
+}
+
+impl InventoryIndex {
+    pub fn with_eviction(eviction: Eviction) -> InventoryIndex {
+        InventoryIndex {
+            eviction_order: EvictionOrder::new(eviction),
+            ..Default::default()
+        }
+    }
+
+    /// Leaves the index untouched if the hash is already indexed.
+    pub fn insert(&mut self, hash: Arc<Vec<u8>>, entry: Entry) -> bool {
+        if self.by_hash.contains_key(&hash) {
+            return false;
+        }
+        debug_assert!(!self.by_counter.contains_key(&entry.counter));
+        self.by_counter.insert(entry.counter, hash.clone());
+        self.by_expiration_time
+            .insert((entry.expiration_time, hash.clone()));
+        if entry.evictable() {
+            self.eviction_order.insert(hash.clone(), &entry);
+        }
+        self.bytes += entry.metadata.size;
+        self.by_hash.insert(hash, entry);
+        true
+    }
+
+    pub fn remove(&mut self, hash: &Arc<Vec<u8>>) -> Option<Entry> {
+        let entry = self.by_hash.remove(hash)?;
+        self.by_counter.remove(&entry.counter);
+        self.by_expiration_time
+            .remove(&(entry.expiration_time, hash.clone()));
+        self.eviction_order.remove(hash);
+        self.bytes -= entry.metadata.size;
+        Some(entry)
+    }
+
+    pub fn pin(&mut self, hash: &Arc<Vec<u8>>) -> bool {
+        match self.by_hash.get_mut(hash) {
+            Some(entry) => {
+                entry.pinned = true;
+                self.eviction_order.remove(hash);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn mark_processed(&mut self, hash: &Arc<Vec<u8>>) -> bool {
+        match self.by_hash.get_mut(hash) {
+            Some(entry) => {
+                if entry.pending {
+                    entry.pending = false;
+                    if entry.evictable() {
+                        self.eviction_order.insert(hash.clone(), entry);
+                    }
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_local_inboxes(&mut self, inbox_ids: Vec<Vec<u8>>, now: i64) {
+        let evictable = self
+            .by_hash
+            .iter()
+            .filter(|(_, entry)| entry.evictable());
+        self.eviction_order.rebuild(inbox_ids, now, evictable);
+    }
+
+    pub fn eviction_order(&self) -> &EvictionOrder {
+        &self.eviction_order
+    }
+
+    pub fn contains(&self, hash: &Arc<Vec<u8>>) -> bool {
+        self.by_hash.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &Arc<Vec<u8>>) -> Option<&Entry> {
+        self.by_hash.get(hash)
+    }
+
+    pub fn count(&self) -> usize {
+        self.by_hash.len()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub fn latest_counter(&self) -> Option<u128> {
+        self.by_counter.keys().next_back().copied()
+    }
+
+    pub fn hashes(&self) -> impl Iterator<Item = &Arc<Vec<u8>>> {
+        self.by_counter.values()
+    }
+
+    pub fn after_counter(&self, counter: u128) -> impl Iterator<Item = (u128, &Arc<Vec<u8>>)> {
+        self.by_counter
+            .range((Excluded(counter), Unbounded))
+            .map(|(&counter, hash)| (counter, hash))
+    }
+
+    pub fn by_hash(&self) -> &BTreeMap<Arc<Vec<u8>>, Entry> {
+        &self.by_hash
+    }
+
+    pub fn remove_expired(&mut self, now: i64) -> Vec<Arc<Vec<u8>>> {
+        let unexpired = self
+            .by_expiration_time
+            .split_off(&(now.saturating_add(1), Arc::new(Vec::new())));
+        let expired = std::mem::replace(&mut self.by_expiration_time, unexpired);
+        expired
+            .into_iter()
+            .map(|(_, hash)| {
+                if let Some(entry) = self.by_hash.remove(&hash) {
+                    self.by_counter.remove(&entry.counter);
+                    self.eviction_order.remove(&hash);
+                    self.bytes -= entry.metadata.size;
+                }
+                hash
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap;
+
+    fn entry(counter: u128, expiration_time: i64) -> Entry {
+        Entry {
+            counter,
+            expiration_time,
+            metadata: Metadata {
+                size: size(counter),
+                achieved_target: 1,
+            },
+            pinned: false,
+            pending: false,
+        }
+    }
+
+    fn size(counter: u128) -> u64 {
+        (counter % 7) as u64 + 1
+    }
+
+    fn check(index: &InventoryIndex, model: &HashMap<Arc<Vec<u8>>, (u128, i64)>) {
+        assert_eq!(index.by_hash.len(), model.len());
+        assert_eq!(index.by_counter.len(), model.len());
+        assert_eq!(index.by_expiration_time.len(), model.len());
+        assert_eq!(index.eviction_order().iter().count(), model.len());
+        for (hash, &(counter, expiration_time)) in model {
+            let entry = index.get(hash).unwrap();
+            assert_eq!(
+                (entry.counter, entry.expiration_time),
+                (counter, expiration_time)
+            );
+            assert_eq!(index.by_counter.get(&counter), Some(hash));
+            assert!(index
+                .by_expiration_time
+                .contains(&(expiration_time, hash.clone())));
+        }
+        assert_eq!(
+            index.latest_counter(),
+            model.values().map(|&(counter, _)| counter).max()
+        );
+        assert_eq!(
+            index.bytes(),
+            model
+                .values()
+                .map(|&(counter, _)| size(counter))
+                .sum::<u64>()
+        );
+    }
+
+    #[test]
+    fn indices_never_diverge() {
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut index = InventoryIndex::default();
+            let mut model = HashMap::new();
+            let mut counter = 0;
+            let mut now = 0;
+            for _ in 0..500 {
+                match rng.gen_range(0, 12) {
+                    0..=6 => {
+                        // Few distinct hashes, so duplicates are common.
+                        let hash = Arc::new(vec![rng.gen_range(0u8, 64)]);
+                        let expiration_time = now + rng.gen_range(0, 50);
+                        counter += 1;
+                        let inserted = index.insert(hash.clone(), entry(counter, expiration_time));
+                        assert_eq!(inserted, !model.contains_key(&hash));
+                        if inserted {
+                            model.insert(hash, (counter, expiration_time));
+                        }
+                    }
+                    7..=8 => {
+                        now += rng.gen_range(0, 10);
+                        let mut removed = index.remove_expired(now);
+                        let mut expected: Vec<_> = model
+                            .iter()
+                            .filter(|(_, (_, expiration_time))| *expiration_time <= now)
+                            .map(|(hash, _)| hash.clone())
+                            .collect();
+                        removed.sort();
+                        expected.sort();
+                        assert_eq!(removed, expected);
+                        model.retain(|_, &mut (_, expiration_time)| expiration_time > now);
+                    }
+                    9 => {
+                        let hash = Arc::new(vec![rng.gen_range(0u8, 64)]);
+                        let removed = index.remove(&hash).map(|entry| entry.counter);
+                        assert_eq!(removed, model.remove(&hash).map(|(counter, _)| counter));
+                    }
+                    _ => {
+                        let after = rng.gen_range(0, counter + 1);
+                        let listed: Vec<_> = index
+                            .after_counter(after)
+                            .map(|(counter, hash)| (counter, hash.clone()))
+                            .collect();
+                        let mut expected: Vec<_> = model
+                            .iter()
+                            .filter(|(_, (counter, _))| *counter > after)
+                            .map(|(hash, &(counter, _))| (counter, hash.clone()))
+                            .collect();
+                        expected.sort();
+                        assert_eq!(listed, expected);
+                    }
+                }
+                check(&index, &model);
+            }
+        }
+    }
+
+    #[test]
+    fn messages_expire_at_their_expiration_time() {
+        let mut index = InventoryIndex::default();
+        index.insert(Arc::new(vec![1]), entry(1, 100));
+        index.insert(Arc::new(vec![2]), entry(2, 101));
+        assert!(index.remove_expired(99).is_empty());
+        assert_eq!(index.remove_expired(100), vec![Arc::new(vec![1])]);
+        assert!(!index.contains(&Arc::new(vec![1])));
+        assert_eq!(index.latest_counter(), Some(2));
+        // Pinned messages still expire.
+        assert!(index.pin(&Arc::new(vec![2])));
+        assert_eq!(index.remove_expired(101), vec![Arc::new(vec![2])]);
+        assert_eq!(index.bytes(), 0);
+    }
+}
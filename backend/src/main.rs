@@ -1,32 +1,69 @@
 #[macro_use]
 extern crate lazy_static;
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use futures::task::LocalSpawn;
 use rusqlite::Connection;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::process::exit;
+mod acceptance_policy;
+mod allowlist;
+mod backoff;
+mod bundle;
+mod compression;
 mod connect;
+mod connection_registry;
 mod derive_state;
+mod discovery;
+mod forwarding;
+mod fragmentation;
+mod handshake;
 mod init_inventory;
 mod inventory;
+mod inventory_index;
 mod log;
 mod message_hash;
 mod mpmc_manual_reset_event;
+mod noise;
+mod peer_exchange;
+mod peer_reputation;
 mod private_box;
 mod proof_of_work;
+mod pull;
+mod push_order;
+#[cfg(feature = "quic")]
+mod quic;
+mod quota;
 mod reconcile_client;
 mod reconcile_server;
+mod session;
+mod set_reconciliation;
+#[cfg(test)]
+mod simulation;
+mod socks5;
 mod state_derive_ipc;
 mod stdio_ipc;
+mod udp;
 mod reconcile_capnp {
     include!(concat!(env!("OUT_DIR"), "/capnp/reconcile_capnp.rs"));
 }
 mod message_capnp {
     include!(concat!(env!("OUT_DIR"), "/capnp/message_capnp.rs"));
 }
+use acceptance_policy::AcceptancePolicy;
+use allowlist::Allowlist;
 use async_std::prelude::*;
 use async_std::sync::{channel, RwLock};
+use compression::Compression;
+use connection_registry::{ConnectionRegistry, Link};
 use derive_state::derive;
+use forwarding::Strategy;
+use inventory::Mutation;
+use noise::NoisePolicy;
+use peer_reputation::{PeerReputation, Thresholds};
+use pull::PullPolicy;
+use push_order::PushOrder;
+use quota::{Eviction, Quota};
+use session::Context;
 use state_derive_ipc::state_derive_ipc;
 use stdio_ipc::{format_struct, Message};
 
@@ -69,7 +106,24 @@ fn main() {
                 .short("r")
                 .long("reverse-address")
                 .value_name("ADDRESS")
-                .help("Sets the reverse reconciliation client address")
+                .help(
+                    "Sets the reverse reconciliation client address. Only peers that predate \
+                     bidirectional sessions need it",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("quic address")
+                .long("quic-address")
+                .value_name("ADDRESS")
+                .help("Sets the QUIC listen address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("udp address")
+                .long("udp-address")
+                .value_name("ADDRESS")
+                .help("Sets the UDP listen address")
                 .takes_value(true),
         )
         .arg(
@@ -91,6 +145,237 @@ fn main() {
                 .help("Unix socket equivalent of the `reverse-address` parameter")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("max payload size")
+                .long("max-payload-size")
+                .value_name("BYTES")
+                .help("Sets the size above which messages are rejected. Zero means no limit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max time to live")
+                .long("max-time-to-live")
+                .value_name("SECONDS")
+                .help(
+                    "Sets the time-to-live above which messages are rejected. Zero means no limit",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min time to live")
+                .long("min-time-to-live")
+                .value_name("SECONDS")
+                .help("Sets the time-to-live below which messages are rejected")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max storage bytes")
+                .long("max-storage-bytes")
+                .value_name("BYTES")
+                .help("Sets how many payload bytes the inventory stores at most. Zero means no limit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max stored messages")
+                .long("max-stored-messages")
+                .value_name("COUNT")
+                .help("Sets how many messages the inventory stores at most. Zero means no limit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eviction")
+                .long("eviction")
+                .value_name("POLICY")
+                .help("Sets which messages are evicted once a storage limit is hit. Defaults to `oldest`")
+                .possible_values(&["oldest", "lowest-surplus", "farthest"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("push order")
+                .long("push-order")
+                .value_name("ORDER")
+                .help("Sets the order in which messages are offered to peers, unless overridden per connection")
+                .possible_values(&[
+                    "insertion",
+                    "newest",
+                    "soonest-expiring",
+                    "smallest",
+                    "highest-surplus",
+                ])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("forwarding")
+                .long("forwarding")
+                .value_name("STRATEGY")
+                .help("Sets which messages are offered to peers. Defaults to `flood`")
+                .possible_values(&["flood", "epidemic", "spray-and-wait"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max hops")
+                .long("max-hops")
+                .value_name("COUNT")
+                .help("Sets how many times `epidemic` relays a message. Defaults to 8")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("copies")
+                .long("copies")
+                .value_name("COUNT")
+                .help("Sets how many copies of a message `spray-and-wait` spreads. Defaults to 8")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("noise")
+                .long("noise")
+                .value_name("POLICY")
+                .help(
+                    "Sets whether peer links are encrypted. `enabled` encrypts outgoing \
+                     connections, `required` also turns away plaintext peers",
+                )
+                .possible_values(&["disabled", "enabled", "required"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .value_name("ALGORITHM")
+                .help("Sets the compression offered on Noise links. Defaults to zstd")
+                .possible_values(&["none", "zstd"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("socks5 proxy")
+                .long("socks5-proxy")
+                .value_name("ADDRESS")
+                .help("Routes outgoing TCP connections through a SOCKS5 proxy, such as Tor")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("discovery")
+                .long("discovery")
+                .help(
+                    "Announces this node on the LAN and connects to the peers it hears about",
+                )
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("discovery group")
+                .long("discovery-group")
+                .value_name("ADDRESS")
+                .help("Sets the multicast group used for discovery. Implies `--discovery`")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("known peer connections")
+                .long("known-peer-connections")
+                .value_name("COUNT")
+                .help(
+                    "Keeps up to COUNT outgoing connections to peers learned through peer \
+                     exchange. Defaults to 0",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("private network")
+                .long("private-network")
+                .help(
+                    "Only reconciles with peers on the allowlist. Implies `--noise required`",
+                )
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("pull only")
+                .long("pull-only")
+                .help(
+                    "Asks peers not to push messages and fetches only the ones the pull \
+                     policy admits",
+                )
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("pull max message size")
+                .long("pull-max-message-size")
+                .value_name("BYTES")
+                .help("Doesn't pull larger messages. Zero means no limit")
+                .requires("pull only")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pull min time to live")
+                .long("pull-min-time-to-live")
+                .value_name("SECONDS")
+                .help("Doesn't pull messages expiring sooner than this")
+                .requires("pull only")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pull bandwidth budget")
+                .long("pull-bandwidth-budget")
+                .value_name("BYTES")
+                .help("Pulls at most this many payload bytes per hour. Zero means no limit")
+                .requires("pull only")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max sessions")
+                .long("max-sessions")
+                .value_name("COUNT")
+                .help("Sets the maximum number of simultaneous sessions. Zero means no limit")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("throttle threshold")
+                .long("throttle-threshold")
+                .value_name("SCORE")
+                .help("Sets the misbehaviour score above which peers are throttled")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ban threshold")
+                .long("ban-threshold")
+                .value_name("SCORE")
+                .help("Sets the misbehaviour score above which peers are banned")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ban duration")
+                .long("ban-duration")
+                .value_name("SECONDS")
+                .help("Sets how long misbehaving peers stay banned")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("export-bundle")
+                .about("Writes the inventory to a bundle file and exits")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("WATERMARK")
+                        .help(
+                            "Only exports messages newer than the watermark reported by a \
+                             previous export",
+                        )
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-bundle")
+                .about("Inserts the messages of a bundle file into the inventory and exits")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .required(true)
+                        .index(1),
+                ),
+        )
         .get_matches();
 
     if !cfg!(unix) {
@@ -100,6 +385,11 @@ fn main() {
         }
     }
 
+    if !cfg!(feature = "quic") && matches.is_present("quic address") {
+        log::fatal("QUIC support wasn't compiled in");
+        exit(1);
+    }
+
     let database_path = matches.value_of("database").unwrap().to_owned();
 
     let frontend_database_path = match matches.value_of("frontend database") {
@@ -139,6 +429,28 @@ fn main() {
         None => None,
     };
 
+    let parsed_quic_address = match matches.value_of("quic address") {
+        Some(address) => match address.parse::<SocketAddr>() {
+            Ok(address) => Some(address),
+            Err(_) => {
+                log::fatal("QUIC listen address is invalid");
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let parsed_udp_address = match matches.value_of("udp address") {
+        Some(address) => match address.parse::<SocketAddr>() {
+            Ok(address) => Some(address),
+            Err(_) => {
+                log::fatal("UDP listen address is invalid");
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
     let unix_socket = match matches.value_of("unix socket") {
         Some(value) => Some(value.to_owned()),
         None => None,
@@ -151,6 +463,218 @@ fn main() {
 
     let dump_inventory = matches.is_present("dump inventory");
 
+    let mut acceptance_policy = AcceptancePolicy::default();
+    if let Some(value) = matches.value_of("max payload size") {
+        acceptance_policy.max_payload_size = match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Maximum payload size is invalid");
+                exit(1);
+            }
+        };
+    }
+    if let Some(value) = matches.value_of("max time to live") {
+        acceptance_policy.max_time_to_live = match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Maximum time-to-live is invalid");
+                exit(1);
+            }
+        };
+    }
+    if let Some(value) = matches.value_of("min time to live") {
+        acceptance_policy.min_time_to_live = match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Minimum time-to-live is invalid");
+                exit(1);
+            }
+        };
+    }
+
+    let mut quota = Quota::default();
+    if let Some(value) = matches.value_of("max storage bytes") {
+        quota.max_bytes = match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Maximum storage size is invalid");
+                exit(1);
+            }
+        };
+    }
+    if let Some(value) = matches.value_of("max stored messages") {
+        quota.max_messages = match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Maximum number of stored messages is invalid");
+                exit(1);
+            }
+        };
+    }
+    quota.eviction = match matches.value_of("eviction") {
+        Some("lowest-surplus") => Eviction::LowestSurplusFirst,
+        Some("farthest") => Eviction::FarthestFromInboxesFirst,
+        _ => Eviction::OldestFirst,
+    };
+
+    let push_order = match matches.value_of("push order") {
+        Some("newest") => PushOrder::NewestFirst,
+        Some("soonest-expiring") => PushOrder::SoonestExpiringFirst,
+        Some("smallest") => PushOrder::SmallestFirst,
+        Some("highest-surplus") => PushOrder::HighestProofOfWorkSurplusFirst,
+        _ => PushOrder::InsertionOrder,
+    };
+
+    let max_hops = match matches.value_of("max hops") {
+        Some(value) => match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Maximum number of hops is invalid");
+                exit(1);
+            }
+        },
+        None => 8,
+    };
+
+    let copies = match matches.value_of("copies") {
+        Some(value) => match value.parse() {
+            Ok(value) if value != 0 => value,
+            _ => {
+                log::fatal("Number of copies is invalid");
+                exit(1);
+            }
+        },
+        None => 8,
+    };
+
+    let forwarding = match matches.value_of("forwarding") {
+        Some("epidemic") => Strategy::Epidemic { max_hops },
+        Some("spray-and-wait") => Strategy::SprayAndWait { copies },
+        _ => Strategy::Flood,
+    };
+
+    let noise_policy = match matches.value_of("noise") {
+        Some("enabled") => NoisePolicy::Enabled,
+        Some("required") => NoisePolicy::Required,
+        _ => NoisePolicy::Disabled,
+    };
+
+    let compression = match matches.value_of("compression") {
+        Some("none") => Compression::None,
+        _ => Compression::Zstd,
+    };
+
+    let socks5_proxy = matches
+        .value_of("socks5 proxy")
+        .map(|proxy| proxy.to_string());
+
+    let discovery_group = match matches.value_of("discovery group") {
+        Some(value) => match value.parse::<SocketAddrV4>() {
+            Ok(group) if group.ip().is_multicast() => Some(group),
+            _ => {
+                log::fatal("Discovery multicast group is invalid");
+                exit(1);
+            }
+        },
+        None if matches.is_present("discovery") => {
+            Some(discovery::DEFAULT_GROUP.parse::<SocketAddrV4>().unwrap())
+        }
+        None => None,
+    };
+
+    let known_peer_connections = match matches.value_of("known peer connections") {
+        Some(value) => match value.parse::<usize>() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Number of known peer connections is invalid");
+                exit(1);
+            }
+        },
+        None => 0,
+    };
+
+    let private_network = matches.is_present("private network");
+
+    let pull_policy = if matches.is_present("pull only") {
+        let max_message_size = match matches.value_of("pull max message size") {
+            Some(value) => match value.parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    log::fatal("Maximum size of pulled messages is invalid");
+                    exit(1);
+                }
+            },
+            None => 0,
+        };
+        let min_time_to_live = match matches.value_of("pull min time to live") {
+            Some(value) => match value.parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    log::fatal("Minimum time to live of pulled messages is invalid");
+                    exit(1);
+                }
+            },
+            None => 0,
+        };
+        let bandwidth_budget = match matches.value_of("pull bandwidth budget") {
+            Some(value) => match value.parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    log::fatal("Pull bandwidth budget is invalid");
+                    exit(1);
+                }
+            },
+            None => 0,
+        };
+        Some(std::rc::Rc::new(PullPolicy::new(
+            max_message_size,
+            min_time_to_live,
+            bandwidth_budget,
+        )))
+    } else {
+        None
+    };
+
+    let max_sessions = match matches.value_of("max sessions") {
+        Some(value) => match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Maximum number of sessions is invalid");
+                exit(1);
+            }
+        },
+        None => 0,
+    };
+
+    let mut thresholds = Thresholds::default();
+    if let Some(value) = matches.value_of("throttle threshold") {
+        thresholds.throttle = match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Throttle threshold is invalid");
+                exit(1);
+            }
+        };
+    }
+    if let Some(value) = matches.value_of("ban threshold") {
+        thresholds.ban = match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Ban threshold is invalid");
+                exit(1);
+            }
+        };
+    }
+    if let Some(value) = matches.value_of("ban duration") {
+        thresholds.ban_duration = match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                log::fatal("Ban duration is invalid");
+                exit(1);
+            }
+        };
+    }
+
     log::welcome("Standard streams are being used for interprocess communication");
 
     if let Some(address) = address.to_owned() {
@@ -167,6 +691,13 @@ fn main() {
         ));
     }
 
+    if let Some(address) = parsed_quic_address {
+        log::notice(format!(
+            "Listening for incoming QUIC connections on {}",
+            address
+        ));
+    }
+
     let mut exec = futures::executor::LocalPool::new();
     let spawner = exec.spawner();
 
@@ -215,9 +746,18 @@ fn main() {
             Some(command_tx)
         }
         None => {
+            let on_disk_tx = on_disk_tx.clone();
+            // Without a frontend no message is addressed to a local inbox.
             spawner
                 .spawn_local_obj(
-                    Box::new(async move { while let Ok(_) = mutate_rx.recv().await {} }).into(),
+                    Box::new(async move {
+                        while let Ok(mutation) = mutate_rx.recv().await {
+                            if let Mutation::Insert(hash) = mutation {
+                                inventory::mark_processed(&on_disk_tx, hash).await;
+                            }
+                        }
+                    })
+                    .into(),
                 )
                 .unwrap();
             None
@@ -233,20 +773,102 @@ fn main() {
             }
         };
 
-        init_inventory::init_inventory(connection, mutate_tx, in_memory_rx, on_disk_rx);
+        init_inventory::init_inventory(
+            connection,
+            mutate_tx,
+            in_memory_rx,
+            on_disk_rx,
+            acceptance_policy,
+            quota,
+        );
     });
 
     let spawner_clone = spawner.clone();
 
-    let reconciliation_intent = std::rc::Rc::new(RwLock::new(
-        mpmc_manual_reset_event::MPMCManualResetEvent::new(),
+    let node_id = async_std::task::block_on(inventory::get_node_id(&on_disk_tx));
+    let noise_keypair = async_std::task::block_on(noise::load_keypair(&on_disk_tx));
+    let allowed_peers = async_std::task::block_on(inventory::get_allowed_peers(&on_disk_tx));
+    log::notice(format!(
+        "Noise public key is {}",
+        base64::encode(&noise_keypair.public)
     ));
 
-    let reconciliation_intent_clone = reconciliation_intent.clone();
+    let context = Context {
+        in_memory_tx,
+        on_disk_tx,
+        reconciliation_intent: std::rc::Rc::new(RwLock::new(
+            mpmc_manual_reset_event::MPMCManualResetEvent::new(),
+        )),
+        peer_reputation: std::rc::Rc::new(RwLock::new(PeerReputation::new(thresholds))),
+        acceptance_policy,
+        node_id: std::rc::Rc::new(node_id),
+        push_order,
+        noise_policy: std::rc::Rc::new(std::cell::Cell::new(noise_policy)),
+        noise_keypair: std::rc::Rc::new(noise_keypair),
+        allowlist: std::rc::Rc::new(RwLock::new(Allowlist::new(private_network, allowed_peers))),
+        compression,
+        compression_statistics: std::rc::Rc::new(Default::default()),
+        connections: std::rc::Rc::new(RwLock::new(ConnectionRegistry::new(max_sessions))),
+        socks5_proxy,
+        listen_address: parsed_address,
+        pull_policy,
+        forwarding,
+    };
+
+    if let Some(matches) = matches.subcommand_matches("export-bundle") {
+        let since = match matches.value_of("since") {
+            Some(value) => match value.parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    log::fatal("Watermark is invalid");
+                    exit(1);
+                }
+            },
+            None => 0,
+        };
+        let path = matches.value_of("file").unwrap();
+        match exec.run_until(bundle::export(&context, path, since)) {
+            Ok(bundle) => {
+                log::notice(format!(
+                    "Exported {} messages to {}. Pass `--since {}` to only export newer messages next time",
+                    bundle.messages.len(),
+                    path,
+                    bundle.watermark
+                ));
+                exit(0);
+            }
+            Err(error) => {
+                log::fatal(format!("Failed to export bundle: {}", error));
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import-bundle") {
+        let path = matches.value_of("file").unwrap();
+        match exec.run_until(bundle::import(&context, path)) {
+            Ok(summary) => {
+                log::notice(format!(
+                    "Imported {} messages from {}. {} were already known, {} expired, {} were rejected and {} didn't fit the storage quota",
+                    summary.imported,
+                    path,
+                    summary.duplicate,
+                    summary.expired,
+                    summary.rejected,
+                    summary.refused
+                ));
+                exit(0);
+            }
+            Err(error) => {
+                log::fatal(format!("Failed to import bundle: {}", error));
+                exit(1);
+            }
+        }
+    }
+
+    let context_clone = context.clone();
 
     if let Some(parsed_address) = parsed_address {
-        let in_memory_tx = in_memory_tx.clone();
-        let on_disk_tx = on_disk_tx.clone();
         spawner
             .spawn_local_obj(
                 Box::new(async move {
@@ -268,10 +890,7 @@ fn main() {
                     while let Some(socket) = incoming.next().await {
                         match socket {
                             Ok(socket) => {
-                                let in_memory_tx = in_memory_tx.clone();
-                                let on_disk_tx = on_disk_tx.clone();
-                                let reconciliation_intent_clone =
-                                    reconciliation_intent_clone.clone();
+                                let context = context_clone.clone();
                                 spawner_clone2
                                     .spawn_local_obj(
                                         Box::new(async move {
@@ -282,11 +901,37 @@ fn main() {
                                                 ));
                                                 return;
                                             };
+                                            let address = match socket.peer_addr() {
+                                                Ok(address) => address,
+                                                Err(error) => {
+                                                    log::warning(format!(
+                                                        "Error occurred while accepting an incoming connection: {:?}",
+                                                        error
+                                                    ));
+                                                    return;
+                                                }
+                                            };
+                                            let socket = match noise::accept_peer(socket, &context).await {
+                                                Ok(socket) => socket,
+                                                Err(error) => {
+                                                    log::warning(format!(
+                                                        "Error occurred while accepting an incoming connection: {:?}",
+                                                        error
+                                                    ));
+                                                    return;
+                                                }
+                                            };
+                                            let link = Link {
+                                                id: None,
+                                                peer: socket.peer_identity(address.ip().to_string()),
+                                                remote_static_key: socket.remote_static_key().map(<[u8]>::to_vec),
+                                                address: address.to_string(),
+                                                inbound: true,
+                                            };
                                             if let Err(error) = reconcile_server::init_server(
                                                 socket,
-                                                in_memory_tx,
-                                                on_disk_tx,
-                                                reconciliation_intent_clone.clone(),
+                                                link,
+                                                context,
                                             )
                                             .await
                                             {
@@ -315,10 +960,8 @@ fn main() {
     }
 
     let spawner_clone = spawner.clone();
-    let reconciliation_intent_clone = reconciliation_intent.clone();
+    let context_clone = context.clone();
     if let Some(parsed_reverse_address) = parsed_reverse_address {
-        let in_memory_tx = in_memory_tx.clone();
-        let on_disk_tx = on_disk_tx.clone();
         spawner
             .spawn_local_obj(
                 Box::new(async move {
@@ -341,10 +984,7 @@ fn main() {
                     while let Some(socket) = incoming.next().await {
                         match socket {
                             Ok(socket) => {
-                                let spawner_clone3 = spawner_clone2.clone();
-                                let reconciliation_intent = reconciliation_intent_clone.clone();
-                                let in_memory_tx = in_memory_tx.clone();
-                                let on_disk_tx = on_disk_tx.clone();
+                                let context = context_clone.clone();
                                 spawner_clone2
                                     .spawn_local_obj(
                                         Box::new(async move {
@@ -355,12 +995,37 @@ fn main() {
                                                 ));
                                                 return;
                                             };
+                                            let address = match socket.peer_addr() {
+                                                Ok(address) => address,
+                                                Err(error) => {
+                                                    log::warning(format!(
+                                                        "Error occurred while accepting an incoming connection: {:?}",
+                                                        error
+                                                    ));
+                                                    return;
+                                                }
+                                            };
+                                            let socket = match noise::accept_reverse_peer(socket, &context).await {
+                                                Ok(socket) => socket,
+                                                Err(error) => {
+                                                    log::warning(format!(
+                                                        "Error occurred while accepting an incoming connection: {:?}",
+                                                        error
+                                                    ));
+                                                    return;
+                                                }
+                                            };
+                                            let link = Link {
+                                                id: None,
+                                                peer: socket.peer_identity(address.ip().to_string()),
+                                                remote_static_key: socket.remote_static_key().map(<[u8]>::to_vec),
+                                                address: address.to_string(),
+                                                inbound: true,
+                                            };
                                             if let Err(error) = reconcile_client::reconcile(
                                                 socket,
-                                                &in_memory_tx,
-                                                &on_disk_tx,
-                                                spawner_clone3.clone(),
-                                                reconciliation_intent.clone(),
+                                                link,
+                                                context,
                                             )
                                             .await
                                             {
@@ -388,14 +1053,145 @@ fn main() {
             .unwrap();
     }
 
+    #[cfg(feature = "quic")]
+    {
+        let spawner_clone = spawner.clone();
+        let context_clone = context.clone();
+        if let Some(parsed_quic_address) = parsed_quic_address {
+            let endpoint = match quic::listen(parsed_quic_address) {
+                Ok(endpoint) => endpoint,
+                Err(error) => {
+                    log::fatal(format!(
+                        "Failed to bind to {} due to error {:?}",
+                        parsed_quic_address, error
+                    ));
+                    exit(1);
+                }
+            };
+            spawner
+                .spawn_local_obj(
+                    Box::new(async move {
+                        while let Some(connecting) = quic::accept(&endpoint).await {
+                            let context = context_clone.clone();
+                            spawner_clone
+                                .spawn_local_obj(
+                                    Box::new(async move {
+                                        let (socket, address) = match quic::establish(connecting).await {
+                                            Ok(established) => established,
+                                            Err(error) => {
+                                                log::warning(format!(
+                                                    "Unexpected error while accepting incoming QUIC connection: {:?}",
+                                                    error
+                                                ));
+                                                return;
+                                            }
+                                        };
+                                        let socket = match noise::accept_peer(socket, &context).await {
+                                            Ok(socket) => socket,
+                                            Err(error) => {
+                                                log::warning(format!(
+                                                    "Error occurred while accepting an incoming connection: {:?}",
+                                                    error
+                                                ));
+                                                return;
+                                            }
+                                        };
+                                        let link = Link {
+                                            id: None,
+                                            peer: socket.peer_identity(address.ip().to_string()),
+                                            remote_static_key: socket.remote_static_key().map(<[u8]>::to_vec),
+                                            address: format!("quic://{}", address),
+                                            inbound: true,
+                                        };
+                                        if let Err(error) =
+                                            reconcile_server::init_server(socket, link, context).await
+                                        {
+                                            log::warning(format!(
+                                                "Error occurred while reconciling: {:?}",
+                                                error
+                                            ));
+                                        }
+                                    })
+                                    .into(),
+                                )
+                                .unwrap();
+                        }
+                    })
+                    .into(),
+                )
+                .unwrap();
+        }
+    }
+
+    {
+        let spawner_clone = spawner.clone();
+        let context_clone = context.clone();
+        if let Some(parsed_udp_address) = parsed_udp_address {
+            spawner
+                .spawn_local_obj(
+                    Box::new(async move {
+                        let listener = match udp::listen(parsed_udp_address).await {
+                            Ok(listener) => listener,
+                            Err(error) => {
+                                log::fatal(format!(
+                                    "Failed to bind to {} due to error {:?}",
+                                    parsed_udp_address, error
+                                ));
+                                exit(1);
+                            }
+                        };
+                        log::notice(format!(
+                            "Listening for incoming UDP connections on {}",
+                            listener.local_addr()
+                        ));
+                        while let Some((socket, address)) = udp::accept(&listener).await {
+                            let context = context_clone.clone();
+                            spawner_clone
+                                .spawn_local_obj(
+                                    Box::new(async move {
+                                        let socket = match noise::accept_peer(socket, &context).await {
+                                            Ok(socket) => socket,
+                                            Err(error) => {
+                                                log::warning(format!(
+                                                    "Error occurred while accepting an incoming connection: {:?}",
+                                                    error
+                                                ));
+                                                return;
+                                            }
+                                        };
+                                        let link = Link {
+                                            id: None,
+                                            peer: socket.peer_identity(address.ip().to_string()),
+                                            remote_static_key: socket.remote_static_key().map(<[u8]>::to_vec),
+                                            address: format!("udp://{}", address),
+                                            inbound: true,
+                                        };
+                                        if let Err(error) =
+                                            reconcile_server::init_server(socket, link, context).await
+                                        {
+                                            log::warning(format!(
+                                                "Error occurred while reconciling: {:?}",
+                                                error
+                                            ));
+                                        }
+                                    })
+                                    .into(),
+                                )
+                                .unwrap();
+                        }
+                    })
+                    .into(),
+                )
+                .unwrap();
+        }
+    }
+
     #[cfg(unix)]
     {
         let spawner_clone = spawner.clone();
-        let reconciliation_intent_clone = reconciliation_intent.clone();
+        let context_clone = context.clone();
 
         if let Some(unix_socket) = unix_socket {
-            let in_memory_tx = in_memory_tx.clone();
-            let on_disk_tx = on_disk_tx.clone();
             spawner
                 .spawn_local_obj(
                     Box::new(async move {
@@ -415,21 +1211,40 @@ fn main() {
                         };
                         let mut incoming = listener.incoming();
                         let spawner_clone2 = spawner_clone.clone();
+                        let mut connection_count = 0u64;
                         while let Some(socket) = incoming.next().await {
                             match socket {
                                 Ok(socket) => {
-                                    let in_memory_tx = in_memory_tx.clone();
-                                    let on_disk_tx = on_disk_tx.clone();
-                                    let reconciliation_intent_clone =
-                                        reconciliation_intent_clone.clone();
+                                    // Unix socket peers don't have an address,
+                                    // so each connection gets its own.
+                                    connection_count += 1;
+                                    let address = format!("unix:{}#{}", unix_socket, connection_count);
+                                    // Peers without a Noise key all share the
+                                    // socket's record, like peers behind a NAT.
+                                    let peer = format!("unix:{}", unix_socket);
+                                    let context = context_clone.clone();
                                     spawner_clone2
                                         .spawn_local_obj(
                                             Box::new(async move {
+                                                let socket = match noise::accept_peer(socket, &context).await {
+                                                    Ok(socket) => socket,
+                                                    Err(error) => {
+                                                        log::warning(format!(
+                                                            "Error occurred while accepting an incoming connection: {:?}",
+                                                            error
+                                                        ));
+                                                        return;
+                                                    }
+                                                };
+                                                let link = Link {
+                                                    id: None,
+                                                    peer: socket.peer_identity(peer),
+                                                    remote_static_key: socket.remote_static_key().map(<[u8]>::to_vec),
+                                                    address,
+                                                    inbound: true,
+                                                };
                                                 if let Err(error) = reconcile_server::init_server(
-                                                    socket,
-                                                    in_memory_tx,
-                                                    on_disk_tx,
-                                                    reconciliation_intent_clone.clone(),
+                                                    socket, link, context,
                                                 )
                                                 .await
                                                 {
@@ -457,10 +1272,8 @@ fn main() {
                 .unwrap();
         }
         let spawner_clone = spawner.clone();
-        let reconciliation_intent_clone = reconciliation_intent.clone();
+        let context_clone = context.clone();
         if let Some(unix_socket) = reverse_unix_socket {
-            let in_memory_tx = in_memory_tx.clone();
-            let on_disk_tx = on_disk_tx.clone();
             spawner
                 .spawn_local_obj(
                     Box::new(async move {
@@ -480,22 +1293,40 @@ fn main() {
                         };
                         let mut incoming = listener.incoming();
                         let spawner_clone2 = spawner_clone.clone();
+                        let mut connection_count = 0u64;
                         while let Some(socket) = incoming.next().await {
                             match socket {
                                 Ok(socket) => {
-                                    let spawner_clone3 = spawner_clone2.clone();
-                                    let reconciliation_intent = reconciliation_intent_clone.clone();
-                                    let in_memory_tx = in_memory_tx.clone();
-                                    let on_disk_tx = on_disk_tx.clone();
+                                    // Unix socket peers don't have an address,
+                                    // so each connection gets its own.
+                                    connection_count += 1;
+                                    let address = format!("unix:{}#{}", unix_socket, connection_count);
+                                    // Peers without a Noise key all share the
+                                    // socket's record, like peers behind a NAT.
+                                    let peer = format!("unix:{}", unix_socket);
+                                    let context = context_clone.clone();
                                     spawner_clone2
                                         .spawn_local_obj(
                                             Box::new(async move {
+                                                let socket = match noise::accept_reverse_peer(socket, &context).await {
+                                                    Ok(socket) => socket,
+                                                    Err(error) => {
+                                                        log::warning(format!(
+                                                            "Error occurred while accepting an incoming connection: {:?}",
+                                                            error
+                                                        ));
+                                                        return;
+                                                    }
+                                                };
+                                                let link = Link {
+                                                    id: None,
+                                                    peer: socket.peer_identity(peer),
+                                                    remote_static_key: socket.remote_static_key().map(<[u8]>::to_vec),
+                                                    address,
+                                                    inbound: true,
+                                                };
                                                 if let Err(error) = reconcile_client::reconcile(
-                                                    socket,
-                                                    &in_memory_tx,
-                                                    &on_disk_tx,
-                                                    spawner_clone3.clone(),
-                                                    reconciliation_intent.clone(),
+                                                    socket, link, context,
                                                 )
                                                 .await
                                                 {
@@ -525,18 +1356,57 @@ fn main() {
     }
 
     let spawner_clone = spawner.clone();
+    if known_peer_connections != 0 {
+        let context = context.clone();
+        let spawner_clone = spawner.clone();
+        spawner
+            .spawn_local_obj(
+                Box::new(async move {
+                    peer_exchange::maintain(context, spawner_clone, known_peer_connections).await;
+                })
+                .into(),
+            )
+            .unwrap();
+    }
+
+    if let Some(group) = discovery_group {
+        let socket = match discovery::bind(group, Ipv4Addr::UNSPECIFIED) {
+            Ok(socket) => socket,
+            Err(error) => {
+                log::fatal(format!(
+                    "Failed to join discovery group {} due to error {:?}",
+                    group, error
+                ));
+                exit(1);
+            }
+        };
+        let keypair =
+            async_std::task::block_on(discovery::load_signing_keypair(&context.on_disk_tx));
+        log::notice(format!("Announcing this node to discovery group {}", group));
+        let context = context.clone();
+        let spawner_clone = spawner.clone();
+        spawner
+            .spawn_local_obj(
+                Box::new(async move {
+                    discovery::run(
+                        socket,
+                        group,
+                        parsed_address,
+                        keypair,
+                        context,
+                        spawner_clone,
+                    )
+                    .await;
+                })
+                .into(),
+            )
+            .unwrap();
+    }
+
     spawner
         .spawn_local_obj(
             Box::new(async move {
-                stdio_ipc::communicate(
-                    reconciliation_intent,
-                    in_memory_tx,
-                    on_disk_tx,
-                    command_tx,
-                    spawner_clone,
-                    dump_inventory,
-                )
-                .await;
+                stdio_ipc::communicate(context, command_tx, spawner_clone, dump_inventory).await;
             })
             .into(),
         )
@@ -0,0 +1,909 @@
+//! The dialing side sends `PREAMBLE` before the Noise XX handshake, so that
+//! listeners still serve peers that predate Noise. Noise messages are framed
+//! with a big-endian `u16` length.
+use crate::compression::{Compressed, Compression, Statistics};
+use crate::inventory::{get_setting, put_setting, OnDisk};
+use crate::log;
+use crate::session::Context;
+use async_std::future::timeout;
+use async_std::io::{self, Read, Write};
+use async_std::sync::Sender;
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::task::{Context as TaskContext, Poll};
+use serde::{Deserialize, Serialize};
+use snow::{Builder, HandshakeState, TransportState};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Duration;
+
+pub const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+pub const PREAMBLE: &[u8; 8] = b"CSNOISE1";
+
+const MAX_MESSAGE_LENGTH: usize = 65535;
+const TAG_LENGTH: usize = 16;
+const MAX_PLAINTEXT_LENGTH: usize = MAX_MESSAGE_LENGTH - TAG_LENGTH;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Peers that predate Noise send nothing on reverse links, so silent peers are
+/// taken for plaintext ones after this long.
+const REVERSE_SNIFF_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum NoisePolicy {
+    /// Incoming Noise connections are still accepted.
+    Disabled,
+    /// Incoming plaintext connections are still accepted.
+    Enabled,
+    Required,
+}
+
+#[derive(Clone)]
+pub struct Keypair {
+    pub private: Vec<u8>,
+    pub public: Vec<u8>,
+}
+
+pub async fn load_keypair(on_disk_tx: &Sender<OnDisk>) -> Keypair {
+    let private = get_setting(on_disk_tx, "noise private key").await;
+    let public = get_setting(on_disk_tx, "noise public key").await;
+    if let (Some(private), Some(public)) = (private, public) {
+        return Keypair { private, public };
+    }
+    let keypair = generate_keypair();
+    put_setting(on_disk_tx, "noise private key", keypair.private.clone()).await;
+    put_setting(on_disk_tx, "noise public key", keypair.public.clone()).await;
+    keypair
+}
+
+fn generate_keypair() -> Keypair {
+    let keypair = Builder::new(NOISE_PARAMS.parse().unwrap())
+        .generate_keypair()
+        .unwrap();
+    Keypair {
+        private: keypair.private,
+        public: keypair.public,
+    }
+}
+
+fn to_io_error(error: snow::Error) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Noise error: {}", error),
+    )
+}
+
+async fn write_frame<T: Write + Unpin>(stream: &mut T, frame: &[u8]) -> io::Result<()> {
+    stream
+        .write_all(&(frame.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(frame).await?;
+    stream.flush().await
+}
+
+async fn read_frame<T: Read + Unpin>(stream: &mut T) -> io::Result<Vec<u8>> {
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length).await?;
+    let mut frame = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+/// Peers that predate compression send empty handshake payloads.
+async fn handshake<T: Read + Write + Unpin>(
+    mut stream: T,
+    mut state: HandshakeState,
+    compression: Compression,
+) -> io::Result<(NoiseStream<T>, Compression)> {
+    let initiator = state.is_initiator();
+    let mut negotiated = Compression::None;
+    let mut message = vec![0u8; MAX_MESSAGE_LENGTH];
+    let mut payload = vec![0u8; MAX_MESSAGE_LENGTH];
+    let mut index = 0;
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let outgoing = match (initiator, index) {
+                (true, 0) => compression.offer(),
+                (false, 1) => negotiated.offer(),
+                _ => Vec::new(),
+            };
+            let length = state
+                .write_message(&outgoing, &mut message)
+                .map_err(to_io_error)?;
+            write_frame(&mut stream, &message[..length]).await?;
+        } else {
+            let frame = read_frame(&mut stream).await?;
+            let length = state
+                .read_message(&frame, &mut payload)
+                .map_err(to_io_error)?;
+            let incoming = &payload[..length];
+            match (initiator, index) {
+                (false, 0) => negotiated = compression.choose(incoming),
+                (true, 1) => negotiated = compression.accept_answer(incoming)?,
+                _ => {}
+            }
+        }
+        index += 1;
+    }
+    let transport = state.into_transport_mode().map_err(to_io_error)?;
+    if let Some(remote) = transport.get_remote_static() {
+        log::notice(format!(
+            "Noise handshake completed with peer {}, compression: {:?}",
+            base64::encode(remote),
+            negotiated
+        ));
+    }
+    Ok((NoiseStream::new(stream, transport), negotiated))
+}
+
+fn upgrade<T: Read + Write + Unpin>(
+    (stream, compression): (NoiseStream<T>, Compression),
+    statistics: &Rc<Statistics>,
+) -> Stream<T> {
+    match compression {
+        Compression::None => Stream::Noise(stream),
+        Compression::Zstd => Stream::Compressed {
+            remote_static_key: stream
+                .transport
+                .get_remote_static()
+                .map(|key| key.to_vec())
+                .unwrap_or_default(),
+            stream: Compressed::new(stream, statistics.clone()),
+        },
+    }
+}
+
+async fn with_timeout<T>(
+    future: impl std::future::Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout(HANDSHAKE_TIMEOUT, future).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Noise handshake timed out",
+        )),
+    }
+}
+
+pub async fn dial<T: Read + Write + Unpin>(
+    mut stream: T,
+    policy: NoisePolicy,
+    keypair: &Keypair,
+    compression: Compression,
+    statistics: &Rc<Statistics>,
+) -> io::Result<Stream<T>> {
+    if policy == NoisePolicy::Disabled {
+        return Ok(Stream::Plaintext(Prefixed::new(stream, Vec::new())));
+    }
+    let state = Builder::new(NOISE_PARAMS.parse().unwrap())
+        .local_private_key(&keypair.private)
+        .build_initiator()
+        .map_err(to_io_error)?;
+    with_timeout(async move {
+        stream.write_all(PREAMBLE).await?;
+        Ok(upgrade(
+            handshake(stream, state, compression).await?,
+            statistics,
+        ))
+    })
+    .await
+}
+
+fn plaintext<T>(stream: T, prefix: Vec<u8>, policy: NoisePolicy) -> io::Result<Stream<T>> {
+    if policy == NoisePolicy::Required {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Peer doesn't speak Noise",
+        ));
+    }
+    Ok(Stream::Plaintext(Prefixed::new(stream, prefix)))
+}
+
+/// Peers that stay silent for `patience` are taken for plaintext ones.
+pub async fn accept<T: Read + Write + Unpin>(
+    mut stream: T,
+    policy: NoisePolicy,
+    keypair: &Keypair,
+    compression: Compression,
+    statistics: &Rc<Statistics>,
+    patience: Option<Duration>,
+) -> io::Result<Stream<T>> {
+    with_timeout(async move {
+        // Plaintext peers open with a Cap'n Proto message, which is always
+        // longer than the preamble.
+        let mut preamble = [0u8; 8];
+        if let Some(patience) = patience {
+            match timeout(patience, stream.read(&mut preamble[..1])).await {
+                Ok(Ok(0)) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(result) => result?,
+                Err(_) => return plaintext(stream, Vec::new(), policy),
+            };
+            stream.read_exact(&mut preamble[1..]).await?;
+        } else {
+            stream.read_exact(&mut preamble).await?;
+        }
+        if &preamble != PREAMBLE {
+            return plaintext(stream, preamble.to_vec(), policy);
+        }
+        let state = Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&keypair.private)
+            .build_responder()
+            .map_err(to_io_error)?;
+        Ok(upgrade(
+            handshake(stream, state, compression).await?,
+            statistics,
+        ))
+    })
+    .await
+}
+
+async fn effective_policy(context: &Context) -> NoisePolicy {
+    if context.allowlist.read().await.private_network() {
+        NoisePolicy::Required
+    } else {
+        context.noise_policy.get()
+    }
+}
+
+/// Runs before the session starts, so that strangers learn nothing.
+async fn admit<T>(stream: Stream<T>, context: &Context) -> io::Result<Stream<T>> {
+    if context
+        .allowlist
+        .read()
+        .await
+        .admits(stream.remote_static_key())
+    {
+        Ok(stream)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Peer isn't on the allowlist",
+        ))
+    }
+}
+
+pub async fn dial_peer<T: Read + Write + Unpin>(
+    stream: T,
+    context: &Context,
+) -> io::Result<Stream<T>> {
+    let policy = effective_policy(context).await;
+    let stream = dial(
+        stream,
+        policy,
+        &context.noise_keypair,
+        context.compression,
+        &context.compression_statistics,
+    )
+    .await?;
+    admit(stream, context).await
+}
+
+/// Noise is used whatever the policy says.
+pub async fn dial_known_peer<T: Read + Write + Unpin>(
+    stream: T,
+    context: &Context,
+    public_key: &[u8],
+) -> io::Result<Stream<T>> {
+    let stream = dial(
+        stream,
+        NoisePolicy::Required,
+        &context.noise_keypair,
+        context.compression,
+        &context.compression_statistics,
+    )
+    .await?;
+    if stream.remote_static_key() != Some(public_key) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Peer's Noise key doesn't match the expected one",
+        ));
+    }
+    admit(stream, context).await
+}
+
+pub async fn accept_peer<T: Read + Write + Unpin>(
+    stream: T,
+    context: &Context,
+) -> io::Result<Stream<T>> {
+    accept_peer_with(stream, context, None).await
+}
+
+pub async fn accept_reverse_peer<T: Read + Write + Unpin>(
+    stream: T,
+    context: &Context,
+) -> io::Result<Stream<T>> {
+    accept_peer_with(stream, context, Some(REVERSE_SNIFF_TIMEOUT)).await
+}
+
+async fn accept_peer_with<T: Read + Write + Unpin>(
+    stream: T,
+    context: &Context,
+    patience: Option<Duration>,
+) -> io::Result<Stream<T>> {
+    let policy = effective_policy(context).await;
+    let stream = accept(
+        stream,
+        policy,
+        &context.noise_keypair,
+        context.compression,
+        &context.compression_statistics,
+        patience,
+    )
+    .await?;
+    admit(stream, context).await
+}
+
+pub struct Prefixed<T> {
+    prefix: Vec<u8>,
+    offset: usize,
+    inner: T,
+}
+
+impl<T> Prefixed<T> {
+    fn new(inner: T, prefix: Vec<u8>) -> Prefixed<T> {
+        Prefixed {
+            prefix,
+            offset: 0,
+            inner,
+        }
+    }
+}
+
+impl<T: Read + Unpin> Read for Prefixed<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.offset < this.prefix.len() {
+            let remaining = &this.prefix[this.offset..];
+            let length = remaining.len().min(buf.len());
+            buf[..length].copy_from_slice(&remaining[..length]);
+            this.offset += length;
+            return Poll::Ready(Ok(length));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: Write + Unpin> Write for Prefixed<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+pub struct NoiseStream<T> {
+    inner: T,
+    transport: TransportState,
+    incoming: Vec<u8>,
+    plaintext: Vec<u8>,
+    plaintext_offset: usize,
+    outgoing: Vec<u8>,
+}
+
+impl<T> NoiseStream<T> {
+    fn new(inner: T, transport: TransportState) -> NoiseStream<T> {
+        NoiseStream {
+            inner,
+            transport,
+            incoming: Vec::new(),
+            plaintext: Vec::new(),
+            plaintext_offset: 0,
+            outgoing: Vec::new(),
+        }
+    }
+}
+
+impl<T: Write + Unpin> NoiseStream<T> {
+    fn poll_write_outgoing(&mut self, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        while !self.outgoing.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.outgoing) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => {
+                    self.outgoing.drain(..written);
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: Read + Unpin> Read for NoiseStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.plaintext_offset < this.plaintext.len() {
+                let remaining = &this.plaintext[this.plaintext_offset..];
+                let length = remaining.len().min(buf.len());
+                buf[..length].copy_from_slice(&remaining[..length]);
+                this.plaintext_offset += length;
+                return Poll::Ready(Ok(length));
+            }
+
+            if this.incoming.len() >= 2 {
+                let length = u16::from_be_bytes([this.incoming[0], this.incoming[1]]) as usize;
+                if this.incoming.len() >= 2 + length {
+                    this.plaintext.resize(MAX_MESSAGE_LENGTH, 0);
+                    let decrypted = this
+                        .transport
+                        .read_message(&this.incoming[2..2 + length], &mut this.plaintext);
+                    this.incoming.drain(..2 + length);
+                    match decrypted {
+                        Ok(decrypted) => {
+                            this.plaintext.truncate(decrypted);
+                            this.plaintext_offset = 0;
+                            continue;
+                        }
+                        Err(error) => return Poll::Ready(Err(to_io_error(error))),
+                    }
+                }
+            }
+
+            let mut chunk = [0u8; 8192];
+            match Pin::new(&mut this.inner).poll_read(cx, &mut chunk) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(if this.incoming.is_empty() {
+                        Ok(0)
+                    } else {
+                        Err(io::ErrorKind::UnexpectedEof.into())
+                    })
+                }
+                Poll::Ready(Ok(read)) => this.incoming.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+}
+
+impl<T: Write + Unpin> Write for NoiseStream<T> {
+    /// The frame is written lazily, so data is only sent after a flush.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_write_outgoing(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+            Poll::Ready(Ok(())) => {}
+        }
+        let length = buf.len().min(MAX_PLAINTEXT_LENGTH);
+        let mut frame = vec![0u8; 2 + length + TAG_LENGTH];
+        let encrypted = match this
+            .transport
+            .write_message(&buf[..length], &mut frame[2..])
+        {
+            Ok(encrypted) => encrypted,
+            Err(error) => return Poll::Ready(Err(to_io_error(error))),
+        };
+        frame[..2].copy_from_slice(&(encrypted as u16).to_be_bytes());
+        frame.truncate(2 + encrypted);
+        this.outgoing = frame;
+        Poll::Ready(Ok(length))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_outgoing(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_write_outgoing(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_close(cx),
+            other => other,
+        }
+    }
+}
+
+pub enum Stream<T> {
+    Plaintext(Prefixed<T>),
+    Noise(NoiseStream<T>),
+    Compressed {
+        stream: Compressed<NoiseStream<T>>,
+        remote_static_key: Vec<u8>,
+    },
+}
+
+impl<T> Stream<T> {
+    pub fn remote_static_key(&self) -> Option<&[u8]> {
+        match self {
+            Stream::Plaintext(_) => None,
+            Stream::Noise(stream) => stream.transport.get_remote_static(),
+            Stream::Compressed {
+                remote_static_key, ..
+            } => Some(remote_static_key),
+        }
+    }
+
+    /// Noise peers keep their static key across addresses, so they are known by it.
+    pub fn peer_identity(&self, fallback: String) -> String {
+        match self.remote_static_key() {
+            Some(key) => format!("noise:{}", base64::encode(key)),
+            None => fallback,
+        }
+    }
+}
+
+impl<T: Read + Write + Unpin> Read for Stream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plaintext(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Noise(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Compressed { stream, .. } => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<T: Read + Write + Unpin> Write for Stream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plaintext(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Noise(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Compressed { stream, .. } => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plaintext(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Noise(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Compressed { stream, .. } => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plaintext(stream) => Pin::new(stream).poll_close(cx),
+            Stream::Noise(stream) => Pin::new(stream).poll_close(cx),
+            Stream::Compressed { stream, .. } => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use async_std::os::unix::net::UnixStream;
+    use futures::future::join;
+
+    async fn transfer(from: &mut Stream<UnixStream>, to: &mut Stream<UnixStream>, data: &[u8]) {
+        let mut received = vec![0u8; data.len()];
+        let send = async {
+            from.write_all(data).await.unwrap();
+            from.flush().await.unwrap();
+        };
+        join(send, async { to.read_exact(&mut received).await.unwrap() }).await;
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn noise_links_carry_data_both_ways() {
+        let (a, b) = UnixStream::pair().unwrap();
+        async_std::task::block_on(async {
+            let (a, b) = join(
+                dial(
+                    a,
+                    NoisePolicy::Enabled,
+                    &generate_keypair(),
+                    Compression::None,
+                    &Rc::default(),
+                ),
+                accept(
+                    b,
+                    NoisePolicy::Required,
+                    &generate_keypair(),
+                    Compression::Zstd,
+                    &Rc::default(),
+                    None,
+                ),
+            )
+            .await;
+            let (mut a, mut b) = (a.unwrap(), b.unwrap());
+            assert!(matches!(a, Stream::Noise(_)));
+            assert!(matches!(b, Stream::Noise(_)));
+            assert!(a
+                .peer_identity("fallback".to_string())
+                .starts_with("noise:"));
+            // Larger than a single Noise message.
+            let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+            transfer(&mut a, &mut b, &data).await;
+            transfer(&mut b, &mut a, &data).await;
+        });
+    }
+
+    #[test]
+    fn plaintext_peers_are_still_served() {
+        let (a, b) = UnixStream::pair().unwrap();
+        async_std::task::block_on(async {
+            let mut a = dial(
+                a,
+                NoisePolicy::Disabled,
+                &generate_keypair(),
+                Compression::Zstd,
+                &Rc::default(),
+            )
+            .await
+            .unwrap();
+            let greeting = b"sniffed bytes are replayed";
+            a.write_all(greeting).await.unwrap();
+            a.flush().await.unwrap();
+            let mut b = accept(
+                b,
+                NoisePolicy::Enabled,
+                &generate_keypair(),
+                Compression::Zstd,
+                &Rc::default(),
+                None,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(b, Stream::Plaintext(_)));
+            assert_eq!(b.peer_identity("fallback".to_string()), "fallback");
+            let mut received = vec![0u8; greeting.len()];
+            b.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received[..], &greeting[..]);
+            transfer(&mut b, &mut a, b"replies are plaintext").await;
+        });
+    }
+
+    #[test]
+    fn plaintext_peers_are_turned_away_when_noise_is_required() {
+        let (a, b) = UnixStream::pair().unwrap();
+        async_std::task::block_on(async {
+            let mut a = dial(
+                a,
+                NoisePolicy::Disabled,
+                &generate_keypair(),
+                Compression::Zstd,
+                &Rc::default(),
+            )
+            .await
+            .unwrap();
+            a.write_all(b"plaintext").await.unwrap();
+            a.flush().await.unwrap();
+            let error = accept(
+                b,
+                NoisePolicy::Required,
+                &generate_keypair(),
+                Compression::Zstd,
+                &Rc::default(),
+                None,
+            )
+            .await
+            .err()
+            .unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        });
+    }
+
+    #[test]
+    fn compression_is_negotiated() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let statistics = Rc::new(Statistics::default());
+        async_std::task::block_on(async {
+            let (a, b) = join(
+                dial(
+                    a,
+                    NoisePolicy::Enabled,
+                    &generate_keypair(),
+                    Compression::Zstd,
+                    &statistics,
+                ),
+                accept(
+                    b,
+                    NoisePolicy::Enabled,
+                    &generate_keypair(),
+                    Compression::Zstd,
+                    &statistics,
+                    None,
+                ),
+            )
+            .await;
+            let (mut a, mut b) = (a.unwrap(), b.unwrap());
+            assert!(matches!(a, Stream::Compressed { .. }));
+            assert!(b.remote_static_key().is_some());
+            let data = b"Cap'n Proto framing is mostly zeroes. ".repeat(1000);
+            transfer(&mut a, &mut b, &data).await;
+            transfer(&mut b, &mut a, &data).await;
+        });
+        let snapshot = statistics.snapshot();
+        assert_eq!(snapshot.links, 2);
+        assert_eq!(snapshot.uncompressed_sent, 2 * 38_000);
+        assert_eq!(snapshot.uncompressed_received, 2 * 38_000);
+        assert!(snapshot.compressed_sent < snapshot.uncompressed_sent / 10);
+    }
+
+    #[test]
+    fn reverse_listeners_serve_silent_peers_in_plaintext() {
+        let (mut a, b) = UnixStream::pair().unwrap();
+        async_std::task::block_on(async {
+            // A reverse peer that predates Noise waits for the listener to
+            // speak first.
+            let patience = Some(Duration::from_millis(100));
+            let mut b = accept(
+                b,
+                NoisePolicy::Enabled,
+                &generate_keypair(),
+                Compression::Zstd,
+                &Rc::default(),
+                patience,
+            )
+            .await
+            .unwrap();
+            assert!(matches!(b, Stream::Plaintext(_)));
+            b.write_all(b"the listener speaks first").await.unwrap();
+            b.flush().await.unwrap();
+            let mut received = [0u8; 25];
+            a.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received[..], &b"the listener speaks first"[..]);
+
+            let (_a, b) = UnixStream::pair().unwrap();
+            let error = accept(
+                b,
+                NoisePolicy::Required,
+                &generate_keypair(),
+                Compression::Zstd,
+                &Rc::default(),
+                patience,
+            )
+            .await
+            .err()
+            .unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        });
+    }
+
+    #[test]
+    fn announced_keys_are_checked() {
+        let server = crate::simulation::Node::new().context;
+        let client = crate::simulation::Node::new().context;
+        async_std::task::block_on(async {
+            for (public_key, admitted) in vec![
+                (server.noise_keypair.public.clone(), true),
+                (generate_keypair().public, false),
+            ] {
+                let (a, b) = UnixStream::pair().unwrap();
+                let (dialed, _) = join(
+                    dial_known_peer(a, &client, &public_key),
+                    accept_peer(b, &server),
+                )
+                .await;
+                match dialed {
+                    Ok(_) => assert!(admitted),
+                    Err(error) => {
+                        assert!(!admitted);
+                        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+                    }
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn backends_sync_inventories_over_noise() {
+        use crate::connection_registry::Link;
+        use crate::inventory::{insert_message, message_exists, Message};
+        use crate::message_hash::message_hash;
+        use async_std::net::{TcpListener, TcpStream};
+        use futures::task::LocalSpawnExt;
+        use std::sync::Arc;
+
+        async fn publish(context: &Context, payload: &[u8]) -> Arc<Vec<u8>> {
+            let expiration_time = chrono::Utc::now().timestamp() + 3600;
+            insert_message(
+                &context.on_disk_tx,
+                Message {
+                    payload: payload.to_vec(),
+                    nonce: 0,
+                    expiration_time,
+                },
+            )
+            .await;
+            Arc::new(message_hash(payload, expiration_time).to_vec())
+        }
+
+        crate::simulation::stub_out_proof_of_work();
+        let server = crate::simulation::Node::new().context;
+        let client = crate::simulation::Node::new().context;
+        let mut pool = futures::executor::LocalPool::new();
+        let spawner = pool.spawner();
+        let listener = pool.run_until(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let address = listener.local_addr().unwrap();
+        let hashes = pool.run_until(async {
+            vec![
+                publish(&server, b"from the server").await,
+                publish(&client, b"from the client").await,
+            ]
+        });
+
+        {
+            let server = server.clone();
+            let client_key = client.noise_keypair.public.clone();
+            spawner
+                .spawn_local(async move {
+                    let (socket, address) = listener.accept().await.unwrap();
+                    let socket = accept_peer(socket, &server).await.unwrap();
+                    assert_eq!(socket.remote_static_key(), Some(&client_key[..]));
+                    let link = Link {
+                        id: None,
+                        peer: socket.peer_identity(address.ip().to_string()),
+                        remote_static_key: socket.remote_static_key().map(<[u8]>::to_vec),
+                        address: address.to_string(),
+                        inbound: true,
+                    };
+                    let _ = crate::reconcile_server::init_server(socket, link, server).await;
+                })
+                .unwrap();
+        }
+        {
+            let client = client.clone();
+            let server_key = server.noise_keypair.public.clone();
+            spawner
+                .spawn_local(async move {
+                    let socket = TcpStream::connect(address).await.unwrap();
+                    let socket = dial_peer(socket, &client).await.unwrap();
+                    assert_eq!(socket.remote_static_key(), Some(&server_key[..]));
+                    let link = Link {
+                        id: None,
+                        peer: socket.peer_identity(address.ip().to_string()),
+                        remote_static_key: socket.remote_static_key().map(<[u8]>::to_vec),
+                        address: address.to_string(),
+                        inbound: false,
+                    };
+                    let _ = crate::reconcile_client::reconcile(socket, link, client).await;
+                })
+                .unwrap();
+        }
+
+        let synced = pool.run_until(timeout(Duration::from_secs(30), async {
+            loop {
+                let mut held = 0;
+                for context in &[&server, &client] {
+                    for hash in &hashes {
+                        if message_exists(&context.in_memory_tx, hash.clone()).await {
+                            held += 1;
+                        }
+                    }
+                }
+                if held == 4 {
+                    break;
+                }
+                async_std::task::sleep(Duration::from_millis(10)).await;
+            }
+        }));
+        assert!(synced.is_ok(), "Inventories didn't sync");
+    }
+}
@@ -0,0 +1,290 @@
+use crate::connect::{connect, Address};
+use crate::inventory::{
+    add_known_peer_failure, get_known_peers, put_known_peers, put_reachable_known_peer, OnDisk,
+};
+use crate::log;
+use crate::reconcile_capnp::{known_peer, reconcile as Reconcile};
+use crate::session::Context;
+use crate::socks5::split_host_port;
+use async_std::sync::Sender;
+use chrono::Utc;
+use futures::executor::LocalSpawner;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Sent or accepted in one exchange.
+pub const MAX_SHARED_PEERS: usize = 100;
+pub const MAX_KNOWN_PEERS: usize = 1000;
+pub const MAX_FAILURES: u32 = 3;
+/// In seconds.
+pub const MAX_AGE: i64 = 7 * 24 * 60 * 60;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KnownPeer {
+    pub address: String,
+    /// In seconds since the Unix epoch.
+    pub last_seen: i64,
+    /// Since the last successful attempt.
+    pub failures: u32,
+}
+
+/// Unix sockets are local to the node that dialed them, and not every node
+/// listens for QUIC or UDP.
+pub fn normalize(address: &str) -> Option<String> {
+    match Address::parse(address) {
+        Address::Tcp(address) => Some(address),
+        Address::Unix(_) | Address::Quic(_) | Address::Udp(_) => None,
+    }
+}
+
+pub fn shareable(peers: Vec<KnownPeer>, now: i64) -> Vec<KnownPeer> {
+    peers
+        .into_iter()
+        .filter(|peer| peer.failures < MAX_FAILURES && peer.last_seen >= now - MAX_AGE)
+        .take(MAX_SHARED_PEERS)
+        .collect()
+}
+
+/// Loopback, unspecified and link-local addresses would point the node at itself
+/// or at its own network segment.
+fn dialable(address: &str) -> bool {
+    let host = match split_host_port(address) {
+        Ok((host, _)) => host,
+        Err(_) => return false,
+    };
+    if host.eq_ignore_ascii_case("localhost") {
+        return false;
+    }
+    let ip = match host.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        // Host names are resolved by whoever dials them, possibly a proxy.
+        Err(_) => return true,
+    };
+    let local = |ip: IpAddr| {
+        let link_local = match ip {
+            IpAddr::V4(ip) => ip.is_link_local(),
+            IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+        };
+        ip.is_loopback() || ip.is_unspecified() || link_local
+    };
+    // IPv4 addresses can hide inside IPv6 ones.
+    let embedded = match ip {
+        IpAddr::V6(ip) => ip.to_ipv4().map(IpAddr::V4),
+        IpAddr::V4(_) => None,
+    };
+    !(local(ip) || embedded.map_or(false, local))
+}
+
+/// Last-seen times are capped at the present, so that no peer can push its
+/// entries to the top of every list.
+pub fn sanitize(peers: Vec<KnownPeer>, now: i64) -> Vec<KnownPeer> {
+    peers
+        .into_iter()
+        .take(MAX_SHARED_PEERS)
+        .filter(|peer| dialable(&peer.address))
+        .filter(|peer| peer.failures < MAX_FAILURES && peer.last_seen >= now - MAX_AGE)
+        .map(|peer| KnownPeer {
+            last_seen: peer.last_seen.min(now),
+            failures: 0,
+            ..peer
+        })
+        .collect()
+}
+
+pub fn write(peers: &[KnownPeer], mut builder: capnp::struct_list::Builder<known_peer::Owned>) {
+    for (index, peer) in peers.iter().enumerate() {
+        let mut entry = builder.reborrow().get(index as u32);
+        entry.set_address(&peer.address);
+        entry.set_last_seen(peer.last_seen);
+        entry.set_failures(peer.failures);
+    }
+}
+
+/// `remote_ip` is set if the remote side was reached directly over TCP.
+pub async fn exchange(
+    remote: &Reconcile::Client,
+    remote_ip: Option<IpAddr>,
+    on_disk_tx: &Sender<OnDisk>,
+) -> capnp::Result<()> {
+    let response = remote.get_peers_request().send().promise.await?;
+    let response = response.get()?;
+    let mut peers = Vec::new();
+    for peer in response.get_peers()?.iter() {
+        peers.push(KnownPeer {
+            address: peer.get_address()?.to_string(),
+            last_seen: peer.get_last_seen(),
+            failures: peer.get_failures(),
+        });
+    }
+    let now = Utc::now().timestamp();
+    let mut peers = sanitize(peers, now);
+    if let (Some(ip), port) = (remote_ip, response.get_listen_port()) {
+        if port != 0 {
+            peers.push(KnownPeer {
+                address: SocketAddr::new(ip, port).to_string(),
+                last_seen: now,
+                failures: 0,
+            });
+        }
+    }
+    put_known_peers(on_disk_tx, peers).await;
+    Ok(())
+}
+
+pub async fn maintain(context: Context, spawner: LocalSpawner, target: usize) {
+    let dialing = Rc::new(RefCell::new(HashSet::<String>::new()));
+    loop {
+        async_std::task::sleep(MAINTENANCE_INTERVAL).await;
+        // Peers dialed here stay in `dialing` for as long as their sessions
+        // last.
+        let mut busy: HashSet<String> = context
+            .connections
+            .read()
+            .await
+            .list()
+            .into_iter()
+            .filter(|connection| !connection.inbound)
+            .filter_map(|connection| normalize(&connection.address))
+            .collect();
+        busy.extend(dialing.borrow().iter().cloned());
+        let missing = target.saturating_sub(busy.len());
+        if missing == 0 || context.connections.read().await.is_full() {
+            continue;
+        }
+        let candidates: Vec<KnownPeer> = get_known_peers(&context.on_disk_tx)
+            .await
+            .into_iter()
+            .filter(|peer| peer.failures < MAX_FAILURES && !busy.contains(&peer.address))
+            .take(missing)
+            .collect();
+        for peer in candidates {
+            let address = peer.address;
+            dialing.borrow_mut().insert(address.clone());
+            let done = {
+                let dialing = dialing.clone();
+                let address = address.clone();
+                move || {
+                    dialing.borrow_mut().remove(&address);
+                }
+            };
+            let (done1, done2, done3) = (done.clone(), done.clone(), done);
+            let (address1, address2, address3) =
+                (address.clone(), address.clone(), address.clone());
+            connect(
+                address.clone(),
+                format!("peer-exchange-{}", address),
+                context.clone(),
+                spawner.clone(),
+                move |error| {
+                    log::warning(format!(
+                        "Can't connect to known peer {} due to error {:?}",
+                        address1, error
+                    ));
+                    done1();
+                },
+                move |error| {
+                    log::warning(format!(
+                        "Error occurred while reconciling with known peer {} due to error {:?}",
+                        address2, error
+                    ));
+                    done2();
+                },
+                move || {
+                    log::notice(format!("Connection to known peer {} severed", address3));
+                    done3();
+                },
+            );
+        }
+    }
+}
+
+pub async fn record_attempt(on_disk_tx: &Sender<OnDisk>, address: &str, reached: bool) {
+    if let Some(address) = normalize(address) {
+        if reached {
+            put_reachable_known_peer(on_disk_tx, address, Utc::now().timestamp()).await;
+        } else {
+            add_known_peer_failure(on_disk_tx, address).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(address: &str, last_seen: i64, failures: u32) -> KnownPeer {
+        KnownPeer {
+            address: address.to_string(),
+            last_seen,
+            failures,
+        }
+    }
+
+    #[test]
+    fn stale_and_failing_peers_are_not_shared() {
+        let now = MAX_AGE * 2;
+        let peers = vec![
+            peer("10.0.0.1:8080", now, 0),
+            peer("10.0.0.2:8080", now, MAX_FAILURES),
+            peer("10.0.0.3:8080", now - MAX_AGE - 1, 0),
+        ];
+        assert_eq!(shareable(peers, now), vec![peer("10.0.0.1:8080", now, 0)]);
+    }
+
+    #[test]
+    fn received_peers_are_sanitized() {
+        let now = MAX_AGE * 2;
+        let peers = vec![
+            peer("example.onion:8080", now + 1000, 0),
+            peer("10.0.0.1:8080", now, MAX_FAILURES - 1),
+            peer("unix:///tmp/contrasleuth.sock", now, 0),
+            peer("10.0.0.1", now, 0),
+            peer("127.0.0.1:8080", now, 0),
+            peer("localhost:8080", now, 0),
+            peer("0.0.0.0:8080", now, 0),
+            peer("169.254.0.1:8080", now, 0),
+            peer("[::1]:8080", now, 0),
+            peer("[::ffff:127.0.0.1]:8080", now, 0),
+            peer("[fe80::1]:8080", now, 0),
+        ];
+        assert_eq!(
+            sanitize(peers, now),
+            vec![
+                peer("example.onion:8080", now, 0),
+                peer("10.0.0.1:8080", now, 0),
+            ]
+        );
+        let flood = (0..MAX_SHARED_PEERS * 2)
+            .map(|index| peer(&format!("10.0.0.1:{}", index + 1), now, 0))
+            .collect();
+        assert_eq!(sanitize(flood, now).len(), MAX_SHARED_PEERS);
+    }
+
+    #[test]
+    fn gossip_evicts_the_peers_seen_least_recently() {
+        let context = crate::simulation::Node::new().context;
+        async_std::task::block_on(async {
+            for round in 0..MAX_KNOWN_PEERS / MAX_SHARED_PEERS + 1 {
+                let peers = (0..MAX_SHARED_PEERS)
+                    .map(|index| {
+                        let index = round * MAX_SHARED_PEERS + index;
+                        peer(
+                            &format!("10.0.{}.{}:8080", index / 256, index % 256),
+                            index as i64,
+                            0,
+                        )
+                    })
+                    .collect();
+                put_known_peers(&context.on_disk_tx, peers).await;
+            }
+            let known = get_known_peers(&context.on_disk_tx).await;
+            assert_eq!(known.len(), MAX_KNOWN_PEERS);
+            assert_eq!(known.last().unwrap().last_seen, MAX_SHARED_PEERS as i64);
+        });
+    }
+}
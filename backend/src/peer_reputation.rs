@@ -0,0 +1,251 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const FORGOTTEN: f64 = 0.1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Offense {
+    InvalidProofOfWork,
+    MalformedMessage,
+    ExpiredMessage,
+    /// Peers that completed the handshake know our limits, but legacy peers don't.
+    PolicyViolation,
+}
+
+impl Offense {
+    /// Duplicates aren't offenses at all, because racing sessions send them
+    /// routinely.
+    fn weight(self) -> f64 {
+        match self {
+            Offense::InvalidProofOfWork => 10.0,
+            Offense::MalformedMessage => 10.0,
+            Offense::ExpiredMessage => 2.0,
+            Offense::PolicyViolation => 2.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Thresholds {
+    pub throttle: f64,
+    pub ban: f64,
+    /// In seconds.
+    pub ban_duration: i64,
+    /// In seconds.
+    pub half_life: i64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Thresholds {
+        Thresholds {
+            throttle: 50.0,
+            ban: 200.0,
+            ban_duration: 3600,
+            half_life: 600,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Accept,
+    Throttle,
+    Ban,
+}
+
+#[derive(Default)]
+struct Record {
+    score: f64,
+    last_updated: i64,
+    invalid_proof_of_work: u64,
+    malformed_message: u64,
+    expired_message: u64,
+    policy_violation: u64,
+    banned_until: Option<i64>,
+}
+
+impl Record {
+    fn decay(&mut self, half_life: i64, now: i64) {
+        let elapsed = (now - self.last_updated).max(0) as f64;
+        self.score *= 0.5f64.powf(elapsed / half_life.max(1) as f64);
+        self.last_updated = now;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PeerReputationEntry {
+    pub peer: String,
+    pub score: f64,
+    pub invalid_proof_of_work: u64,
+    pub malformed_message: u64,
+    pub expired_message: u64,
+    pub policy_violation: u64,
+    pub banned_until: Option<i64>,
+}
+
+/// Peers are identified by their Noise static key when they have one, and by
+/// their remote address otherwise.
+pub struct PeerReputation {
+    thresholds: Thresholds,
+    records: HashMap<String, Record>,
+    last_expired: i64,
+}
+
+impl PeerReputation {
+    pub fn new(thresholds: Thresholds) -> PeerReputation {
+        PeerReputation {
+            thresholds,
+            records: HashMap::new(),
+            last_expired: 0,
+        }
+    }
+
+    /// Runs at most once per half-life, so that it doesn't cost a scan per offense.
+    fn expire(&mut self, now: i64) {
+        if now - self.last_expired < self.thresholds.half_life {
+            return;
+        }
+        let half_life = self.thresholds.half_life;
+        self.records.retain(|_, record| {
+            record.decay(half_life, now);
+            record.score >= FORGOTTEN || record.banned_until.is_some_and(|until| until > now)
+        });
+        self.last_expired = now;
+    }
+
+    pub fn record(&mut self, peer: &str, offense: Offense, now: i64) -> Verdict {
+        self.expire(now);
+        let thresholds = &self.thresholds;
+        let record = self
+            .records
+            .entry(peer.to_string())
+            .or_insert_with(|| Record {
+                last_updated: now,
+                ..Default::default()
+            });
+        record.decay(thresholds.half_life, now);
+        record.score += offense.weight();
+        match offense {
+            Offense::InvalidProofOfWork => record.invalid_proof_of_work += 1,
+            Offense::MalformedMessage => record.malformed_message += 1,
+            Offense::ExpiredMessage => record.expired_message += 1,
+            Offense::PolicyViolation => record.policy_violation += 1,
+        }
+
+        if record.score > thresholds.ban {
+            record.banned_until = Some(now + thresholds.ban_duration);
+            return Verdict::Ban;
+        }
+        if record.score > thresholds.throttle {
+            return Verdict::Throttle;
+        }
+        Verdict::Accept
+    }
+
+    pub fn is_banned(&self, peer: &str, now: i64) -> bool {
+        match self.records.get(peer) {
+            Some(Record {
+                banned_until: Some(banned_until),
+                ..
+            }) => *banned_until > now,
+            _ => false,
+        }
+    }
+
+    pub fn clear(&mut self, peer: &str) -> bool {
+        self.records.remove(peer).is_some()
+    }
+
+    pub fn dump(&mut self, now: i64) -> Vec<PeerReputationEntry> {
+        let half_life = self.thresholds.half_life;
+        self.records
+            .iter_mut()
+            .map(|(peer, record)| {
+                record.decay(half_life, now);
+                PeerReputationEntry {
+                    peer: peer.to_string(),
+                    score: record.score,
+                    invalid_proof_of_work: record.invalid_proof_of_work,
+                    malformed_message: record.malformed_message,
+                    expired_message: record.expired_message,
+                    policy_violation: record.policy_violation,
+                    banned_until: match record.banned_until {
+                        Some(banned_until) if banned_until > now => Some(banned_until),
+                        _ => None,
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn floods_are_throttled_then_banned() {
+        let mut reputation = PeerReputation::new(Default::default());
+        let mut verdicts = Vec::new();
+        for _ in 0..25 {
+            verdicts.push(reputation.record("10.0.0.1", Offense::InvalidProofOfWork, 0));
+        }
+        assert_eq!(verdicts[0], Verdict::Accept);
+        assert!(verdicts.contains(&Verdict::Throttle));
+        assert_eq!(verdicts.last(), Some(&Verdict::Ban));
+        assert!(reputation.is_banned("10.0.0.1", 0));
+        assert!(!reputation.is_banned("10.0.0.1", 3600));
+        assert!(!reputation.is_banned("10.0.0.2", 0));
+    }
+
+    #[test]
+    fn scores_decay() {
+        let mut reputation = PeerReputation::new(Default::default());
+        for _ in 0..10 {
+            reputation.record("10.0.0.1", Offense::ExpiredMessage, 0);
+        }
+        // Expired messages an hour apart never add up to anything.
+        for hour in 1..10 {
+            assert_eq!(
+                reputation.record("10.0.0.1", Offense::ExpiredMessage, hour * 3600),
+                Verdict::Accept
+            );
+        }
+        assert!(reputation.dump(36000)[0].score < 2.0);
+    }
+
+    #[test]
+    fn bans_can_be_cleared() {
+        let mut reputation = PeerReputation::new(Default::default());
+        while reputation.record("10.0.0.1", Offense::MalformedMessage, 0) != Verdict::Ban {}
+        assert!(reputation.clear("10.0.0.1"));
+        assert!(!reputation.is_banned("10.0.0.1", 0));
+        assert!(!reputation.clear("10.0.0.1"));
+    }
+
+    #[test]
+    fn decayed_records_are_forgotten() {
+        let thresholds = Thresholds {
+            ban_duration: 86400,
+            ..Default::default()
+        };
+        let half_life = thresholds.half_life;
+        let mut reputation = PeerReputation::new(thresholds);
+        for index in 0..1000 {
+            reputation.record(
+                &format!("10.0.{}.{}", index / 256, index % 256),
+                Offense::MalformedMessage,
+                0,
+            );
+        }
+        while reputation.record("10.1.0.1", Offense::MalformedMessage, 0) != Verdict::Ban {}
+        assert_eq!(reputation.records.len(), 1001);
+
+        // Only the ban keeps the banned peer's record around.
+        let later = half_life * 20;
+        reputation.record("10.1.0.2", Offense::ExpiredMessage, later);
+        let mut remaining: Vec<String> = reputation.records.keys().cloned().collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["10.1.0.1", "10.1.0.2"]);
+    }
+}
@@ -61,6 +61,13 @@ pub fn verify(payload: &[u8], nonce: i64, expiration_time: i64) -> bool {
         return true;
     }
 
+    #[cfg(test)]
+    {
+        if crate::simulation::proof_of_work_stubbed_out() {
+            return true;
+        }
+    }
+
     let expected_target = match get_expected_target2(payload, expiration_time) {
         Some(target) => target,
         None => return false,
@@ -73,6 +80,21 @@ pub fn verify(payload: &[u8], nonce: i64, expiration_time: i64) -> bool {
     current_target <= expected_target
 }
 
+/// Lower is harder.
+pub fn achieved_target(payload: &[u8], nonce: i64) -> u64 {
+    let mut hasher = Blake2b::new(64);
+    hasher.input(payload);
+    let mut payload_hash = [0u8; 64];
+    hasher.result(&mut payload_hash);
+    get_current_target(&payload_hash, nonce)
+}
+
+pub fn surplus(payload_length: u64, expiration_time: i64, achieved_target: u64, now: i64) -> f64 {
+    let time_to_live = (expiration_time - now).max(0) as u64;
+    let expected_target = get_expected_target(payload_length, time_to_live);
+    expected_target as f64 / achieved_target.max(1) as f64
+}
+
 /// Loosely based on https://github.com/imrehg/bmpow-rust/blob/master/src/lib.rs
 /// This function returns None when the PoW operation is cancelled.
 pub async fn prove(
@@ -0,0 +1,167 @@
+//! Announce-and-pull relaying. Payloads are sealed, so whether a message is
+//! addressed to a local inbox can't be told before fetching it.
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const ANNOUNCEMENT_BATCH_SIZE: u32 = 256;
+/// A larger message is fetched on its own.
+pub const FETCH_BATCH_BYTES: u64 = 4 * 1024 * 1024;
+pub const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// In seconds.
+const BUDGET_PERIOD: i64 = 60 * 60;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announcement {
+    pub hash: Arc<Vec<u8>>,
+    pub size: u64,
+    pub expiration_time: i64,
+}
+
+#[derive(Debug)]
+pub struct PullPolicy {
+    /// Zero means that there is no limit.
+    pub max_message_size: u64,
+    /// In seconds.
+    pub min_time_to_live: i64,
+    /// Per hour across every peer. Zero means that there is no limit.
+    pub bandwidth_budget: u64,
+    /// The start of the current budget period and the bytes spent in it.
+    spent: Cell<(i64, u64)>,
+}
+
+impl PullPolicy {
+    pub fn new(max_message_size: u64, min_time_to_live: i64, bandwidth_budget: u64) -> PullPolicy {
+        PullPolicy {
+            max_message_size,
+            min_time_to_live,
+            bandwidth_budget,
+            spent: Cell::new((0, 0)),
+        }
+    }
+
+    /// Admitted messages are charged to the bandwidth budget.
+    pub fn admit(&self, announcement: &Announcement, now: i64) -> bool {
+        if self.max_message_size != 0 && announcement.size > self.max_message_size {
+            return false;
+        }
+        if announcement.expiration_time - now < self.min_time_to_live {
+            return false;
+        }
+        if self.bandwidth_budget == 0 {
+            return true;
+        }
+        let (period_start, spent) = match self.spent.get() {
+            (period_start, spent) if now - period_start < BUDGET_PERIOD => (period_start, spent),
+            _ => (now, 0),
+        };
+        if spent + announcement.size > self.bandwidth_budget {
+            self.spent.set((period_start, spent));
+            return false;
+        }
+        self.spent.set((period_start, spent + announcement.size));
+        true
+    }
+
+    pub fn refund(&self, announcement: &Announcement, admitted_at: i64) {
+        let (period_start, spent) = self.spent.get();
+        if admitted_at >= period_start {
+            self.spent
+                .set((period_start, spent.saturating_sub(announcement.size)));
+        }
+    }
+}
+
+pub fn fetch_batches(announcements: Vec<Announcement>) -> Vec<Vec<Announcement>> {
+    let mut batches: Vec<Vec<Announcement>> = Vec::new();
+    let mut bytes = 0;
+    for announcement in announcements {
+        match batches.last_mut() {
+            Some(batch) if bytes + announcement.size <= FETCH_BATCH_BYTES => {
+                bytes += announcement.size;
+                batch.push(announcement);
+            }
+            _ => {
+                bytes = announcement.size;
+                batches.push(vec![announcement]);
+            }
+        }
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(size: u64, expiration_time: i64) -> Announcement {
+        Announcement {
+            hash: Arc::new(vec![0; 64]),
+            size,
+            expiration_time,
+        }
+    }
+
+    #[test]
+    fn large_and_expiring_messages_are_skipped() {
+        let policy = PullPolicy::new(1000, 60, 0);
+        assert!(policy.admit(&announcement(1000, 1060), 1000));
+        assert!(!policy.admit(&announcement(1001, 1060), 1000));
+        assert!(!policy.admit(&announcement(10, 1059), 1000));
+    }
+
+    #[test]
+    fn bandwidth_budget_is_replenished_hourly() {
+        let policy = PullPolicy::new(0, 0, 1500);
+        let now = 10_000;
+        assert!(policy.admit(&announcement(1000, now + 60), now));
+        assert!(!policy.admit(&announcement(1000, now + 60), now + 1));
+        assert!(policy.admit(&announcement(500, now + 60), now + 2));
+        let later = now + BUDGET_PERIOD;
+        assert!(policy.admit(&announcement(1000, later + 60), later));
+    }
+
+    #[test]
+    fn failed_fetches_are_refunded() {
+        let policy = PullPolicy::new(0, 0, 1500);
+        let now = 10_000;
+        let message = announcement(1000, now + 60);
+        assert!(policy.admit(&message, now));
+        policy.refund(&message, now);
+        assert!(policy.admit(&announcement(1500, now + 60), now + 1));
+
+        // Refunds don't carry over into the next period.
+        let later = now + BUDGET_PERIOD;
+        assert!(policy.admit(&announcement(1000, later + 60), later));
+        policy.refund(&message, now);
+        assert!(!policy.admit(&announcement(1000, later + 60), later + 1));
+    }
+
+    #[test]
+    fn fetch_batches_are_capped_by_size() {
+        let sizes = |batches: Vec<Vec<Announcement>>| {
+            batches
+                .iter()
+                .map(|batch| batch.iter().map(|announcement| announcement.size).collect())
+                .collect::<Vec<Vec<u64>>>()
+        };
+        let half = FETCH_BATCH_BYTES / 2;
+        let announcements = vec![
+            announcement(half, 0),
+            announcement(half, 0),
+            announcement(1, 0),
+            announcement(FETCH_BATCH_BYTES * 2, 0),
+            announcement(1, 0),
+        ];
+        assert_eq!(
+            sizes(fetch_batches(announcements)),
+            vec![
+                vec![half, half],
+                vec![1],
+                vec![FETCH_BATCH_BYTES * 2],
+                vec![1]
+            ]
+        );
+        assert!(fetch_batches(Vec::new()).is_empty());
+    }
+}
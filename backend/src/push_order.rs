@@ -0,0 +1,94 @@
+use crate::proof_of_work;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PushOrder {
+    InsertionOrder,
+    NewestFirst,
+    SoonestExpiringFirst,
+    SmallestFirst,
+    HighestProofOfWorkSurplusFirst,
+}
+
+impl Default for PushOrder {
+    fn default() -> PushOrder {
+        PushOrder::InsertionOrder
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Item {
+    pub counter: u128,
+    pub expiration_time: i64,
+    pub size: u64,
+    pub achieved_target: u64,
+}
+
+impl Item {
+    fn surplus(&self, now: i64) -> f64 {
+        proof_of_work::surplus(self.size, self.expiration_time, self.achieved_target, now)
+    }
+}
+
+/// Ties are broken by insertion order.
+pub fn sort(items: &mut Vec<(Arc<Vec<u8>>, Item)>, order: PushOrder, now: i64) {
+    items.sort_by(|(_, a), (_, b)| {
+        let ordering = match order {
+            PushOrder::InsertionOrder => Ordering::Equal,
+            PushOrder::NewestFirst => b.counter.cmp(&a.counter),
+            PushOrder::SoonestExpiringFirst => a.expiration_time.cmp(&b.expiration_time),
+            PushOrder::SmallestFirst => a.size.cmp(&b.size),
+            PushOrder::HighestProofOfWorkSurplusFirst => b
+                .surplus(now)
+                .partial_cmp(&a.surplus(now))
+                .unwrap_or(Ordering::Equal),
+        };
+        ordering.then(a.counter.cmp(&b.counter))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items() -> Vec<(Arc<Vec<u8>>, Item)> {
+        vec![
+            (1, 500, 30, u64::max_value() / 1_000_000),
+            (2, 100, 20, u64::max_value() / 1_000_000_000),
+            (3, 300, 10, u64::max_value() / 1000),
+        ]
+        .into_iter()
+        .map(|(counter, expiration_time, size, achieved_target)| {
+            (
+                Arc::new(vec![counter as u8]),
+                Item {
+                    counter,
+                    expiration_time,
+                    size,
+                    achieved_target,
+                },
+            )
+        })
+        .collect()
+    }
+
+    fn sorted(order: PushOrder) -> Vec<u128> {
+        let mut items = items();
+        sort(&mut items, order, 0);
+        items.into_iter().map(|(_, item)| item.counter).collect()
+    }
+
+    #[test]
+    fn strategies() {
+        assert_eq!(sorted(PushOrder::InsertionOrder), vec![1, 2, 3]);
+        assert_eq!(sorted(PushOrder::NewestFirst), vec![3, 2, 1]);
+        assert_eq!(sorted(PushOrder::SoonestExpiringFirst), vec![2, 3, 1]);
+        assert_eq!(sorted(PushOrder::SmallestFirst), vec![3, 2, 1]);
+        assert_eq!(
+            sorted(PushOrder::HighestProofOfWorkSurplusFirst),
+            vec![2, 1, 3]
+        );
+    }
+}
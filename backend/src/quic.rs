@@ -0,0 +1,272 @@
+//! Certificates are self-signed and not verified. Peers are authenticated by
+//! the Noise handshake that runs over the stream, as they are over TCP.
+use async_io::{Async, Timer};
+use async_std::io::{self, Read, Write};
+use async_std::net::ToSocketAddrs;
+use futures::task::{Context, Poll};
+use futures::Future;
+use quinn::udp::{RecvMeta, Transmit, UdpSocketState, UdpState};
+use quinn::{
+    AsyncTimer, AsyncUdpSocket, ClientConfig, Connecting, Connection, Endpoint, EndpointConfig,
+    RecvStream, Runtime, SendStream, ServerConfig,
+};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+const SERVER_NAME: &str = "contrasleuth";
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    _connection: Connection,
+}
+
+impl Read for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl Write for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_close(cx)
+    }
+}
+
+/// quinn's own async-std runtime requires a newer async-std than the backend is
+/// built against.
+#[derive(Debug)]
+struct AsyncIoRuntime;
+
+impl Runtime for AsyncIoRuntime {
+    fn new_timer(&self, instant: Instant) -> Pin<Box<dyn AsyncTimer>> {
+        Box::pin(QuicTimer(Timer::at(instant)))
+    }
+
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        async_std::task::spawn(future);
+    }
+
+    fn wrap_udp_socket(&self, socket: std::net::UdpSocket) -> io::Result<Box<dyn AsyncUdpSocket>> {
+        UdpSocketState::configure((&socket).into())?;
+        Ok(Box::new(UdpSocket {
+            io: Async::new(socket)?,
+            state: UdpSocketState::new(),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct QuicTimer(Timer);
+
+impl AsyncTimer for QuicTimer {
+    fn reset(mut self: Pin<&mut Self>, instant: Instant) {
+        self.0.set_at(instant)
+    }
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        Pin::new(&mut self.0).poll(cx).map(|_| ())
+    }
+}
+
+#[derive(Debug)]
+struct UdpSocket {
+    io: Async<std::net::UdpSocket>,
+    state: UdpSocketState,
+}
+
+impl AsyncUdpSocket for UdpSocket {
+    fn poll_send(
+        &self,
+        state: &UdpState,
+        cx: &mut Context,
+        transmits: &[Transmit],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            futures::ready!(self.io.poll_writable(cx))?;
+            if let Ok(sent) = self.state.send((&self.io).into(), state, transmits) {
+                return Poll::Ready(Ok(sent));
+            }
+        }
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        buffers: &mut [std::io::IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            futures::ready!(self.io.poll_readable(cx))?;
+            if let Ok(received) = self.state.recv((&self.io).into(), buffers, meta) {
+                return Poll::Ready(Ok(received));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.as_ref().local_addr()
+    }
+}
+
+fn endpoint(config: Option<ServerConfig>, address: SocketAddr) -> io::Result<Endpoint> {
+    Endpoint::new(
+        EndpointConfig::default(),
+        config,
+        std::net::UdpSocket::bind(address)?,
+        Arc::new(AsyncIoRuntime),
+    )
+}
+
+fn other(error: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    Arc::new(transport)
+}
+
+struct SkipServerVerification;
+
+impl rustls::client::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _: &rustls::Certificate,
+        _: &[rustls::Certificate],
+        _: &rustls::ServerName,
+        _: &mut dyn Iterator<Item = &[u8]>,
+        _: &[u8],
+        _: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+fn client_config() -> ClientConfig {
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
+        .with_no_client_auth();
+    let mut config = ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport_config());
+    config
+}
+
+pub fn listen(address: SocketAddr) -> io::Result<Endpoint> {
+    let certificate =
+        rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()]).map_err(other)?;
+    let mut config = ServerConfig::with_single_cert(
+        vec![rustls::Certificate(
+            certificate.serialize_der().map_err(other)?,
+        )],
+        rustls::PrivateKey(certificate.serialize_private_key_der()),
+    )
+    .map_err(other)?;
+    config.transport_config(transport_config());
+    endpoint(Some(config), address)
+}
+
+pub async fn establish(connecting: Connecting) -> io::Result<(QuicStream, SocketAddr)> {
+    let connection = connecting.await?;
+    let (send, recv) = connection.accept_bi().await?;
+    let address = connection.remote_address();
+    Ok((
+        QuicStream {
+            send,
+            recv,
+            _connection: connection,
+        },
+        address,
+    ))
+}
+
+/// The handshake is left to `establish`, so that a slow peer doesn't hold up
+/// the ones behind it.
+pub async fn accept(endpoint: &Endpoint) -> Option<Connecting> {
+    endpoint.accept().await
+}
+
+pub async fn dial(address: &str) -> io::Result<(QuicStream, SocketAddr)> {
+    let remote = address.to_socket_addrs().await?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} doesn't resolve to any address", address),
+        )
+    })?;
+    let local: SocketAddr = if remote.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let endpoint = endpoint(None, local)?;
+    let connection = endpoint
+        .connect_with(client_config(), remote, SERVER_NAME)
+        .map_err(other)?
+        .await?;
+    let (send, recv) = connection.open_bi().await?;
+    Ok((
+        QuicStream {
+            send,
+            recv,
+            _connection: connection,
+        },
+        remote,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn streams_cross_loopback() {
+        async_std::task::block_on(async {
+            let endpoint = listen("127.0.0.1:0".parse().unwrap()).unwrap();
+            let address = endpoint.local_addr().unwrap();
+            let server = async_std::task::spawn(async move {
+                let connecting = accept(&endpoint).await.unwrap();
+                let (mut stream, _) = establish(connecting).await.unwrap();
+                let mut request = [0u8; 5];
+                stream.read_exact(&mut request).await.unwrap();
+                assert_eq!(&request, b"hello");
+                stream.write_all(b"world").await.unwrap();
+                stream.close().await.unwrap();
+                // Lets the peer read the reply before the connection goes.
+                let mut rest = Vec::new();
+                stream.read_to_end(&mut rest).await.unwrap();
+            });
+
+            // The stream only reaches the server once something is written.
+            let (mut stream, remote) = dial(&address.to_string()).await.unwrap();
+            assert_eq!(remote, address);
+            stream.write_all(b"hello").await.unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, b"world");
+            stream.close().await.unwrap();
+            server.await;
+        });
+    }
+}
@@ -0,0 +1,327 @@
+//! Once a limit is hit, stored messages are evicted in favour of new ones.
+//! Pinned messages and messages `derive_state` hasn't checked yet are never
+//! evicted. When only those are left, new messages are refused instead.
+use crate::inventory_index::{Entry, InventoryIndex};
+use crate::proof_of_work;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eviction {
+    OldestFirst,
+    /// Per byte.
+    LowestSurplusFirst,
+    /// By XOR distance. Nodes then keep different parts of the network's messages.
+    FarthestFromInboxesFirst,
+}
+
+impl Default for Eviction {
+    fn default() -> Eviction {
+        Eviction::OldestFirst
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quota {
+    /// Zero means that there is no limit.
+    pub max_bytes: u64,
+    /// Zero means that there is no limit.
+    pub max_messages: usize,
+    pub eviction: Eviction,
+}
+
+fn surplus_per_byte(entry: &Entry, now: i64) -> f64 {
+    proof_of_work::surplus(
+        entry.metadata.size,
+        entry.expiration_time,
+        entry.metadata.achieved_target,
+        now,
+    ) / entry.metadata.size.max(1) as f64
+}
+
+fn distance(hash: &[u8], inboxes: &[Vec<u8>]) -> Vec<u8> {
+    inboxes
+        .iter()
+        .map(|inbox| {
+            hash.iter()
+                .zip(inbox.iter())
+                .map(|(a, b)| a ^ b)
+                .collect::<Vec<_>>()
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+/// Ties go to the oldest message.
+type Rank = (Vec<u8>, u128);
+
+/// Kept up to date as messages come and go, so that making room doesn't sort
+/// the inventory.
+#[derive(Default)]
+pub struct EvictionOrder {
+    eviction: Eviction,
+    inboxes: Vec<Vec<u8>>,
+    /// Surpluses shrink as messages age, so they are all compared as of the last
+    /// rebuild.
+    reference: i64,
+    ranks: HashMap<Arc<Vec<u8>>, Rank>,
+    order: BTreeSet<(Rank, Arc<Vec<u8>>)>,
+}
+
+impl EvictionOrder {
+    pub fn new(eviction: Eviction) -> EvictionOrder {
+        EvictionOrder {
+            eviction,
+            ..Default::default()
+        }
+    }
+
+    fn rank(&self, hash: &[u8], entry: &Entry) -> Rank {
+        let key = match self.eviction {
+            Eviction::OldestFirst => Vec::new(),
+            // The bits of non-negative floats sort like the floats.
+            Eviction::LowestSurplusFirst => surplus_per_byte(entry, self.reference)
+                .to_bits()
+                .to_be_bytes()
+                .to_vec(),
+            // Inverted, so that the farthest messages come first.
+            Eviction::FarthestFromInboxesFirst => distance(hash, &self.inboxes)
+                .iter()
+                .map(|byte| !byte)
+                .collect(),
+        };
+        (key, entry.counter)
+    }
+
+    pub fn insert(&mut self, hash: Arc<Vec<u8>>, entry: &Entry) {
+        let rank = self.rank(&hash, entry);
+        self.order.insert((rank.clone(), hash.clone()));
+        self.ranks.insert(hash, rank);
+    }
+
+    pub fn remove(&mut self, hash: &Arc<Vec<u8>>) {
+        if let Some(rank) = self.ranks.remove(hash) {
+            self.order.remove(&(rank, hash.clone()));
+        }
+    }
+
+    pub fn rebuild<'a>(
+        &mut self,
+        inboxes: Vec<Vec<u8>>,
+        now: i64,
+        evictable: impl Iterator<Item = (&'a Arc<Vec<u8>>, &'a Entry)>,
+    ) {
+        self.inboxes = inboxes;
+        self.reference = now;
+        self.ranks.clear();
+        self.order.clear();
+        for (hash, entry) in evictable {
+            self.insert(hash.clone(), entry);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Vec<u8>>> {
+        self.order.iter().map(|(_, hash)| hash)
+    }
+}
+
+impl Quota {
+    fn exceeded(&self, messages: usize, bytes: u64) -> bool {
+        (self.max_messages != 0 && messages > self.max_messages)
+            || (self.max_bytes != 0 && bytes > self.max_bytes)
+    }
+
+    /// `None` as the size brings the inventory back under the limits without adding
+    /// a message.
+    pub fn make_room(
+        &self,
+        index: &InventoryIndex,
+        size: Option<u64>,
+    ) -> Option<Vec<Arc<Vec<u8>>>> {
+        let (mut messages, mut bytes) = match size {
+            Some(size) => (index.count() + 1, index.bytes() + size),
+            None => (index.count(), index.bytes()),
+        };
+        let mut evicted = Vec::new();
+        for hash in index.eviction_order().iter() {
+            if !self.exceeded(messages, bytes) {
+                break;
+            }
+            messages -= 1;
+            bytes -= index.get(hash).unwrap().metadata.size;
+            evicted.push(hash.clone());
+        }
+        if size.is_some() && self.exceeded(messages, bytes) {
+            return None;
+        }
+        Some(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::Metadata;
+
+    fn index(eviction: Eviction, inboxes: &[Vec<u8>]) -> InventoryIndex {
+        let mut index = InventoryIndex::with_eviction(eviction);
+        index.set_local_inboxes(inboxes.to_vec(), 0);
+        let achieved_targets = [
+            u64::max_value() / 1_000_000,
+            u64::max_value() / 1_000_000_000,
+            u64::max_value() / 1000,
+            u64::max_value() / 100_000_000,
+        ];
+        for (position, &achieved_target) in achieved_targets.iter().enumerate() {
+            let hash = Arc::new(vec![0x10 * position as u8; 4]);
+            index.insert(
+                hash.clone(),
+                Entry {
+                    counter: position as u128 + 1,
+                    expiration_time: 1000,
+                    metadata: Metadata {
+                        size: 100,
+                        achieved_target,
+                    },
+                    pinned: false,
+                    pending: false,
+                },
+            );
+            if position == 1 {
+                index.pin(&hash);
+            }
+        }
+        index
+    }
+
+    fn evicted(quota: Quota, size: u64, inboxes: &[Vec<u8>]) -> Option<Vec<u8>> {
+        quota
+            .make_room(&index(quota.eviction, inboxes), Some(size))
+            .map(|hashes| hashes.iter().map(|hash| hash[0] / 0x10).collect())
+    }
+
+    #[test]
+    fn policies_pick_different_messages() {
+        let quota = |eviction| Quota {
+            max_bytes: 400,
+            max_messages: 0,
+            eviction,
+        };
+        assert_eq!(
+            evicted(quota(Eviction::OldestFirst), 150, &[]),
+            Some(vec![0, 2])
+        );
+        assert_eq!(
+            evicted(quota(Eviction::LowestSurplusFirst), 150, &[]),
+            Some(vec![2, 0])
+        );
+        assert_eq!(
+            evicted(
+                quota(Eviction::FarthestFromInboxesFirst),
+                50,
+                &[vec![0x28; 4]]
+            ),
+            Some(vec![0])
+        );
+    }
+
+    #[test]
+    fn pinned_messages_are_never_evicted() {
+        let quota = Quota {
+            max_bytes: 0,
+            max_messages: 2,
+            eviction: Eviction::OldestFirst,
+        };
+        assert_eq!(evicted(quota, 10, &[]), Some(vec![0, 2, 3]));
+        let quota = Quota {
+            max_messages: 1,
+            ..quota
+        };
+        assert_eq!(evicted(quota, 10, &[]), None);
+        assert_eq!(
+            quota
+                .make_room(&index(quota.eviction, &[]), None)
+                .map(|hashes| hashes.len()),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn pending_messages_are_evicted_once_processed() {
+        let quota = Quota {
+            max_bytes: 0,
+            max_messages: 4,
+            eviction: Eviction::OldestFirst,
+        };
+        let mut index = index(quota.eviction, &[]);
+        let hash = Arc::new(vec![0x40; 4]);
+        index.insert(
+            hash.clone(),
+            Entry {
+                counter: 0,
+                expiration_time: 1000,
+                metadata: Metadata {
+                    size: 100,
+                    achieved_target: 0,
+                },
+                pinned: false,
+                pending: true,
+            },
+        );
+        let evicted = |index: &InventoryIndex| {
+            quota
+                .make_room(index, Some(10))
+                .map(|hashes| hashes.iter().map(|hash| hash[0] / 0x10).collect())
+        };
+        assert_eq!(evicted(&index), Some(vec![0, 2]));
+        assert!(index.mark_processed(&hash));
+        assert_eq!(evicted(&index), Some(vec![4, 0]));
+    }
+
+    #[test]
+    fn insertions_can_evict_many_messages() {
+        use crate::inventory::{insert_message, message_exists, Insertion, Message};
+        use crate::message_hash::message_hash;
+        use async_std::{future, task};
+        use std::time::Duration;
+
+        let context = crate::simulation::Node::with_quota(Quota {
+            max_bytes: 1000,
+            max_messages: 0,
+            eviction: Eviction::OldestFirst,
+        })
+        .context;
+        let expiration_time = chrono::Utc::now().timestamp() + 60 * 60;
+        let message = |byte: u8, size: usize| Message {
+            payload: vec![byte; size],
+            nonce: 0,
+            expiration_time,
+        };
+        task::block_on(async {
+            for byte in 0..8 {
+                let inserted = insert_message(&context.on_disk_tx, message(byte, 100)).await;
+                assert!(matches!(inserted, Insertion::Inserted));
+            }
+            // Room is only made once `derive_state` has checked the stored
+            // messages, and making it purges seven of them at once.
+            let inserted = future::timeout(Duration::from_secs(30), async {
+                while let Insertion::Refused =
+                    insert_message(&context.on_disk_tx, message(8, 900)).await
+                {
+                    task::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await;
+            assert!(inserted.is_ok());
+            let mut kept = Vec::new();
+            for byte in 0..9 {
+                let size = if byte == 8 { 900 } else { 100 };
+                let hash = message_hash(&vec![byte; size], expiration_time).to_vec();
+                if message_exists(&context.in_memory_tx, Arc::new(hash)).await {
+                    kept.push(byte);
+                }
+            }
+            assert_eq!(kept, vec![7, 8]);
+        });
+    }
+}
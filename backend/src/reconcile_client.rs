@@ -1,111 +1,617 @@
-use crate::inventory::{get_message, get_one_after_counter, InMemory, OnDisk};
+use crate::acceptance_policy::AcceptancePolicy;
+use crate::connection_registry::{Connection, Link};
+use crate::forwarding::{self, Forwarding, Strategy};
+use crate::handshake::Features;
+use crate::inventory::{
+    forward, get_all_after_counter, get_counter, get_message, get_peer_watermark, insert_forwarded_message,
+    message_exists, set_peer_watermark, snapshot, sort, InMemory, Insertion, Message, OnDisk,
+};
+use crate::log;
+use crate::message_hash::message_hash;
 use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
-use crate::reconcile_capnp::reconcile as Reconcile;
+use crate::pull::{self, Announcement, PullPolicy};
+use crate::push_order::PushOrder;
+use crate::reconcile_capnp::{range_response, reconcile as Reconcile, SubmitResult};
+use crate::session::{self, Context, SessionError};
+use crate::set_reconciliation::{Range, Reconciler, Response, Summary, MAX_RANGES};
 use async_std::io::{Read, Write};
 use async_std::sync::{RwLock, Sender};
-use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
-use futures::task::LocalSpawn;
-use futures::AsyncReadExt;
-use futures_intrusive::channel::LocalUnbufferedChannel;
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use futures_intrusive::sync::LocalManualResetEvent;
+use std::collections::HashSet;
+use std::rc::Rc;
+use std::sync::Arc;
 
-pub async fn reconcile<T: Read + Write + 'static>(
-    stream: T,
-    in_memory_tx: &Sender<InMemory>,
+pub const BATCH_SIZE: usize = 64;
+
+const WINDOW_SIZE: usize = 8;
+
+async fn request_ranges(
+    reconcile: &Reconcile::Client,
+    queries: &[(Range, Summary)],
+) -> Result<Vec<Response>, capnp::Error> {
+    let mut request = reconcile.reconcile_ranges_request();
+    {
+        let mut ranges = request.get().init_ranges(queries.len() as u32);
+        for (index, (range, summary)) in queries.iter().enumerate() {
+            let mut entry = ranges.reborrow().get(index as u32);
+            entry.set_lower(&range.lower);
+            entry.set_upper(&range.upper);
+            entry.set_fingerprint(&summary.fingerprint);
+            entry.set_count(summary.count);
+        }
+    }
+    let result = request.send().promise.await?;
+    let responses = result.get()?.get_responses()?;
+    if responses.len() as usize != queries.len() {
+        return Err(capnp::Error::failed(
+            "Server returned the wrong number of range responses".to_string(),
+        ));
+    }
+    let mut list = Vec::new();
+    for response in responses.iter() {
+        list.push(match response.which()? {
+            range_response::Which::Identical(()) => Response::Identical,
+            range_response::Which::Hashes(hashes) => {
+                let mut list = Vec::new();
+                for hash in hashes?.iter() {
+                    list.push(Arc::new(hash?.to_vec()));
+                }
+                Response::Hashes(list)
+            }
+            range_response::Which::Mismatch(()) => Response::Mismatch,
+        });
+    }
+    Ok(list)
+}
+
+async fn reconcile_ranges(
+    reconcile: &Reconcile::Client,
+    hashes: Vec<Arc<Vec<u8>>>,
+) -> Result<Vec<Arc<Vec<u8>>>, capnp::Error> {
+    let mut reconciler = Reconciler::new(hashes);
+    let mut missing = Vec::new();
+    while !reconciler.is_done() {
+        let queries = reconciler.queries();
+        if queries.is_empty() {
+            break;
+        }
+        // The requests of a round are sent all at once.
+        let requests = queries
+            .chunks(MAX_RANGES)
+            .map(|chunk| request_ranges(reconcile, chunk));
+        let mut responses = Vec::new();
+        for result in futures::future::join_all(requests).await {
+            responses.extend(result?);
+        }
+        for ((range, _), response) in queries.into_iter().zip(responses) {
+            reconciler.process(range, response);
+        }
+        missing.extend(reconciler.take_missing_on_remote());
+
+        // The tests are sent all at once so that they only cost a single round trip.
+        let untested = reconciler.take_untested();
+        let promises = untested.iter().map(|hash| {
+            let mut request = reconcile.test_request();
+            request.get().set_hash(hash);
+            request.send().promise
+        });
+        let results = futures::future::join_all(promises).await;
+        for (hash, result) in untested.into_iter().zip(results) {
+            if !result?.get()?.get_exists() {
+                missing.push(hash);
+            }
+        }
+    }
+    Ok(missing)
+}
+
+enum Retrieval {
+    Found(Message, Forwarding),
+    Gone,
+    HeldBack,
+}
+
+async fn retrieve(
+    on_disk_tx: &Sender<OnDisk>,
+    policy: &AcceptancePolicy,
+    strategy: Strategy,
+    hash: Arc<Vec<u8>>,
+) -> Retrieval {
+    let message = match get_message(&on_disk_tx, hash.clone()).await {
+        Some(message) => message,
+        None => return Retrieval::Gone,
+    };
+    if policy
+        .check(
+            message.payload.len(),
+            message.expiration_time,
+            Utc::now().timestamp(),
+        )
+        .is_err()
+    {
+        return Retrieval::HeldBack;
+    }
+    match forward(&on_disk_tx, hash, strategy).await {
+        Some(forwarding) => Retrieval::Found(message, forwarding),
+        None => Retrieval::HeldBack,
+    }
+}
+
+fn log_rejection(result: Result<SubmitResult, capnp::NotInSchema>) -> bool {
+    match result {
+        Ok(SubmitResult::Accepted) => return true,
+        Ok(SubmitResult::PayloadTooLarge) => {
+            log::notice("The peer rejected a message because its payload is too large")
+        }
+        Ok(SubmitResult::TimeToLiveTooLong) => {
+            log::notice("The peer rejected a message because its time-to-live is too long")
+        }
+        Ok(SubmitResult::TimeToLiveTooShort) => {
+            log::notice("The peer rejected a message because its time-to-live is too short")
+        }
+        Ok(SubmitResult::QuotaExceeded) => {
+            log::notice("The peer rejected a message because its storage quota is exhausted")
+        }
+        Err(_) => log::notice("The peer rejected a message for an unknown reason"),
+    }
+    false
+}
+
+#[derive(Default)]
+struct Outcome {
+    sent: usize,
+    /// They must be offered again later.
+    undelivered: Vec<Arc<Vec<u8>>>,
+}
+
+async fn submit(
+    reconcile: &Reconcile::Client,
+    on_disk_tx: &Sender<OnDisk>,
+    policy: &AcceptancePolicy,
+    strategy: Strategy,
+    hash: Arc<Vec<u8>>,
+) -> Result<Outcome, capnp::Error> {
+    let mut outcome = Outcome::default();
+    match retrieve(on_disk_tx, policy, strategy, hash.clone()).await {
+        Retrieval::Found(message, forwarding) => {
+            let mut request = reconcile.submit_request();
+            request.get().get_message()?.set_payload(&message.payload);
+            request.get().get_message()?.set_nonce(message.nonce);
+            request
+                .get()
+                .get_message()?
+                .set_expiration_time(message.expiration_time);
+            forwarding::write(forwarding, request.get().get_message()?);
+            let response = request.send().promise.await?;
+            outcome.sent = 1;
+            if !log_rejection(response.get()?.get_result()) {
+                outcome.undelivered.push(hash);
+            }
+        }
+        Retrieval::Gone => {}
+        Retrieval::HeldBack => outcome.undelivered.push(hash),
+    }
+    Ok(outcome)
+}
+
+async fn submit_many(
+    reconcile: &Reconcile::Client,
     on_disk_tx: &Sender<OnDisk>,
-    spawner: futures::executor::LocalSpawner,
-    reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+    policy: &AcceptancePolicy,
+    strategy: Strategy,
+    hashes: Vec<Arc<Vec<u8>>>,
+) -> Result<Outcome, capnp::Error> {
+    let mut outcome = Outcome::default();
+    let mut messages = Vec::new();
+    for hash in hashes {
+        match retrieve(on_disk_tx, policy, strategy, hash.clone()).await {
+            Retrieval::Found(message, forwarding) => messages.push((hash, message, forwarding)),
+            Retrieval::Gone => {}
+            Retrieval::HeldBack => outcome.undelivered.push(hash),
+        }
+    }
+    if messages.is_empty() {
+        return Ok(outcome);
+    }
+    let mut request = reconcile.submit_many_request();
+    {
+        let mut list = request.get().init_messages(messages.len() as u32);
+        for (index, (_, message, forwarding)) in messages.iter().enumerate() {
+            let mut entry = list.reborrow().get(index as u32);
+            entry.set_payload(&message.payload);
+            entry.set_nonce(message.nonce);
+            entry.set_expiration_time(message.expiration_time);
+            forwarding::write(*forwarding, entry);
+        }
+    }
+    let response = request.send().promise.await?;
+    let results = response.get()?.get_results()?;
+    if results.len() as usize != messages.len() {
+        return Err(capnp::Error::failed(
+            "Server returned the wrong number of submit results".to_string(),
+        ));
+    }
+    for ((hash, _, _), result) in messages.into_iter().zip(results.iter()) {
+        if !log_rejection(result) {
+            outcome.undelivered.push(hash);
+        }
+    }
+    outcome.sent = results.len() as usize;
+    Ok(outcome)
+}
+
+async fn test_many(
+    reconcile: &Reconcile::Client,
+    hashes: Vec<Arc<Vec<u8>>>,
+) -> Result<Vec<Arc<Vec<u8>>>, capnp::Error> {
+    let mut request = reconcile.test_many_request();
+    {
+        let mut list = request.get().init_hashes(hashes.len() as u32);
+        for (index, hash) in hashes.iter().enumerate() {
+            list.set(index as u32, hash);
+        }
+    }
+    let result = request.send().promise.await?;
+    let exists = result.get()?.get_exists()?;
+    if exists.len() as usize != hashes.len() {
+        return Err(capnp::Error::failed(
+            "Server returned the wrong number of test results".to_string(),
+        ));
+    }
+    Ok(hashes
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !exists.get(*index as u32))
+        .map(|(_, hash)| hash)
+        .collect())
+}
+
+/// The hashes are tested first unless the server is already known to lack them.
+async fn push_batch(
+    reconcile: Reconcile::Client,
+    on_disk_tx: Sender<OnDisk>,
+    batching: bool,
+    policy: AcceptancePolicy,
+    strategy: Strategy,
+    hashes: Vec<Arc<Vec<u8>>>,
+    known_missing: bool,
+    connection: Rc<Connection>,
+) -> Result<Vec<Arc<Vec<u8>>>, capnp::Error> {
+    if batching {
+        let missing = if known_missing {
+            hashes
+        } else {
+            test_many(&reconcile, hashes).await?
+        };
+        let outcome = submit_many(&reconcile, &on_disk_tx, &policy, strategy, missing).await?;
+        connection.record_messages_sent(outcome.sent);
+        return Ok(outcome.undelivered);
+    }
+
+    let mut undelivered = Vec::new();
+    for hash in hashes {
+        if !known_missing {
+            let mut request = reconcile.test_request();
+            request.get().set_hash(&hash);
+            let result = request.send().promise.await?;
+            if result.get()?.get_exists() {
+                continue;
+            }
+        }
+        let outcome = submit(&reconcile, &on_disk_tx, &policy, strategy, hash).await?;
+        connection.record_messages_sent(outcome.sent);
+        undelivered.extend(outcome.undelivered);
+    }
+    Ok(undelivered)
+}
+
+struct Pipeline {
+    reconcile: Reconcile::Client,
+    on_disk_tx: Sender<OnDisk>,
+    batching: bool,
+    policy: AcceptancePolicy,
+    strategy: Strategy,
+    connection: Rc<Connection>,
+    in_flight: FuturesUnordered<LocalBoxFuture<'static, Result<Vec<Arc<Vec<u8>>>, capnp::Error>>>,
+    undelivered: Vec<Arc<Vec<u8>>>,
+}
+
+impl Pipeline {
+    fn new(
+        reconcile: Reconcile::Client,
+        on_disk_tx: Sender<OnDisk>,
+        batching: bool,
+        policy: AcceptancePolicy,
+        strategy: Strategy,
+        connection: Rc<Connection>,
+    ) -> Pipeline {
+        Pipeline {
+            reconcile,
+            on_disk_tx,
+            batching,
+            policy,
+            strategy,
+            connection,
+            in_flight: FuturesUnordered::new(),
+            undelivered: Vec::new(),
+        }
+    }
+
+    async fn push(
+        &mut self,
+        hashes: Vec<Arc<Vec<u8>>>,
+        known_missing: bool,
+    ) -> Result<(), capnp::Error> {
+        while self.in_flight.len() >= WINDOW_SIZE {
+            if let Some(result) = self.in_flight.next().await {
+                self.undelivered.extend(result?);
+            }
+        }
+        self.in_flight.push(
+            push_batch(
+                self.reconcile.clone(),
+                self.on_disk_tx.clone(),
+                self.batching,
+                self.policy,
+                self.strategy,
+                hashes,
+                known_missing,
+                self.connection.clone(),
+            )
+            .boxed_local(),
+        );
+        Ok(())
+    }
+
+    async fn drain(&mut self) -> Result<Vec<Arc<Vec<u8>>>, capnp::Error> {
+        while let Some(result) = self.in_flight.next().await {
+            self.undelivered.extend(result?);
+        }
+        Ok(std::mem::take(&mut self.undelivered))
+    }
+}
+
+/// The watermark, up to which the peer is known to have every message, is kept
+/// under the peer's Noise static key.
+pub async fn push(
+    reconcile: Reconcile::Client,
+    features: Features,
+    policy: AcceptancePolicy,
+    strategy: Strategy,
+    peer: Option<Vec<u8>>,
+    order: PushOrder,
+    in_memory_tx: Sender<InMemory>,
+    on_disk_tx: Sender<OnDisk>,
+    event: Rc<LocalManualResetEvent>,
+    connection: Rc<Connection>,
 ) -> Result<(), capnp::Error> {
-    let (reader, writer) = stream.split();
-    let network = twoparty::VatNetwork::new(
-        reader,
-        writer,
-        rpc_twoparty_capnp::Side::Client,
-        Default::default(),
+    let mut pipeline = Pipeline::new(
+        reconcile.clone(),
+        on_disk_tx.clone(),
+        features.batching,
+        policy,
+        strategy,
+        connection,
     );
-    let mut rpc_system = RpcSystem::new(Box::new(network), None);
-    let reconcile: Reconcile::Client = rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
-    let handle = reconciliation_intent.write().await.get_handle();
-    let reconciliation_intent1 = reconciliation_intent.clone();
-    let reconciliation_intent2 = reconciliation_intent.clone();
-
-    #[derive(Debug)]
-    enum TerminateOrProceed {
-        Terminate(Result<(), capnp::Error>),
-        Proceed,
-    };
 
-    let channel = std::rc::Rc::new(LocalUnbufferedChannel::new());
-    let channel1 = channel.clone();
-    let channel2 = channel.clone();
-
-    spawner
-        .spawn_local_obj(
-            Box::new(async move {
-                if let Err(error) = rpc_system.await {
-                    if let Err(_) = channel1
-                        .send(TerminateOrProceed::Terminate(Err(error)))
-                        .await
-                    {}
-                } else {
-                    if let Err(_) = channel1.send(TerminateOrProceed::Terminate(Ok(()))).await {}
-                }
-                reconciliation_intent1.write().await.drop_handle(handle);
-            })
-            .into(),
-        )
-        .unwrap();
-
-    spawner
-        .spawn_local_obj(
-            Box::new(async move {
-                let event = reconciliation_intent2.read().await.get_event(handle);
-                loop {
-                    event.wait().await;
-                    event.reset();
-                    if let Err(_) = channel2.send(TerminateOrProceed::Proceed).await {
-                        break;
-                    }
-                }
-            })
-            .into(),
-        )
-        .unwrap();
+    let mut watermark = match &peer {
+        Some(peer) => get_peer_watermark(&on_disk_tx, peer.clone()).await,
+        None => None,
+    };
 
-    let mut counter = 0u128;
+    let mut counter = if let Some(watermark) = watermark {
+        watermark
+    } else if features.sketches {
+        // Messages inserted after the snapshot are picked up by the counter-based loop below.
+        let (hashes, latest_counter) = snapshot(&in_memory_tx).await;
+        let missing = reconcile_ranges(&reconcile, hashes).await?;
+        for batch in sort(&in_memory_tx, missing, order).await.chunks(BATCH_SIZE) {
+            pipeline.push(batch.to_vec(), true).await?;
+        }
+        latest_counter
+    } else {
+        // Without range-based reconciliation, every message is tested.
+        0u128
+    };
 
+    // The oldest message the server didn't take during this session. The
+    // watermark stays below it, so that it is offered again next time.
+    let mut oldest_undelivered: Option<u128> = None;
     loop {
+        let (hashes, latest_counter) = get_all_after_counter(&in_memory_tx, counter, order).await;
+        for batch in hashes.chunks(BATCH_SIZE) {
+            pipeline.push(batch.to_vec(), false).await?;
+        }
+        counter = latest_counter;
+        for hash in pipeline.drain().await? {
+            if let Some(undelivered) = get_counter(&in_memory_tx, hash).await {
+                oldest_undelivered = Some(match oldest_undelivered {
+                    Some(oldest) => oldest.min(undelivered),
+                    None => undelivered,
+                });
+            }
+        }
+
+        // Every message up to the counter has been tested or submitted.
+        if let Some(peer) = &peer {
+            let delivered = match oldest_undelivered {
+                Some(oldest) => counter.min(oldest - 1),
+                None => counter,
+            };
+            if watermark < Some(delivered) {
+                set_peer_watermark(&on_disk_tx, peer.clone(), delivered).await;
+                watermark = Some(delivered);
+            }
+        }
+
+        event.wait().await;
+        event.reset();
+    }
+}
+
+async fn get_announcements(
+    reconcile: &Reconcile::Client,
+    after: u64,
+) -> Result<(Vec<Announcement>, u64), capnp::Error> {
+    let mut request = reconcile.announcements_request();
+    request.get().set_after(after);
+    request.get().set_limit(pull::ANNOUNCEMENT_BATCH_SIZE);
+    let response = request.send().promise.await?;
+    let response = response.get()?;
+    let mut announcements = Vec::new();
+    for announcement in response.get_announcements()?.iter() {
+        announcements.push(Announcement {
+            hash: Arc::new(announcement.get_hash()?.to_vec()),
+            size: announcement.get_size(),
+            expiration_time: announcement.get_expiration_time(),
+        });
+    }
+    Ok((announcements, response.get_next()))
+}
+
+async fn fetch(
+    reconcile: &Reconcile::Client,
+    on_disk_tx: &Sender<OnDisk>,
+    policy: &AcceptancePolicy,
+    hashes: &[Arc<Vec<u8>>],
+) -> Result<usize, capnp::Error> {
+    let mut request = reconcile.fetch_request();
+    {
+        let mut list = request.get().init_hashes(hashes.len() as u32);
+        for (index, hash) in hashes.iter().enumerate() {
+            list.set(index as u32, hash);
+        }
+    }
+    let response = request.send().promise.await?;
+    let requested: HashSet<&Vec<u8>> = hashes.iter().map(|hash| &**hash).collect();
+    let now = Utc::now().timestamp();
+    let mut inserted = 0;
+    for message in response.get()?.get_messages()?.iter() {
+        let forwarding = forwarding::read(message);
+        let message = Message {
+            payload: message.get_payload()?.to_vec(),
+            nonce: message.get_nonce(),
+            expiration_time: message.get_expiration_time(),
+        };
+        let hash = message_hash(&message.payload, message.expiration_time).to_vec();
+        if !requested.contains(&hash)
+            || message.expiration_time <= now
+            || policy
+                .check(message.payload.len(), message.expiration_time, now)
+                .is_err()
+            || !crate::proof_of_work::verify(
+                &message.payload,
+                message.nonce,
+                message.expiration_time,
+            )
         {
-            while let Some((hash, new_counter)) =
-                get_one_after_counter(&in_memory_tx, counter).await
+            log::notice("The peer returned a message that wasn't asked for or is invalid");
+            continue;
+        }
+        if insert_forwarded_message(on_disk_tx, message, forwarding).await == Insertion::Inserted {
+            inserted += 1;
+        }
+    }
+    Ok(inserted)
+}
+
+pub async fn pull(
+    reconcile: Reconcile::Client,
+    pull_policy: Rc<PullPolicy>,
+    policy: AcceptancePolicy,
+    in_memory_tx: Sender<InMemory>,
+    on_disk_tx: Sender<OnDisk>,
+    reconciliation_intent: Rc<RwLock<MPMCManualResetEvent>>,
+    connection: Rc<Connection>,
+) -> Result<(), capnp::Error> {
+    let mut cursor = 0;
+    loop {
+        let (announcements, next) = get_announcements(&reconcile, cursor).await?;
+        let caught_up = announcements.len() < pull::ANNOUNCEMENT_BATCH_SIZE as usize;
+        let now = Utc::now().timestamp();
+        let mut wanted = Vec::new();
+        for announcement in announcements {
+            if announcement.expiration_time <= now
+                || policy
+                    .check(
+                        announcement.size as usize,
+                        announcement.expiration_time,
+                        now,
+                    )
+                    .is_err()
+                || message_exists(&in_memory_tx, announcement.hash.clone()).await
+                || !pull_policy.admit(&announcement, now)
             {
-                counter = new_counter;
-                {
-                    let mut request = reconcile.test_request();
-                    request.get().set_hash(&hash);
-                    let result = request.send().promise.await?;
-                    if result.get()?.get_exists() {
-                        continue;
+                continue;
+            }
+            wanted.push(announcement);
+        }
+
+        let mut inserted = 0;
+        let batches = pull::fetch_batches(wanted);
+        for (index, batch) in batches.iter().enumerate() {
+            let hashes: Vec<_> = batch
+                .iter()
+                .map(|announcement| announcement.hash.clone())
+                .collect();
+            match fetch(&reconcile, &on_disk_tx, &policy, &hashes).await {
+                Ok(count) => inserted += count,
+                Err(error) => {
+                    // The budget only pays for the messages actually fetched.
+                    for announcement in batches[index..].iter().flatten() {
+                        pull_policy.refund(announcement, now);
                     }
-                }
-                if let Some(message) = get_message(&on_disk_tx, hash).await {
-                    let mut request = reconcile.submit_request();
-                    request.get().get_message()?.set_payload(&message.payload);
-                    request.get().get_message()?.set_nonce(message.nonce);
-                    request
-                        .get()
-                        .get_message()?
-                        .set_expiration_time(message.expiration_time);
-                    request.send().promise.await?;
+                    return Err(error);
                 }
             }
         }
+        connection.record_messages_received(inserted);
+        if inserted != 0 {
+            reconciliation_intent.read().await.broadcast();
+        }
 
-        match channel.receive().await {
-            Some(terminate_or_proceed) => match terminate_or_proceed {
-                TerminateOrProceed::Terminate(result) => return result,
-                TerminateOrProceed::Proceed => continue,
-            },
-            None => return Ok(()),
+        // A cursor that doesn't move means the remote side has nothing newer.
+        if caught_up || next <= cursor {
+            async_std::task::sleep(pull::POLL_INTERVAL).await;
         }
+        cursor = cursor.max(next);
+    }
+}
+
+pub async fn reconcile<T: Read + Write + Unpin + 'static>(
+    stream: T,
+    link: Link,
+    context: Context,
+) -> Result<(), SessionError> {
+    session::run(stream, Side::Client, link, context).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::inventory::get_peer_watermark;
+    use crate::simulation::{Conditions, Network};
+    use std::time::Duration;
+
+    #[test]
+    fn watermarks_stop_before_messages_the_peer_didnt_take() {
+        let mut network = Network::new(2, 5);
+        network.nodes[1].context.acceptance_policy.max_payload_size = 16;
+        network.connect(0, 1, Conditions::default());
+        let small = network.publish(0, b"small".to_vec());
+        let large = network.publish(0, vec![0; 64]);
+        let newer = network.publish(0, b"newer".to_vec());
+        network.run_for(Duration::from_secs(10));
+        assert!(network.holds(1, small));
+        assert!(!network.holds(1, large));
+        assert!(network.holds(1, newer));
+
+        let on_disk_tx = network.nodes[0].context.on_disk_tx.clone();
+        let peer = network.nodes[1].context.noise_keypair.public.clone();
+        assert_eq!(network.run(get_peer_watermark(&on_disk_tx, peer)), Some(1));
     }
 }
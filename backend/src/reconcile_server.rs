@@ -1,31 +1,146 @@
-use crate::inventory::{insert_message, message_exists, InMemory, Message, OnDisk};
+use crate::acceptance_policy::Violation;
+use crate::connection_registry::{Connection, Link};
+use crate::forwarding::{self, Forwarding};
+use crate::handshake::{check, Hello, REJECTION_MARKER};
+use crate::inventory::{
+    forward, get_announcements, get_known_peers, get_message, insert_forwarded_message,
+    message_exists, respond_to_ranges, Insertion, Message,
+};
+use crate::log;
 use crate::message_hash::message_hash;
-use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
-use crate::reconcile_capnp::reconcile as Reconcile;
+use crate::peer_exchange;
+use crate::peer_reputation::{Offense, Verdict};
+use crate::pull;
+use crate::reconcile_capnp::{message, reconcile as Reconcile, SubmitResult};
+use crate::reconcile_client;
+use crate::session::{self, Context, SessionError};
+use crate::set_reconciliation::{Range, Response, Summary, FINGERPRINT_BYTES, MAX_RANGES};
 use async_std::io::{Read, Write};
-use async_std::sync::{RwLock, Sender};
+use async_std::task;
 use capnp::capability::Promise;
 use capnp::Error;
-use capnp_rpc::{pry, rpc_twoparty_capnp, twoparty, RpcSystem};
-use futures::AsyncReadExt;
+use capnp_rpc::pry;
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use chrono::Utc;
+use futures_intrusive::sync::LocalManualResetEvent;
+use std::rc::Rc;
+use std::time::Duration;
 
+const THROTTLE_DELAY: Duration = Duration::from_secs(1);
+
+fn submit_result(violation: &Violation) -> SubmitResult {
+    match violation {
+        Violation::PayloadTooLarge { .. } => SubmitResult::PayloadTooLarge,
+        Violation::TimeToLiveTooLong { .. } => SubmitResult::TimeToLiveTooLong,
+        Violation::TimeToLiveTooShort { .. } => SubmitResult::TimeToLiveTooShort,
+        Violation::QuotaExceeded => SubmitResult::QuotaExceeded,
+    }
+}
+
+enum Outcome {
+    Inserted,
+    /// Racing sessions and sketch pushes send duplicates routinely.
+    Duplicate,
+    Rejected(Offense),
+    Refused,
+}
+
+fn read_message(message: message::Reader) -> Result<(Message, Forwarding), Error> {
+    Ok((
+        Message {
+            payload: message.get_payload()?.to_vec(),
+            nonce: message.get_nonce(),
+            expiration_time: message.get_expiration_time(),
+        },
+        forwarding::read(message),
+    ))
+}
+
+async fn accept(context: &Context, message: Message, forwarding: Forwarding) -> Outcome {
+    let hash =
+        std::sync::Arc::new(message_hash(&message.payload, message.expiration_time).to_vec());
+    if message_exists(&context.in_memory_tx, hash).await {
+        return Outcome::Duplicate;
+    }
+
+    if message.expiration_time <= Utc::now().timestamp() {
+        return Outcome::Rejected(Offense::ExpiredMessage);
+    }
+
+    if !crate::proof_of_work::verify(&message.payload, message.nonce, message.expiration_time) {
+        return Outcome::Rejected(Offense::InvalidProofOfWork);
+    }
+
+    match insert_forwarded_message(&context.on_disk_tx, message, forwarding).await {
+        // Another session may have stored the message since it was checked.
+        Insertion::Inserted | Insertion::Duplicate => Outcome::Inserted,
+        Insertion::Refused => Outcome::Refused,
+    }
+}
+
+#[derive(Clone)]
 struct ReconcileRPCServer {
-    in_memory_tx: Sender<InMemory>,
-    on_disk_tx: Sender<OnDisk>,
-    reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
+    context: Context,
+    peer: Rc<String>,
+    disconnect: Rc<LocalManualResetEvent>,
+    connection: Rc<Connection>,
 }
 
 impl ReconcileRPCServer {
-    fn new(
-        in_memory_tx: Sender<InMemory>,
-        on_disk_tx: Sender<OnDisk>,
-        reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
-    ) -> ReconcileRPCServer {
-        ReconcileRPCServer {
-            in_memory_tx,
-            on_disk_tx,
-            reconciliation_intent,
+    /// Fails when the peer gets banned, which terminates the session.
+    async fn penalize(&self, offense: Offense) -> Result<(), Error> {
+        let verdict = self.context.peer_reputation.write().await.record(
+            &self.peer,
+            offense,
+            Utc::now().timestamp(),
+        );
+        match verdict {
+            Verdict::Accept => Ok(()),
+            Verdict::Throttle => {
+                task::sleep(THROTTLE_DELAY).await;
+                Ok(())
+            }
+            Verdict::Ban => {
+                log::warning(format!("Banning {} for misbehaving", self.peer));
+                self.disconnect.set();
+                Err(Error::failed("Banned for misbehaving".to_string()))
+            }
+        }
+    }
+
+    async fn submit_all(
+        &self,
+        messages: Vec<(Message, Forwarding)>,
+    ) -> Result<Vec<SubmitResult>, Error> {
+        let now = Utc::now().timestamp();
+        let mut results = Vec::new();
+        let mut inserted = false;
+        self.connection.record_messages_received(messages.len());
+        for (message, forwarding) in messages {
+            if let Err(violation) = self.context.acceptance_policy.check(
+                message.payload.len(),
+                message.expiration_time,
+                now,
+            ) {
+                self.penalize(Offense::PolicyViolation).await?;
+                results.push(submit_result(&violation));
+                continue;
+            }
+            match accept(&self.context, message, forwarding).await {
+                Outcome::Inserted => inserted = true,
+                Outcome::Duplicate => {}
+                Outcome::Rejected(offense) => self.penalize(offense).await?,
+                Outcome::Refused => {
+                    results.push(submit_result(&Violation::QuotaExceeded));
+                    continue;
+                }
+            }
+            results.push(SubmitResult::Accepted);
         }
+        if inserted {
+            self.context.reconciliation_intent.read().await.broadcast();
+        }
+        Ok(results)
     }
 }
 
@@ -35,7 +150,7 @@ impl Reconcile::Server for ReconcileRPCServer {
         params: Reconcile::TestParams,
         mut results: Reconcile::TestResults,
     ) -> Promise<(), Error> {
-        let in_memory_tx = self.in_memory_tx.clone();
+        let in_memory_tx = self.context.in_memory_tx.clone();
         Promise::from_future(async move {
             let hash = params.get()?.get_hash()?.to_vec();
             results
@@ -48,58 +163,343 @@ impl Reconcile::Server for ReconcileRPCServer {
     fn submit(
         &mut self,
         params: Reconcile::SubmitParams,
-        _results: Reconcile::SubmitResults,
+        mut results: Reconcile::SubmitResults,
+    ) -> Promise<(), Error> {
+        let server = self.clone();
+        let message = params
+            .get()
+            .and_then(|params| params.get_message())
+            .and_then(read_message);
+        Promise::from_future(async move {
+            match message {
+                Ok(message) => {
+                    let result = server.submit_all(vec![message]).await?;
+                    results.get().set_result(result[0]);
+                    Ok(())
+                }
+                Err(error) => {
+                    server.penalize(Offense::MalformedMessage).await?;
+                    Err(error)
+                }
+            }
+        })
+    }
+
+    fn test_many(
+        &mut self,
+        params: Reconcile::TestManyParams,
+        mut results: Reconcile::TestManyResults,
+    ) -> Promise<(), Error> {
+        let server = self.clone();
+        Promise::from_future(async move {
+            let list = params.get()?.get_hashes()?;
+            // Clients test no more hashes at once.
+            if list.len() as usize > reconcile_client::BATCH_SIZE {
+                server.penalize(Offense::MalformedMessage).await?;
+                return Err(Error::failed("Too many hashes tested".to_string()));
+            }
+            let mut hashes = Vec::new();
+            for hash in list.iter() {
+                hashes.push(std::sync::Arc::new(hash?.to_vec()));
+            }
+            let in_memory_tx = &server.context.in_memory_tx;
+            let mut exists = results.get().init_exists(hashes.len() as u32);
+            for (index, hash) in hashes.into_iter().enumerate() {
+                exists.set(index as u32, message_exists(in_memory_tx, hash).await);
+            }
+            Ok(())
+        })
+    }
+
+    fn submit_many(
+        &mut self,
+        params: Reconcile::SubmitManyParams,
+        mut results: Reconcile::SubmitManyResults,
     ) -> Promise<(), Error> {
-        let in_memory_tx = self.in_memory_tx.clone();
-        let on_disk_tx = self.on_disk_tx.clone();
-        let reconciliation_intent = self.reconciliation_intent.clone();
-        let message = pry!(pry!(params.get()).get_message());
-        let payload = pry!(message.get_payload()).to_vec();
-        let nonce = message.get_nonce();
-        let expiration_time = message.get_expiration_time();
+        let server = self.clone();
+        let messages = params
+            .get()
+            .and_then(|params| params.get_messages())
+            .and_then(|messages| messages.iter().map(read_message).collect());
         Promise::from_future(async move {
-            let hash1 = std::sync::Arc::new(message_hash(&payload, expiration_time).to_vec());
-            let message_exists = message_exists(&in_memory_tx, hash1).await;
-
-            let proof_of_work_valid =
-                crate::proof_of_work::verify(&payload, nonce, expiration_time);
-
-            if !message_exists && proof_of_work_valid {
-                insert_message(
-                    &on_disk_tx,
-                    Message {
-                        payload,
-                        nonce,
-                        expiration_time,
+            match messages {
+                Ok(messages) => {
+                    let submit_results = server.submit_all(messages).await?;
+                    let mut list = results.get().init_results(submit_results.len() as u32);
+                    for (index, result) in submit_results.into_iter().enumerate() {
+                        list.set(index as u32, result);
+                    }
+                    Ok(())
+                }
+                Err(error) => {
+                    server.penalize(Offense::MalformedMessage).await?;
+                    Err(error)
+                }
+            }
+        })
+    }
+
+    fn hello(
+        &mut self,
+        params: Reconcile::HelloParams,
+        mut results: Reconcile::HelloResults,
+    ) -> Promise<(), Error> {
+        let local = Hello::local(
+            &self.context.acceptance_policy,
+            &self.context.node_id,
+            self.context.pull_policy.is_some(),
+        );
+        let remote = pry!(Hello::read(pry!(pry!(params.get()).get_hello())));
+        if let Err(rejection) = check(&local, &remote) {
+            return Promise::err(Error::failed(format!("{}{}", REJECTION_MARKER, rejection)));
+        }
+        local.write(results.get().init_hello());
+        Promise::ok(())
+    }
+
+    fn get_peers(
+        &mut self,
+        _: Reconcile::GetPeersParams,
+        mut results: Reconcile::GetPeersResults,
+    ) -> Promise<(), Error> {
+        let context = self.context.clone();
+        Promise::from_future(async move {
+            let peers = peer_exchange::shareable(
+                get_known_peers(&context.on_disk_tx).await,
+                Utc::now().timestamp(),
+            );
+            let mut builder = results.get();
+            peer_exchange::write(&peers, builder.reborrow().init_peers(peers.len() as u32));
+            if let Some(address) = context.listen_address {
+                builder.set_listen_port(address.port());
+            }
+            Ok(())
+        })
+    }
+
+    fn announcements(
+        &mut self,
+        params: Reconcile::AnnouncementsParams,
+        mut results: Reconcile::AnnouncementsResults,
+    ) -> Promise<(), Error> {
+        let in_memory_tx = self.context.in_memory_tx.clone();
+        Promise::from_future(async move {
+            let params = params.get()?;
+            let limit = params.get_limit().min(pull::ANNOUNCEMENT_BATCH_SIZE) as usize;
+            let (announcements, next) =
+                get_announcements(&in_memory_tx, params.get_after() as u128, limit).await;
+            let mut builder = results.get();
+            builder.set_next(next as u64);
+            let mut list = builder.init_announcements(announcements.len() as u32);
+            for (index, announcement) in announcements.iter().enumerate() {
+                let mut entry = list.reborrow().get(index as u32);
+                entry.set_hash(&announcement.hash);
+                entry.set_size(announcement.size);
+                entry.set_expiration_time(announcement.expiration_time);
+            }
+            Ok(())
+        })
+    }
+
+    fn fetch(
+        &mut self,
+        params: Reconcile::FetchParams,
+        mut results: Reconcile::FetchResults,
+    ) -> Promise<(), Error> {
+        let server = self.clone();
+        Promise::from_future(async move {
+            let mut hashes = Vec::new();
+            for hash in params.get()?.get_hashes()?.iter() {
+                hashes.push(std::sync::Arc::new(hash?.to_vec()));
+            }
+            // Clients fetch announced messages, and no more are announced at once.
+            if hashes.len() > pull::ANNOUNCEMENT_BATCH_SIZE as usize {
+                server.penalize(Offense::MalformedMessage).await?;
+                return Err(Error::failed("Too many messages requested".to_string()));
+            }
+            let now = Utc::now().timestamp();
+            let on_disk_tx = &server.context.on_disk_tx;
+            let mut messages = Vec::new();
+            let mut bytes = 0;
+            for hash in hashes {
+                let message = match get_message(on_disk_tx, hash.clone()).await {
+                    Some(message) if message.expiration_time > now => message,
+                    _ => continue,
+                };
+                // Clients split their requests the same way, going by the
+                // announced sizes.
+                let size = message.payload.len() as u64;
+                if !messages.is_empty() && bytes + size > pull::FETCH_BATCH_BYTES {
+                    break;
+                }
+                // Messages the strategy wouldn't push aren't handed out either.
+                let forwarding = match forward(on_disk_tx, hash, server.context.forwarding).await {
+                    Some(forwarding) => forwarding,
+                    None => continue,
+                };
+                bytes += size;
+                messages.push((message, forwarding));
+            }
+            let mut list = results.get().init_messages(messages.len() as u32);
+            for (index, (message, forwarding)) in messages.iter().enumerate() {
+                let mut entry = list.reborrow().get(index as u32);
+                entry.set_payload(&message.payload);
+                entry.set_nonce(message.nonce);
+                entry.set_expiration_time(message.expiration_time);
+                forwarding::write(*forwarding, entry);
+            }
+            server.connection.record_messages_sent(messages.len());
+            Ok(())
+        })
+    }
+
+    fn reconcile_ranges(
+        &mut self,
+        params: Reconcile::ReconcileRangesParams,
+        mut results: Reconcile::ReconcileRangesResults,
+    ) -> Promise<(), Error> {
+        let server = self.clone();
+        Promise::from_future(async move {
+            let list = params.get()?.get_ranges()?;
+            if list.len() as usize > MAX_RANGES {
+                server.penalize(Offense::MalformedMessage).await?;
+                return Err(Error::failed("Too many ranges".to_string()));
+            }
+            let mut ranges = Vec::new();
+            for range in list.iter() {
+                let fingerprint = range.get_fingerprint()?;
+                if fingerprint.len() != FINGERPRINT_BYTES {
+                    server.penalize(Offense::MalformedMessage).await?;
+                    return Err(Error::failed(
+                        "Fingerprint has the wrong length".to_string(),
+                    ));
+                }
+                let mut summary = Summary {
+                    fingerprint: [0u8; FINGERPRINT_BYTES],
+                    count: range.get_count(),
+                };
+                summary.fingerprint.copy_from_slice(fingerprint);
+                ranges.push((
+                    Range {
+                        lower: range.get_lower()?.to_vec(),
+                        upper: range.get_upper()?.to_vec(),
                     },
-                )
-                .await;
-                let cloned = reconciliation_intent.clone();
-                cloned.read().await.broadcast();
+                    summary,
+                ));
+            }
+            let responses = respond_to_ranges(&server.context.in_memory_tx, ranges).await;
+            let mut builder = results.get().init_responses(responses.len() as u32);
+            for (index, response) in responses.iter().enumerate() {
+                let mut entry = builder.reborrow().get(index as u32);
+                match response {
+                    Response::Identical => entry.set_identical(()),
+                    Response::Hashes(hashes) => {
+                        let mut list = entry.init_hashes(hashes.len() as u32);
+                        for (index, hash) in hashes.iter().enumerate() {
+                            list.set(index as u32, hash);
+                        }
+                    }
+                    Response::Mismatch => entry.set_mismatch(()),
+                }
             }
             Ok(())
         })
     }
 }
 
-pub async fn init_server<T: Read + Write + 'static>(
+pub fn new_client(
+    context: Context,
+    peer: Rc<String>,
+    disconnect: Rc<LocalManualResetEvent>,
+    connection: Rc<Connection>,
+) -> Reconcile::Client {
+    capnp_rpc::new_client(ReconcileRPCServer {
+        context,
+        peer,
+        disconnect,
+        connection,
+    })
+}
+
+pub async fn init_server<T: Read + Write + Unpin + 'static>(
     stream: T,
-    in_memory_tx: Sender<InMemory>,
-    on_disk_tx: Sender<OnDisk>,
-    reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
-) -> Result<(), capnp::Error> {
-    let reconcile: Reconcile::Client = capnp_rpc::new_client(ReconcileRPCServer::new(
-        in_memory_tx,
-        on_disk_tx,
-        reconciliation_intent,
-    ));
-    let (reader, writer) = stream.split();
-    let network = twoparty::VatNetwork::new(
-        reader,
-        writer,
-        rpc_twoparty_capnp::Side::Server,
-        Default::default(),
-    );
-    let rpc_system = RpcSystem::new(Box::new(network), Some(reconcile.clone().client));
-    rpc_system.await
+    link: Link,
+    context: Context,
+) -> Result<(), SessionError> {
+    session::run(stream, Side::Server, link, context).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection_registry::Role;
+
+    fn server() -> (Reconcile::Client, Context) {
+        let context = crate::simulation::Node::new().context;
+        let link = Link {
+            id: None,
+            peer: "peer".to_string(),
+            remote_static_key: None,
+            address: "peer".to_string(),
+            inbound: true,
+        };
+        let (_, connection) = context
+            .connections
+            .try_write()
+            .unwrap()
+            .register(&link, Role::Server, 0)
+            .unwrap();
+        let client = new_client(
+            context.clone(),
+            Rc::new(link.peer),
+            Rc::new(LocalManualResetEvent::new(false)),
+            connection,
+        );
+        (client, context)
+    }
+
+    #[test]
+    fn oversized_test_batches_are_refused() {
+        let (client, _) = server();
+        let test = |count: usize| {
+            let mut request = client.test_many_request();
+            let mut list = request.get().init_hashes(count as u32);
+            for index in 0..count {
+                list.set(index as u32, &[index as u8; 64]);
+            }
+            request.send().promise
+        };
+        futures::executor::block_on(async {
+            let response = test(reconcile_client::BATCH_SIZE).await.unwrap();
+            assert_eq!(
+                response.get().unwrap().get_exists().unwrap().len() as usize,
+                reconcile_client::BATCH_SIZE
+            );
+            assert!(test(reconcile_client::BATCH_SIZE + 1).await.is_err());
+        });
+    }
+
+    #[test]
+    fn oversized_range_requests_are_refused() {
+        let (client, _) = server();
+        let reconcile = |count: usize| {
+            let mut request = client.reconcile_ranges_request();
+            let mut list = request.get().init_ranges(count as u32);
+            for index in 0..count {
+                let mut entry = list.reborrow().get(index as u32);
+                entry.set_lower(&(index as u16).to_be_bytes());
+                entry.set_upper(&(index as u16 + 1).to_be_bytes());
+                entry.set_fingerprint(&[0; FINGERPRINT_BYTES]);
+            }
+            request.send().promise
+        };
+        futures::executor::block_on(async {
+            let response = reconcile(MAX_RANGES).await.unwrap();
+            assert_eq!(
+                response.get().unwrap().get_responses().unwrap().len() as usize,
+                MAX_RANGES
+            );
+            assert!(reconcile(MAX_RANGES + 1).await.is_err());
+        });
+    }
 }
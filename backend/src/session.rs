@@ -0,0 +1,276 @@
+use crate::acceptance_policy::AcceptancePolicy;
+use crate::allowlist::Allowlist;
+use crate::compression::{Compression, Statistics};
+use crate::connection_registry::{ConnectionRegistry, Link, Metered, RegistryError, Role};
+use crate::forwarding::Strategy;
+use crate::handshake::{handshake, Hello, Rejection};
+use crate::inventory::{InMemory, OnDisk};
+use crate::log;
+use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
+use crate::noise::{Keypair, NoisePolicy};
+use crate::peer_exchange;
+use crate::peer_reputation::PeerReputation;
+use crate::pull::PullPolicy;
+use crate::push_order::PushOrder;
+use crate::reconcile_capnp::reconcile as Reconcile;
+use crate::reconcile_client;
+use crate::reconcile_server;
+use async_std::io::{Read, Write};
+use async_std::sync::{RwLock, Sender};
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use capnp_rpc::{twoparty, RpcSystem};
+use chrono::Utc;
+use futures::future::{join, select, Either};
+use futures::AsyncReadExt;
+use futures_intrusive::sync::LocalManualResetEvent;
+use std::cell::Cell;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::rc::Rc;
+
+#[derive(Clone)]
+pub struct Context {
+    pub in_memory_tx: Sender<InMemory>,
+    pub on_disk_tx: Sender<OnDisk>,
+    pub reconciliation_intent: Rc<RwLock<MPMCManualResetEvent>>,
+    pub peer_reputation: Rc<RwLock<PeerReputation>>,
+    pub acceptance_policy: AcceptancePolicy,
+    pub node_id: Rc<Vec<u8>>,
+    pub push_order: PushOrder,
+    pub noise_policy: Rc<Cell<NoisePolicy>>,
+    pub noise_keypair: Rc<Keypair>,
+    pub allowlist: Rc<RwLock<Allowlist>>,
+    pub compression: Compression,
+    pub compression_statistics: Rc<Statistics>,
+    pub connections: Rc<RwLock<ConnectionRegistry>>,
+    pub socks5_proxy: Option<String>,
+    pub listen_address: Option<SocketAddr>,
+    pub pull_policy: Option<Rc<PullPolicy>>,
+    pub forwarding: Strategy,
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Rpc(capnp::Error),
+    Rejected(Rejection),
+    Banned,
+    TooManySessions,
+    Duplicate,
+    Disconnected,
+}
+
+impl From<capnp::Error> for SessionError {
+    fn from(error: capnp::Error) -> SessionError {
+        SessionError::Rpc(error)
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Rpc(error) => write!(f, "{}", error.description),
+            SessionError::Rejected(rejection) => write!(f, "{}", rejection),
+            SessionError::Banned => write!(f, "Peer is banned for misbehaving"),
+            SessionError::TooManySessions => write!(f, "Too many simultaneous sessions"),
+            SessionError::Duplicate => write!(f, "Already connected to the peer"),
+            SessionError::Disconnected => write!(f, "Disconnected on request"),
+        }
+    }
+}
+
+/// Both ends push their inventory over the same connection. Peers that predate
+/// symmetric sessions don't expose a bootstrap interface on the client side, so
+/// pushing to them fails while the receiving half keeps running.
+pub async fn run<T: Read + Write + Unpin + 'static>(
+    stream: T,
+    side: Side,
+    link: Link,
+    context: Context,
+) -> Result<(), SessionError> {
+    if context
+        .peer_reputation
+        .read()
+        .await
+        .is_banned(&link.peer, Utc::now().timestamp())
+    {
+        return Err(SessionError::Banned);
+    }
+
+    let role = match side {
+        Side::Client => Role::Client,
+        Side::Server => Role::Server,
+    };
+    let (key, connection) =
+        match context
+            .connections
+            .write()
+            .await
+            .register(&link, role, Utc::now().timestamp())
+        {
+            Ok(registration) => registration,
+            Err(RegistryError::TooManySessions) => return Err(SessionError::TooManySessions),
+            Err(RegistryError::Duplicate) => return Err(SessionError::Duplicate),
+        };
+
+    // Only peers reached directly over TCP have an IP address others can
+    // dial.
+    let remote_ip = link.peer.parse::<IpAddr>().ok();
+    // Node IDs are claimed by the peer, so watermarks are only kept for peers
+    // authenticated by Noise.
+    let watermark_key = link
+        .remote_static_key
+        .clone()
+        .filter(|remote_static_key| *remote_static_key != context.noise_keypair.public);
+    let (reader, writer) = Metered::new(stream, connection.clone()).split();
+    let network = twoparty::VatNetwork::new(reader, writer, side, Default::default());
+    let disconnect = Rc::new(LocalManualResetEvent::new(false));
+    let local = reconcile_server::new_client(
+        context.clone(),
+        Rc::new(link.peer),
+        disconnect.clone(),
+        connection.clone(),
+    );
+    let mut rpc_system = RpcSystem::new(Box::new(network), Some(local.client));
+    let remote: Reconcile::Client = rpc_system.bootstrap(match side {
+        Side::Client => Side::Server,
+        Side::Server => Side::Client,
+    });
+
+    let Context {
+        in_memory_tx,
+        on_disk_tx,
+        reconciliation_intent,
+        acceptance_policy,
+        node_id,
+        push_order,
+        connections,
+        pull_policy,
+        forwarding,
+        ..
+    } = context;
+    let handle = reconciliation_intent.write().await.get_handle();
+    let event = reconciliation_intent.read().await.get_event(handle);
+
+    let push = {
+        let connections = connections.clone();
+        let connection = connection.clone();
+        let reconciliation_intent = reconciliation_intent.clone();
+        async move {
+            let local = Hello::local(&acceptance_policy, &node_id, pull_policy.is_some());
+            let hello = match handshake(&remote, &local).await {
+                Ok(hello) => hello,
+                Err(SessionError::Rpc(error)) => {
+                    log::warning(format!(
+                        "Stopped pushing messages to the peer due to error {:?}",
+                        error
+                    ));
+                    return futures::future::pending::<SessionError>().await;
+                }
+                Err(error) => return error,
+            };
+            if !hello.node_id.is_empty()
+                && hello.node_id != *node_id
+                && connections
+                    .write()
+                    .await
+                    .identify(key, &node_id, hello.node_id.clone())
+                    .is_err()
+            {
+                return SessionError::Duplicate;
+            }
+            let features = local.features.intersect(&hello.features);
+            if features.peer_exchange {
+                if let Err(error) = peer_exchange::exchange(&remote, remote_ip, &on_disk_tx).await {
+                    log::warning(format!("Peer exchange failed due to error {:?}", error));
+                }
+            }
+            if pull_policy.is_some() && !features.pull {
+                log::notice("The peer can't be pulled from, so it pushes messages instead");
+            }
+            let can_pull = features.pull;
+            // Peers that pull fetch what they want on their own.
+            let remote_pulls = features.pull && hello.pull_only;
+
+            let pushing = {
+                let remote = remote.clone();
+                let on_disk_tx = on_disk_tx.clone();
+                let in_memory_tx = in_memory_tx.clone();
+                let connection = connection.clone();
+                async move {
+                    if remote_pulls {
+                        return;
+                    }
+                    if let Err(error) = reconcile_client::push(
+                        remote,
+                        features,
+                        hello.acceptance_policy(),
+                        forwarding,
+                        watermark_key,
+                        push_order,
+                        in_memory_tx,
+                        on_disk_tx,
+                        event,
+                        connection,
+                    )
+                    .await
+                    {
+                        log::warning(format!(
+                            "Stopped pushing messages to the peer due to error {:?}",
+                            error
+                        ));
+                    }
+                }
+            };
+            let pulling = async move {
+                let pull_policy = match pull_policy {
+                    Some(pull_policy) if can_pull => pull_policy,
+                    _ => return,
+                };
+                if let Err(error) = reconcile_client::pull(
+                    remote,
+                    pull_policy,
+                    acceptance_policy,
+                    in_memory_tx,
+                    on_disk_tx,
+                    reconciliation_intent,
+                    connection,
+                )
+                .await
+                {
+                    log::warning(format!(
+                        "Stopped pulling messages from the peer due to error {:?}",
+                        error
+                    ));
+                }
+            };
+            join(pushing, pulling).await;
+            // The session lasts as long as the connection does.
+            futures::future::pending::<SessionError>().await
+        }
+    };
+    let terminate = Box::pin(async {
+        let closed = select(
+            Box::pin(disconnect.wait()),
+            Box::pin(connection.disconnect.wait()),
+        );
+        match select(Box::pin(push), closed).await {
+            Either::Left((error, _)) => error,
+            Either::Right((Either::Left(_), _)) => SessionError::Banned,
+            Either::Right((Either::Right(_), _)) => {
+                if connection.duplicate.get() {
+                    SessionError::Duplicate
+                } else {
+                    SessionError::Disconnected
+                }
+            }
+        }
+    });
+
+    let result = match select(Box::pin(rpc_system), terminate).await {
+        Either::Left((result, _)) => result.map_err(SessionError::Rpc),
+        Either::Right((error, _)) => Err(error),
+    };
+    reconciliation_intent.write().await.drop_handle(handle);
+    connections.write().await.unregister(key);
+    result
+}
@@ -0,0 +1,312 @@
+//! Range-based set reconciliation. A range of the hash space is summarized by
+//! the XOR of every hash in it, so that ranges both sides agree on are skipped.
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::sync::Arc;
+
+pub const FINGERPRINT_BYTES: usize = 64;
+
+/// Ranges in which the responder has at most this many hashes are answered
+/// with the hashes themselves.
+pub const HASH_LIST_THRESHOLD: u64 = 32;
+
+/// The remaining hashes are tested one by one instead.
+pub const TEST_THRESHOLD: usize = 8;
+
+pub const SPLIT_FACTOR: usize = 16;
+
+/// Larger rounds take several requests.
+pub const MAX_RANGES: usize = 256;
+
+/// Empty bounds stand for the ends of the hash space.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Range {
+    pub lower: Vec<u8>,
+    pub upper: Vec<u8>,
+}
+
+impl Range {
+    pub fn full() -> Range {
+        Range {
+            lower: Vec::new(),
+            upper: Vec::new(),
+        }
+    }
+
+    /// Inverted ranges would make `BTreeSet::range` panic.
+    pub fn is_valid(&self) -> bool {
+        self.upper.is_empty() || self.lower < self.upper
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub fingerprint: [u8; FINGERPRINT_BYTES],
+    pub count: u64,
+}
+
+#[derive(Debug)]
+pub enum Response {
+    Identical,
+    Hashes(Vec<Arc<Vec<u8>>>),
+    Mismatch,
+}
+
+pub fn summarize<'a, I: Iterator<Item = &'a Arc<Vec<u8>>>>(hashes: I) -> Summary {
+    let mut fingerprint = [0u8; FINGERPRINT_BYTES];
+    let mut count = 0u64;
+    for hash in hashes {
+        for (accumulated, byte) in fingerprint.iter_mut().zip(hash.iter()) {
+            *accumulated ^= byte;
+        }
+        count += 1;
+    }
+    Summary { fingerprint, count }
+}
+
+/// The range must be valid.
+pub fn hashes_in_range<'a, V>(
+    map: &'a BTreeMap<Arc<Vec<u8>>, V>,
+    range: &Range,
+) -> impl Iterator<Item = &'a Arc<Vec<u8>>> {
+    let upper = if range.upper.is_empty() {
+        Unbounded
+    } else {
+        Excluded(&range.upper)
+    };
+    map.range::<Vec<u8>, _>((Included(&range.lower), upper))
+        .map(|(hash, _)| hash)
+}
+
+pub fn respond<V>(map: &BTreeMap<Arc<Vec<u8>>, V>, range: &Range, remote: &Summary) -> Response {
+    if !range.is_valid() {
+        return Response::Hashes(Vec::new());
+    }
+    let local = summarize(hashes_in_range(map, range));
+    if &local == remote {
+        return Response::Identical;
+    }
+    if local.count <= HASH_LIST_THRESHOLD {
+        return Response::Hashes(hashes_in_range(map, range).cloned().collect());
+    }
+    Response::Mismatch
+}
+
+/// Only learns which of its own hashes the responder lacks, which is all a
+/// pushing client needs.
+pub struct Reconciler {
+    local: Vec<Arc<Vec<u8>>>,
+    pending: Vec<Range>,
+    missing_on_remote: Vec<Arc<Vec<u8>>>,
+    untested: Vec<Arc<Vec<u8>>>,
+}
+
+impl Reconciler {
+    pub fn new(mut local: Vec<Arc<Vec<u8>>>) -> Reconciler {
+        local.sort();
+        local.dedup();
+        let pending = if local.is_empty() {
+            Vec::new()
+        } else {
+            vec![Range::full()]
+        };
+        Reconciler {
+            local,
+            pending,
+            missing_on_remote: Vec::new(),
+            untested: Vec::new(),
+        }
+    }
+
+    fn lower_bound(&self, bound: &[u8]) -> usize {
+        match self.local.binary_search_by(|hash| hash[..].cmp(bound)) {
+            Ok(index) => index,
+            Err(index) => index,
+        }
+    }
+
+    fn local_in_range(&self, range: &Range) -> &[Arc<Vec<u8>>] {
+        let start = self.lower_bound(&range.lower);
+        let end = if range.upper.is_empty() {
+            self.local.len()
+        } else {
+            self.lower_bound(&range.upper)
+        };
+        &self.local[start..end]
+    }
+
+    /// Every query must be answered through `process` before the next call.
+    pub fn queries(&mut self) -> Vec<(Range, Summary)> {
+        let pending = std::mem::take(&mut self.pending);
+        pending
+            .into_iter()
+            .map(|range| {
+                let summary = summarize(self.local_in_range(&range).iter());
+                (range, summary)
+            })
+            .filter(|(_, summary)| summary.count > 0)
+            .collect()
+    }
+
+    pub fn process(&mut self, range: Range, response: Response) {
+        let local = self.local_in_range(&range).to_vec();
+        match response {
+            Response::Identical => {}
+            Response::Hashes(remote) => {
+                let remote: BTreeSet<_> = remote.into_iter().collect();
+                self.missing_on_remote
+                    .extend(local.into_iter().filter(|hash| !remote.contains(hash)));
+            }
+            Response::Mismatch => {
+                if local.len() <= TEST_THRESHOLD {
+                    self.untested.extend(local);
+                    return;
+                }
+                let chunk_size = (local.len() + SPLIT_FACTOR - 1) / SPLIT_FACTOR;
+                let mut lower = range.lower;
+                for chunk in local.chunks(chunk_size).skip(1) {
+                    let upper = chunk[0].to_vec();
+                    self.pending.push(Range {
+                        lower: std::mem::replace(&mut lower, upper.clone()),
+                        upper,
+                    });
+                }
+                self.pending.push(Range {
+                    lower,
+                    upper: range.upper,
+                });
+            }
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn take_missing_on_remote(&mut self) -> Vec<Arc<Vec<u8>>> {
+        std::mem::take(&mut self.missing_on_remote)
+    }
+
+    pub fn take_untested(&mut self) -> Vec<Arc<Vec<u8>>> {
+        std::mem::take(&mut self.untested)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    fn random_hashes(rng: &mut rand::rngs::StdRng, count: usize) -> Vec<Arc<Vec<u8>>> {
+        (0..count)
+            .map(|_| {
+                let mut hash = vec![0u8; FINGERPRINT_BYTES];
+                rng.fill(&mut hash as &mut [u8]);
+                Arc::new(hash)
+            })
+            .collect()
+    }
+
+    fn run(
+        initiator: Vec<Arc<Vec<u8>>>,
+        responder: &BTreeMap<Arc<Vec<u8>>, ()>,
+    ) -> (Vec<Arc<Vec<u8>>>, usize) {
+        let mut reconciler = Reconciler::new(initiator);
+        let mut missing = Vec::new();
+        let mut round_trips = 0;
+        while !reconciler.is_done() {
+            let queries = reconciler.queries();
+            if queries.is_empty() {
+                break;
+            }
+            round_trips += 1;
+            for (range, summary) in queries {
+                let response = respond(responder, &range, &summary);
+                reconciler.process(range, response);
+            }
+            missing.extend(reconciler.take_missing_on_remote());
+            missing.extend(
+                reconciler
+                    .take_untested()
+                    .into_iter()
+                    .filter(|hash| !responder.contains_key(hash)),
+            );
+        }
+        missing.sort();
+        (missing, round_trips)
+    }
+
+    fn check(shared: usize, only_initiator: usize, only_responder: usize) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(
+            (shared * 31 + only_initiator * 7 + only_responder) as u64,
+        );
+        let shared = random_hashes(&mut rng, shared);
+        let mut only_initiator = random_hashes(&mut rng, only_initiator);
+        let only_responder = random_hashes(&mut rng, only_responder);
+
+        let initiator: Vec<_> = shared
+            .iter()
+            .chain(only_initiator.iter())
+            .cloned()
+            .collect();
+        let responder: BTreeMap<_, _> = shared
+            .iter()
+            .chain(only_responder.iter())
+            .map(|hash| (hash.clone(), ()))
+            .collect();
+
+        let (missing, round_trips) = run(initiator, &responder);
+        only_initiator.sort();
+        assert_eq!(missing, only_initiator);
+        assert!(round_trips <= 8, "took {} round trips", round_trips);
+    }
+
+    #[test]
+    fn identical_inventories() {
+        check(10000, 0, 0);
+    }
+
+    #[test]
+    fn initiator_is_larger() {
+        check(5000, 300, 0);
+    }
+
+    #[test]
+    fn responder_is_larger() {
+        check(5000, 3, 2000);
+    }
+
+    #[test]
+    fn disjoint_inventories() {
+        check(0, 700, 1200);
+    }
+
+    #[test]
+    fn empty_initiator() {
+        check(0, 0, 100);
+    }
+
+    #[test]
+    fn empty_responder() {
+        check(0, 50, 0);
+    }
+
+    #[test]
+    fn inverted_ranges_are_rejected() {
+        let map: BTreeMap<_, _> = vec![(Arc::new(vec![1u8; FINGERPRINT_BYTES]), ())]
+            .into_iter()
+            .collect();
+        let range = Range {
+            lower: vec![2u8; FINGERPRINT_BYTES],
+            upper: vec![1u8; FINGERPRINT_BYTES],
+        };
+        match respond(
+            &map,
+            &range,
+            &summarize(std::iter::empty::<&Arc<Vec<u8>>>()),
+        ) {
+            Response::Hashes(hashes) => assert!(hashes.is_empty()),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+}
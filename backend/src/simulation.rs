@@ -0,0 +1,558 @@
+//! In-memory networks of complete nodes for tests. Latency, loss and the
+//! network's retransmissions follow a simulated `Clock`, but timers inside the
+//! sessions still follow the real clock.
+use crate::acceptance_policy::AcceptancePolicy;
+use crate::allowlist::Allowlist;
+use crate::compression::Compression;
+use crate::connection_registry::{ConnectionRegistry, Link};
+use crate::derive_state::{derive, Command, Event};
+use crate::forwarding::Forwarding;
+use crate::fragmentation::{self, Config};
+use crate::inventory::{self, Message};
+use crate::message_hash::message_hash;
+use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
+use crate::noise::{self, NoisePolicy};
+use crate::peer_reputation::{PeerReputation, Thresholds};
+use crate::quota::Quota;
+use crate::reconcile_client;
+use crate::reconcile_server;
+use crate::session::Context;
+use async_std::sync::{channel, Receiver, RwLock, Sender};
+use async_std::task;
+use chrono::Utc;
+use futures::executor::{LocalPool, LocalSpawner};
+use futures::future::{self, Either};
+use futures::task::{LocalSpawnExt, Poll, Waker};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const QUEUE: usize = 1024;
+/// Messages published in simulations expire after this long. In seconds.
+const TIME_TO_LIVE: i64 = 60 * 60;
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Lets the inventory and derive threads, which don't run on the pool, answer
+/// before the simulated clock moves on.
+const IDLE: Duration = Duration::from_millis(1);
+
+thread_local! {
+    /// Simulated networks can't afford seconds of work per message.
+    static PROOF_OF_WORK_STUBBED_OUT: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn stub_out_proof_of_work() {
+    PROOF_OF_WORK_STUBBED_OUT.with(|stubbed_out| stubbed_out.set(true));
+}
+
+pub fn proof_of_work_stubbed_out() -> bool {
+    PROOF_OF_WORK_STUBBED_OUT.with(|stubbed_out| stubbed_out.get())
+}
+
+/// Only moves while `run_until` runs, jumping to the next timer.
+#[derive(Clone, Default)]
+pub struct Clock(Rc<RefCell<Timers>>);
+
+#[derive(Default)]
+struct Timers {
+    now: Duration,
+    pending: Vec<(Duration, Waker)>,
+}
+
+impl Clock {
+    pub fn now(&self) -> Duration {
+        self.0.borrow().now
+    }
+
+    pub fn sleep_until(&self, deadline: Duration) -> impl Future<Output = ()> {
+        let clock = self.clone();
+        future::poll_fn(move |cx| {
+            let mut timers = clock.0.borrow_mut();
+            if timers.now >= deadline {
+                return Poll::Ready(());
+            }
+            timers.pending.push((deadline, cx.waker().clone()));
+            Poll::Pending
+        })
+    }
+
+    pub fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+        self.sleep_until(self.now() + duration)
+    }
+
+    fn advance(&self) {
+        let due = {
+            let mut timers = self.0.borrow_mut();
+            let next = match timers.pending.iter().map(|(deadline, _)| *deadline).min() {
+                Some(next) => next,
+                None => return,
+            };
+            timers.now = timers.now.max(next);
+            let now = timers.now;
+            let (due, pending) = timers
+                .pending
+                .drain(..)
+                .partition::<Vec<_>, _>(|(deadline, _)| *deadline <= now);
+            timers.pending = pending;
+            due
+        };
+        for (_, waker) in due {
+            waker.wake();
+        }
+    }
+
+    pub fn run_until<T>(&self, pool: &mut LocalPool, future: impl Future<Output = T>) -> T {
+        let mut future = Box::pin(future);
+        loop {
+            let output = pool.run_until(async {
+                match future::select(future.as_mut(), Box::pin(task::sleep(IDLE))).await {
+                    Either::Left((output, _)) => Some(output),
+                    Either::Right(_) => None,
+                }
+            });
+            match output {
+                Some(output) => return output,
+                None => self.advance(),
+            }
+        }
+    }
+}
+
+impl fragmentation::Timer for Clock {
+    type Sleep = Pin<Box<dyn Future<Output = ()>>>;
+
+    fn now(&self) -> Duration {
+        Clock::now(self)
+    }
+
+    fn sleep(&self, duration: Duration) -> Self::Sleep {
+        Box::pin(Clock::sleep(self, duration))
+    }
+}
+
+pub struct Node {
+    pub context: Context,
+    pub command_tx: Sender<Command>,
+    pub events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Node {
+    pub fn new() -> Node {
+        Node::with_quota(Quota::default())
+    }
+
+    pub fn with_quota(quota: Quota) -> Node {
+        Node::open(rusqlite::Connection::open_in_memory().unwrap(), quota)
+    }
+
+    pub fn open(connection: rusqlite::Connection, quota: Quota) -> Node {
+        let (in_memory_tx, in_memory_rx) = channel(1);
+        let (on_disk_tx, on_disk_rx) = channel(1);
+        let (mutate_tx, mutate_rx) = channel(1);
+        let (command_tx, command_rx) = channel(1);
+        let (event_tx, event_rx) = channel(1);
+        let acceptance_policy = AcceptancePolicy::default();
+        std::thread::spawn(move || {
+            crate::init_inventory::init_inventory(
+                connection,
+                mutate_tx,
+                in_memory_rx,
+                on_disk_rx,
+                acceptance_policy,
+                quota,
+            );
+        });
+        {
+            let in_memory_tx = in_memory_tx.clone();
+            let on_disk_tx = on_disk_tx.clone();
+            std::thread::spawn(move || {
+                task::block_on(derive(
+                    in_memory_tx,
+                    on_disk_tx,
+                    mutate_rx,
+                    command_rx,
+                    rusqlite::Connection::open_in_memory().unwrap(),
+                    event_tx,
+                ));
+            });
+        }
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let events = events.clone();
+            std::thread::spawn(move || {
+                task::block_on(async move {
+                    while let Ok(event) = event_rx.recv().await {
+                        events.lock().unwrap().push(event);
+                    }
+                });
+            });
+        }
+
+        let node_id = task::block_on(inventory::get_node_id(&on_disk_tx));
+        let noise_keypair = task::block_on(noise::load_keypair(&on_disk_tx));
+        Node {
+            context: Context {
+                in_memory_tx,
+                on_disk_tx,
+                reconciliation_intent: Rc::new(RwLock::new(MPMCManualResetEvent::new())),
+                peer_reputation: Rc::new(RwLock::new(PeerReputation::new(Thresholds::default()))),
+                acceptance_policy,
+                node_id: Rc::new(node_id),
+                push_order: Default::default(),
+                noise_policy: Rc::new(Cell::new(NoisePolicy::Enabled)),
+                noise_keypair: Rc::new(noise_keypair),
+                allowlist: Rc::new(RwLock::new(Allowlist::new(false, Vec::new()))),
+                compression: Compression::Zstd,
+                compression_statistics: Rc::new(Default::default()),
+                connections: Rc::new(RwLock::new(ConnectionRegistry::new(0))),
+                socks5_proxy: None,
+                listen_address: None,
+                pull_policy: None,
+                forwarding: Default::default(),
+            },
+            command_tx,
+            events,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Conditions {
+    pub latency: Duration,
+    pub loss: f64,
+}
+
+struct Random(u64);
+
+impl Random {
+    fn chance(&mut self, probability: f64) -> bool {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 as f64 / u64::MAX as f64) < probability
+    }
+}
+
+async fn relay(
+    from: Receiver<Vec<u8>>,
+    to: Sender<Vec<u8>>,
+    conditions: Conditions,
+    mut random: Random,
+    partitioned: Rc<Cell<bool>>,
+    clock: Clock,
+) {
+    let (delayed_tx, delayed_rx) = channel::<(Duration, Vec<u8>)>(QUEUE);
+    let deliver_clock = clock.clone();
+    let send = async move {
+        while let Ok(datagram) = from.recv().await {
+            if partitioned.get() || random.chance(conditions.loss) {
+                continue;
+            }
+            delayed_tx
+                .send((clock.now() + conditions.latency, datagram))
+                .await;
+        }
+    };
+    let deliver = async move {
+        while let Ok((deadline, datagram)) = delayed_rx.recv().await {
+            deliver_clock.sleep_until(deadline).await;
+            to.send(datagram).await;
+        }
+    };
+    futures::future::join(send, deliver).await;
+}
+
+struct SimulatedLink {
+    ends: (usize, usize),
+    partitioned: Rc<Cell<bool>>,
+}
+
+pub struct Network {
+    pool: LocalPool,
+    spawner: LocalSpawner,
+    pub nodes: Vec<Node>,
+    links: Vec<SimulatedLink>,
+    seed: u64,
+    clock: Clock,
+}
+
+impl Network {
+    /// Proofs of work aren't checked on the calling thread from now on.
+    pub fn new(size: usize, seed: u64) -> Network {
+        sodiumoxide::init().unwrap();
+        stub_out_proof_of_work();
+        let pool = LocalPool::new();
+        let spawner = pool.spawner();
+        Network {
+            pool,
+            spawner,
+            nodes: (0..size).map(|_| Node::new()).collect(),
+            links: Vec::new(),
+            seed,
+            clock: Clock::default(),
+        }
+    }
+
+    pub fn connect(&mut self, a: usize, b: usize, conditions: Conditions) {
+        let fragmentation = Config {
+            retransmission_timeout: Duration::from_millis(100) + conditions.latency * 2,
+            // Links outlive partitions, which only last until `heal`.
+            max_retransmissions: u32::MAX,
+            ..Config::default()
+        };
+        let (a_out_tx, a_out_rx) = channel(QUEUE);
+        let (a_in_tx, a_in_rx) = channel(QUEUE);
+        let (b_out_tx, b_out_rx) = channel(QUEUE);
+        let (b_in_tx, b_in_rx) = channel(QUEUE);
+        let (a_stream, a_driver) =
+            fragmentation::fragment(a_out_tx, a_in_rx, fragmentation, self.clock.clone());
+        let (b_stream, b_driver) =
+            fragmentation::fragment(b_out_tx, b_in_rx, fragmentation, self.clock.clone());
+
+        // Xorshift never leaves zero, so every seed is made odd.
+        let seed = (self.seed ^ (self.links.len() as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1;
+        let partitioned = Rc::new(Cell::new(false));
+        let spawner = &self.spawner;
+        spawner.spawn_local(a_driver).unwrap();
+        spawner.spawn_local(b_driver).unwrap();
+        spawner
+            .spawn_local(relay(
+                a_out_rx,
+                b_in_tx,
+                conditions,
+                Random(seed),
+                partitioned.clone(),
+                self.clock.clone(),
+            ))
+            .unwrap();
+        spawner
+            .spawn_local(relay(
+                b_out_rx,
+                a_in_tx,
+                conditions,
+                Random(seed.rotate_left(32) | 1),
+                partitioned.clone(),
+                self.clock.clone(),
+            ))
+            .unwrap();
+
+        let link = |node: usize, inbound: bool| Link {
+            id: None,
+            peer: format!("node-{}", node),
+            remote_static_key: None,
+            address: format!("node-{}", node),
+            inbound,
+        };
+        let client = self.nodes[a].context.clone();
+        let server = self.nodes[b].context.clone();
+        let (mut client_link, mut server_link) = (link(b, false), link(a, true));
+        spawner
+            .spawn_local(async move {
+                let stream = noise::dial_peer(a_stream, &client)
+                    .await
+                    .expect("Noise handshake failed");
+                client_link.remote_static_key = stream.remote_static_key().map(<[u8]>::to_vec);
+                let _ = reconcile_client::reconcile(stream, client_link, client).await;
+            })
+            .unwrap();
+        spawner
+            .spawn_local(async move {
+                let stream = noise::accept_peer(b_stream, &server)
+                    .await
+                    .expect("Noise handshake failed");
+                server_link.remote_static_key = stream.remote_static_key().map(<[u8]>::to_vec);
+                let _ = reconcile_server::init_server(stream, server_link, server).await;
+            })
+            .unwrap();
+
+        self.links.push(SimulatedLink {
+            ends: (a, b),
+            partitioned,
+        });
+    }
+
+    pub fn partition(&mut self, side: &[usize]) {
+        for link in &self.links {
+            let (a, b) = link.ends;
+            if side.contains(&a) != side.contains(&b) {
+                link.partitioned.set(true);
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        for link in &self.links {
+            link.partitioned.set(false);
+        }
+    }
+
+    pub fn run<T>(&mut self, future: impl Future<Output = T>) -> T {
+        self.clock.run_until(&mut self.pool, future)
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        let sleep = self.clock.sleep(duration);
+        self.run(sleep);
+    }
+
+    pub fn publish(&mut self, node: usize, payload: Vec<u8>) -> Arc<Vec<u8>> {
+        let expiration_time = Utc::now().timestamp() + TIME_TO_LIVE;
+        let hash = Arc::new(message_hash(&payload, expiration_time).to_vec());
+        let context = &self.nodes[node].context;
+        self.pool.run_until(async {
+            inventory::insert_message(
+                &context.on_disk_tx,
+                Message {
+                    payload,
+                    nonce: 0,
+                    expiration_time,
+                },
+            )
+            .await;
+            context.reconciliation_intent.read().await.broadcast();
+        });
+        hash
+    }
+
+    pub fn holds(&mut self, node: usize, hash: Arc<Vec<u8>>) -> bool {
+        let in_memory_tx = self.nodes[node].context.in_memory_tx.clone();
+        self.run(async move { inventory::message_exists(&in_memory_tx, hash).await })
+    }
+
+    pub fn forwarding(&mut self, node: usize, hash: Arc<Vec<u8>>) -> Option<Forwarding> {
+        let on_disk_tx = self.nodes[node].context.on_disk_tx.clone();
+        self.run(async move { inventory::get_forwarding(&on_disk_tx, hash).await })
+    }
+
+    pub fn converge(&mut self, timeout: Duration) -> bool {
+        let nodes = &self.nodes;
+        let clock = &self.clock;
+        let converged = async {
+            loop {
+                let mut inventories = Vec::new();
+                for node in nodes {
+                    let (hashes, _) = inventory::snapshot(&node.context.in_memory_tx).await;
+                    inventories.push(hashes.into_iter().collect::<HashSet<_>>());
+                }
+                if inventories.windows(2).all(|pair| pair[0] == pair[1]) {
+                    return;
+                }
+                clock.sleep(POLL_INTERVAL).await;
+            }
+        };
+        clock.run_until(&mut self.pool, async {
+            futures::pin_mut!(converged);
+            let expired = clock.sleep(timeout);
+            futures::pin_mut!(expired);
+            match future::select(converged, expired).await {
+                Either::Left(_) => true,
+                Either::Right(_) => false,
+            }
+        })
+    }
+
+    pub fn await_event(
+        &mut self,
+        node: usize,
+        timeout: Duration,
+        matches: impl Fn(&Event) -> bool,
+    ) -> bool {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            if self.nodes[node].events.lock().unwrap().iter().any(&matches) {
+                return true;
+            }
+            if self.clock.now() >= deadline {
+                return false;
+            }
+            self.run_for(POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::derive_state::{encode_message, new_inbox, RichTextFormat};
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    fn lossy() -> Conditions {
+        Conditions {
+            latency: Duration::from_millis(20),
+            loss: 0.1,
+        }
+    }
+
+    #[test]
+    fn messages_spread_along_a_lossy_line() {
+        let mut network = Network::new(4, 1);
+        for node in 0..3 {
+            network.connect(node, node + 1, lossy());
+        }
+        let first = network.publish(0, b"from the first node".to_vec());
+        let last = network.publish(3, b"from the last node".to_vec());
+        assert!(network.converge(TIMEOUT));
+        for node in 0..4 {
+            assert!(network.holds(node, first.clone()));
+            assert!(network.holds(node, last.clone()));
+        }
+    }
+
+    #[test]
+    fn partitioned_nodes_catch_up_after_healing() {
+        let mut network = Network::new(4, 2);
+        for node in 0..4 {
+            network.connect(node, (node + 1) % 4, Conditions::default());
+        }
+        network.run_for(Duration::from_secs(1));
+        network.partition(&[0, 1]);
+        let left = network.publish(0, b"left".to_vec());
+        let right = network.publish(2, b"right".to_vec());
+        network.run_for(Duration::from_secs(2));
+        assert!(network.holds(1, left.clone()));
+        assert!(!network.holds(2, left.clone()));
+        assert!(!network.holds(1, right.clone()));
+
+        network.heal();
+        assert!(network.converge(TIMEOUT));
+        assert!(network.holds(2, left));
+        assert!(network.holds(1, right));
+    }
+
+    #[test]
+    fn messages_reach_the_inbox_they_are_sealed_to() {
+        let mut network = Network::new(3, 3);
+        network.connect(0, 1, lossy());
+        network.connect(1, 2, lossy());
+
+        let (recipient_tx, sender_tx) = (
+            network.nodes[0].command_tx.clone(),
+            network.nodes[2].command_tx.clone(),
+        );
+        let (recipient_inbox, recipient) =
+            network.run(new_inbox(&recipient_tx, "Recipient".to_string()));
+        let (sender_inbox, _) = network.run(new_inbox(&sender_tx, "Sender".to_string()));
+        let payload = network.run(encode_message(
+            &sender_tx,
+            None,
+            Vec::new(),
+            RichTextFormat::Plaintext,
+            "Hello across the network".to_string(),
+            Vec::new(),
+            vec![recipient.public_encryption_key],
+            sender_inbox,
+        ));
+        network.publish(2, payload);
+
+        assert!(network.await_event(0, TIMEOUT, |event| match event {
+            Event::Message {
+                message, inbox_id, ..
+            } => *inbox_id == recipient_inbox && message.content == "Hello across the network",
+            _ => false,
+        }));
+    }
+}
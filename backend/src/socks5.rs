@@ -0,0 +1,207 @@
+//! Host names are sent to the proxy unresolved, so lookups don't leak outside
+//! the proxy.
+use async_std::io::{self, Read, Write};
+use async_std::prelude::*;
+use std::net::IpAddr;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+fn error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, message)
+}
+
+/// IPv6 hosts are enclosed in brackets.
+pub fn split_host_port(address: &str) -> io::Result<(String, u16)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} isn't a valid host and port", address),
+        )
+    };
+    let separator = address.rfind(':').ok_or_else(invalid)?;
+    let port = address[separator + 1..].parse().map_err(|_| invalid())?;
+    let host = &address[..separator];
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..host.len() - 1]
+    } else {
+        host
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port))
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "SOCKS5 proxy reported a general failure",
+        2 => "SOCKS5 proxy doesn't allow the connection",
+        3 => "SOCKS5 proxy reported that the network is unreachable",
+        4 => "SOCKS5 proxy reported that the host is unreachable",
+        5 => "SOCKS5 proxy reported that the connection was refused",
+        6 => "SOCKS5 proxy reported that the TTL expired",
+        7 => "SOCKS5 proxy doesn't support the CONNECT command",
+        8 => "SOCKS5 proxy doesn't support the address type",
+        _ => "SOCKS5 proxy replied with an unknown error",
+    }
+}
+
+pub async fn connect<T: Read + Write + Unpin>(
+    stream: &mut T,
+    host: &str,
+    port: u16,
+) -> io::Result<()> {
+    stream.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != VERSION {
+        return Err(error("Proxy doesn't speak SOCKS5"));
+    }
+    match choice[1] {
+        NO_AUTHENTICATION => {}
+        NO_ACCEPTABLE_METHODS => return Err(error("SOCKS5 proxy requires authentication")),
+        _ => return Err(error("SOCKS5 proxy chose an unsupported method")),
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(address)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&address.octets());
+        }
+        Ok(IpAddr::V6(address)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&address.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Host name is too long for SOCKS5",
+                ));
+            }
+            request.push(DOMAIN_NAME);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(error("Proxy doesn't speak SOCKS5"));
+    }
+    if reply[1] != 0 {
+        return Err(error(reply_message(reply[1])));
+    }
+    // The address the proxy bound to isn't needed, but it has to be read so
+    // that it isn't mistaken for the peer's data.
+    let address_length = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => {
+            let mut length = [0u8; 1];
+            stream.read_exact(&mut length).await?;
+            length[0] as usize
+        }
+        _ => return Err(error("SOCKS5 proxy replied with an unknown address type")),
+    };
+    let mut bound_address = vec![0u8; address_length + 2];
+    stream.read_exact(&mut bound_address).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task;
+
+    async fn proxy(listener: TcpListener) {
+        let (mut client, _) = listener.accept().await.unwrap();
+        let mut greeting = [0u8; 2];
+        client.read_exact(&mut greeting).await.unwrap();
+        let mut methods = vec![0u8; greeting[1] as usize];
+        client.read_exact(&mut methods).await.unwrap();
+        assert!(methods.contains(&NO_AUTHENTICATION));
+        client
+            .write_all(&[VERSION, NO_AUTHENTICATION])
+            .await
+            .unwrap();
+
+        let mut request = [0u8; 4];
+        client.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..3], [VERSION, CONNECT, 0]);
+        assert_eq!(request[3], DOMAIN_NAME);
+        let mut length = [0u8; 1];
+        client.read_exact(&mut length).await.unwrap();
+        let mut host = vec![0u8; length[0] as usize];
+        client.read_exact(&mut host).await.unwrap();
+        let mut port = [0u8; 2];
+        client.read_exact(&mut port).await.unwrap();
+        let host = String::from_utf8(host).unwrap();
+        let port = u16::from_be_bytes(port);
+
+        let target = TcpStream::connect((host.as_str(), port)).await.unwrap();
+        client
+            .write_all(&[VERSION, 0, 0, IPV4, 127, 0, 0, 1, 0, 0])
+            .await
+            .unwrap();
+
+        let (client_reader, client_writer) = &mut (&client, &client);
+        let (target_reader, target_writer) = &mut (&target, &target);
+        let _ = futures::future::join(
+            io::copy(client_reader, target_writer),
+            io::copy(target_reader, client_writer),
+        )
+        .await;
+    }
+
+    #[test]
+    fn host_names_are_resolved_by_the_proxy() {
+        task::block_on(async {
+            let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target_port = target.local_addr().unwrap().port();
+            let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_address = proxy_listener.local_addr().unwrap();
+            task::spawn(proxy(proxy_listener));
+            task::spawn(async move {
+                let (mut socket, _) = target.accept().await.unwrap();
+                let mut buffer = [0u8; 5];
+                socket.read_exact(&mut buffer).await.unwrap();
+                socket.write_all(&buffer).await.unwrap();
+            });
+
+            let mut stream = TcpStream::connect(proxy_address).await.unwrap();
+            connect(&mut stream, "localhost", target_port)
+                .await
+                .unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            let mut buffer = [0u8; 5];
+            stream.read_exact(&mut buffer).await.unwrap();
+            assert_eq!(&buffer, b"hello");
+        });
+    }
+
+    #[test]
+    fn addresses_are_split() {
+        assert_eq!(
+            split_host_port("example.onion:8080").unwrap(),
+            ("example.onion".to_string(), 8080)
+        );
+        assert_eq!(
+            split_host_port("[::1]:8080").unwrap(),
+            ("::1".to_string(), 8080)
+        );
+        assert!(split_host_port("example.onion").is_err());
+        assert!(split_host_port(":8080").is_err());
+    }
+}
@@ -1,14 +1,27 @@
-use crate::connect::{connect, reverse_connect};
+use crate::acceptance_policy::Violation;
+use crate::bundle::{self, ImportSummary};
+use crate::compression::StatisticsSnapshot;
+use crate::connect::{connect, connect_persistently, reverse_connect, ConnectionState, Direction};
+use crate::connection_registry::ConnectionInfo;
 use crate::derive_state::Command;
-use crate::inventory::{get_message, get_one_after_counter, insert_message, InMemory, OnDisk};
+use crate::inventory::{
+    add_allowed_peer, get_known_peers, get_message, get_one_after_counter, insert_message,
+    prune_known_peers, remove_allowed_peer, Insertion,
+};
 use crate::log;
-use crate::mpmc_manual_reset_event::MPMCManualResetEvent;
+use crate::noise::NoisePolicy;
+use crate::peer_exchange::KnownPeer;
+use crate::peer_reputation::PeerReputationEntry;
+use crate::push_order::PushOrder;
+use crate::session::Context;
 use crate::state_derive_ipc::attempt_parse;
 use async_std::io;
 use async_std::sync::{RwLock, Sender};
 use futures::executor::LocalSpawner;
 use futures::task::LocalSpawn;
+use futures_intrusive::sync::LocalManualResetEvent;
 use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::process::exit;
 use std::rc::Rc;
@@ -35,12 +48,64 @@ enum Operation {
     EstablishConnection {
         address: String,
         operation_id: String,
+        #[serde(default)]
+        push_order: Option<PushOrder>,
+        #[serde(default)]
+        noise: Option<NoisePolicy>,
+        /// An empty string connects directly.
+        #[serde(default)]
+        socks5_proxy: Option<String>,
+        #[serde(default)]
+        persistent: bool,
     },
+    /// Kept for peers that predate bidirectional sessions.
     EstablishReverseConnection {
         address: String,
         operation_id: String,
+        #[serde(default)]
+        persistent: bool,
+    },
+    CancelConnection {
+        to_be_cancelled: String,
     },
     DumpPendingProofOfWorkOperations,
+    DumpPeerReputation,
+    ClearPeerBan {
+        peer: String,
+    },
+    /// Applies to connections established afterwards.
+    SetNoisePolicy {
+        policy: NoisePolicy,
+    },
+    /// Noise static public key.
+    AddAllowedPeer {
+        public_key: Vec<u8>,
+    },
+    RemoveAllowedPeer {
+        public_key: Vec<u8>,
+    },
+    ListAllowedPeers,
+    DumpCompressionStatistics,
+    ListConnections,
+    /// Outgoing connections are identified by the operation ID that established
+    /// them.
+    Disconnect {
+        operation_id: String,
+    },
+    ListKnownPeers,
+    PruneKnownPeers {
+        max_failures: u32,
+        max_age: i64,
+    },
+    /// Every message is exported when `since` is omitted.
+    ExportBundle {
+        path: String,
+        #[serde(default)]
+        since: u64,
+    },
+    ImportBundle {
+        path: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -62,15 +127,25 @@ pub enum Message<'a> {
     ProofOfWorkCompleted {
         in_reply_to: &'a str,
     },
+    SubmitRejected {
+        in_reply_to: &'a str,
+        reason: Violation,
+    },
     ConnectionEstablishmentFailure {
         in_reply_to: &'a str,
     },
     ReconcileFailure {
         in_reply_to: &'a str,
+        reason: &'a str,
     },
     ConnectionSevered {
         in_reply_to: &'a str,
     },
+    /// Only sent for persistent connections.
+    ConnectionStateChanged {
+        in_reply_to: &'a str,
+        state: ConnectionState,
+    },
     ServerListenAddress {
         address: &'a str,
     },
@@ -78,23 +153,118 @@ pub enum Message<'a> {
         address: &'a str,
     },
     PendingProofOfWorkOperations(Vec<ProofOfWorkOperation>),
+    PeerReputation(Vec<PeerReputationEntry>),
+    AllowedPeers {
+        own_public_key: &'a [u8],
+        private_network: bool,
+        allowed: Vec<Vec<u8>>,
+    },
+    CompressionStatistics(StatisticsSnapshot),
+    Connections(Vec<ConnectionInfo>),
+    /// Sent the first time a peer is heard from on the LAN.
+    PeerDiscovered {
+        node_id: &'a [u8],
+        /// `None` when the peer doesn't accept incoming connections.
+        address: Option<String>,
+        public_key: &'a [u8],
+    },
+    KnownPeers(Vec<KnownPeer>),
+    KnownPeersPruned {
+        deleted: usize,
+    },
+    BundleExported {
+        path: &'a str,
+        count: usize,
+        /// Pass this as `since` to only export newer messages next time.
+        watermark: u64,
+    },
+    BundleImported {
+        path: &'a str,
+        summary: ImportSummary,
+    },
+    BundleFailed {
+        path: &'a str,
+        reason: &'a str,
+    },
 }
 
 pub fn format_struct<T: Serialize>(value: &T) -> String {
     base64::encode(&serde_json::to_string(value).unwrap())
 }
 
+type PersistentConnections = Rc<RefCell<HashMap<String, Rc<LocalManualResetEvent>>>>;
+
+fn establish_persistent_connection(
+    address: String,
+    operation_id: String,
+    context: Context,
+    spawner: LocalSpawner,
+    direction: Direction,
+    persistent_connections: &PersistentConnections,
+) {
+    // Replacing the handle of a running loop would leave it uncancellable.
+    if persistent_connections.borrow().contains_key(&operation_id) {
+        log::warning(format!(
+            "Operation ID {} is already taken by a persistent connection",
+            operation_id
+        ));
+        log::ipc(format_struct(&Message::ConnectionEstablishmentFailure {
+            in_reply_to: &operation_id,
+        }));
+        return;
+    }
+    let cancel = Rc::new(LocalManualResetEvent::new(false));
+    persistent_connections
+        .borrow_mut()
+        .insert(operation_id.clone(), cancel.clone());
+    let persistent_connections = persistent_connections.clone();
+    connect_persistently(
+        address,
+        operation_id.clone(),
+        context,
+        spawner,
+        direction,
+        cancel.clone(),
+        move |state| {
+            if let ConnectionState::Cancelled = state {
+                let mut persistent_connections = persistent_connections.borrow_mut();
+                // The ID may have been taken again since the loop was cancelled.
+                if persistent_connections
+                    .get(&operation_id)
+                    .is_some_and(|entry| Rc::ptr_eq(entry, &cancel))
+                {
+                    persistent_connections.remove(&operation_id);
+                }
+            }
+            log::ipc(format_struct(&Message::ConnectionStateChanged {
+                in_reply_to: &operation_id,
+                state,
+            }));
+        },
+    );
+}
+
 pub async fn communicate(
-    reconciliation_intent: std::rc::Rc<RwLock<MPMCManualResetEvent>>,
-    in_memory_tx: Sender<InMemory>,
-    on_disk_tx: Sender<OnDisk>,
+    context: Context,
     command_tx: Option<Sender<Command>>,
     spawner: LocalSpawner,
     dump_inventory: bool,
 ) {
+    let Context {
+        in_memory_tx,
+        on_disk_tx,
+        reconciliation_intent,
+        peer_reputation,
+        acceptance_policy,
+        allowlist,
+        ..
+    } = context.clone();
+
     let atomic_cancel_flags: Rc<RwLock<HashMap<String, Arc<AtomicBool>>>> =
         Rc::new(RwLock::new(HashMap::new()));
 
+    let persistent_connections: PersistentConnections = Rc::new(RefCell::new(HashMap::new()));
+
     let associated_frontend_data_map: Rc<RwLock<HashMap<String, String>>> =
         Rc::new(RwLock::new(HashMap::new()));
 
@@ -161,6 +331,21 @@ pub async fn communicate(
                         operation_id,
                         associated_frontend_data,
                     } => {
+                        if let Err(violation) = acceptance_policy.check(
+                            payload.len(),
+                            expiration_time,
+                            chrono::Utc::now().timestamp(),
+                        ) {
+                            log::warning(format!(
+                                "Message violates the acceptance policy: {}",
+                                violation
+                            ));
+                            log::ipc(format_struct(&Message::SubmitRejected {
+                                in_reply_to: &operation_id,
+                                reason: violation,
+                            }));
+                            continue;
+                        }
                         log::notice(
                             "A task has been spawned to calculate the proof of work. Hang tight.",
                         );
@@ -218,16 +403,23 @@ pub async fn communicate(
                                             return;
                                         }
                                     };
-                                    insert_message(&on_disk_tx, crate::inventory::Message { payload, nonce, expiration_time }).await;
-                                    reconciliation_intent.read().await.broadcast();
-                                    log::ipc(format_struct(&Message::ProofOfWorkCompleted {
-                                        in_reply_to: &operation_id,
-                                    }));
+                                    let insertion = insert_message(&on_disk_tx, crate::inventory::Message { payload, nonce, expiration_time }).await;
                                     atomic_cancel_flags
                                         .write()
                                         .await
                                         .remove(&operation_id);
                                     associated_frontend_data_map.write().await.remove(&operation_id);
+                                    if insertion == Insertion::Refused {
+                                        log::ipc(format_struct(&Message::SubmitRejected {
+                                            in_reply_to: &operation_id,
+                                            reason: Violation::QuotaExceeded,
+                                        }));
+                                        return;
+                                    }
+                                    reconciliation_intent.read().await.broadcast();
+                                    log::ipc(format_struct(&Message::ProofOfWorkCompleted {
+                                        in_reply_to: &operation_id,
+                                    }));
                                     log::notice("Message submitted successfully");
                                 })
                                 .into(),
@@ -264,7 +456,36 @@ pub async fn communicate(
                     Operation::EstablishConnection {
                         address,
                         operation_id,
+                        push_order,
+                        noise,
+                        socks5_proxy,
+                        persistent,
                     } => {
+                        let mut context = context.clone();
+                        if let Some(push_order) = push_order {
+                            context.push_order = push_order;
+                        }
+                        if let Some(noise) = noise {
+                            context.noise_policy = Rc::new(Cell::new(noise));
+                        }
+                        if let Some(socks5_proxy) = socks5_proxy {
+                            context.socks5_proxy = if socks5_proxy.is_empty() {
+                                None
+                            } else {
+                                Some(socks5_proxy)
+                            };
+                        }
+                        if persistent {
+                            establish_persistent_connection(
+                                address,
+                                operation_id,
+                                context,
+                                spawner.clone(),
+                                Direction::Forward,
+                                &persistent_connections,
+                            );
+                            continue;
+                        }
                         let operation_id1 = std::rc::Rc::new(operation_id);
                         let operation_id2 = operation_id1.clone();
                         let operation_id3 = operation_id1.clone();
@@ -273,10 +494,9 @@ pub async fn communicate(
                         let socket_address3 = socket_address1.clone();
                         connect(
                             address,
-                            in_memory_tx.clone(),
-                            on_disk_tx.clone(),
+                            operation_id1.to_string(),
+                            context,
                             spawner.clone(),
-                            reconciliation_intent.clone(),
                             move |error| {
                                 log::warning(format!(
                                     "Can't connect to {} due to error {:?}",
@@ -293,6 +513,7 @@ pub async fn communicate(
                                 ));
                                 log::ipc(format_struct(&Message::ReconcileFailure {
                                     in_reply_to: &operation_id2,
+                                    reason: &error.to_string(),
                                 }));
                             },
                             move || {
@@ -306,7 +527,19 @@ pub async fn communicate(
                     Operation::EstablishReverseConnection {
                         address,
                         operation_id,
+                        persistent,
                     } => {
+                        if persistent {
+                            establish_persistent_connection(
+                                address,
+                                operation_id,
+                                context.clone(),
+                                spawner.clone(),
+                                Direction::Reverse,
+                                &persistent_connections,
+                            );
+                            continue;
+                        }
                         let operation_id1 = std::rc::Rc::new(operation_id);
                         let operation_id2 = operation_id1.clone();
                         let operation_id3 = operation_id1.clone();
@@ -315,10 +548,9 @@ pub async fn communicate(
                         let socket_address3 = socket_address1.clone();
                         reverse_connect(
                             address,
-                            in_memory_tx.clone(),
-                            on_disk_tx.clone(),
+                            operation_id1.to_string(),
+                            context.clone(),
                             spawner.clone(),
-                            reconciliation_intent.clone(),
                             move |error| {
                                 log::warning(format!(
                                     "Can't connect to {} due to error {:?}",
@@ -335,6 +567,7 @@ pub async fn communicate(
                                 ));
                                 log::ipc(format_struct(&Message::ReconcileFailure {
                                     in_reply_to: &operation_id2,
+                                    reason: &error.to_string(),
                                 }));
                             },
                             move || {
@@ -357,6 +590,137 @@ pub async fn communicate(
                         }
                         log::ipc(format_struct(&Message::PendingProofOfWorkOperations(dump)));
                     }
+                    Operation::DumpPeerReputation => {
+                        let dump = peer_reputation
+                            .write()
+                            .await
+                            .dump(chrono::Utc::now().timestamp());
+                        log::ipc(format_struct(&Message::PeerReputation(dump)));
+                    }
+                    Operation::ClearPeerBan { peer } => {
+                        if !peer_reputation.write().await.clear(&peer) {
+                            log::warning(format!(
+                                "Peer {} has no reputation record. Offending command: {}",
+                                peer,
+                                line.trim()
+                            ));
+                        }
+                    }
+                    Operation::SetNoisePolicy { policy } => {
+                        context.noise_policy.set(policy);
+                    }
+                    Operation::AddAllowedPeer { public_key } => {
+                        if allowlist.write().await.add(public_key.clone()) {
+                            add_allowed_peer(&on_disk_tx, public_key).await;
+                        }
+                    }
+                    Operation::RemoveAllowedPeer { public_key } => {
+                        if allowlist.write().await.remove(&public_key) {
+                            remove_allowed_peer(&on_disk_tx, public_key).await;
+                        } else {
+                            log::warning(format!(
+                                "Peer isn't on the allowlist. Offending command: {}",
+                                line.trim()
+                            ));
+                        }
+                    }
+                    Operation::ListAllowedPeers => {
+                        let allowlist = allowlist.read().await;
+                        log::ipc(format_struct(&Message::AllowedPeers {
+                            own_public_key: &context.noise_keypair.public,
+                            private_network: allowlist.private_network(),
+                            allowed: allowlist.list(),
+                        }));
+                    }
+                    Operation::CancelConnection { to_be_cancelled } => {
+                        match persistent_connections.borrow_mut().remove(&to_be_cancelled) {
+                            Some(cancel) => cancel.set(),
+                            None => log::warning(format!(
+                                "Persistent connection not found. Offending command: {}",
+                                line.trim()
+                            )),
+                        }
+                    }
+                    Operation::ListConnections => {
+                        let connections = context.connections.read().await.list();
+                        log::ipc(format_struct(&Message::Connections(connections)));
+                    }
+                    Operation::Disconnect { operation_id } => {
+                        if !context.connections.read().await.disconnect(&operation_id) {
+                            log::warning(format!(
+                                "Connection not found. Offending command: {}",
+                                line.trim()
+                            ));
+                        }
+                    }
+                    Operation::DumpCompressionStatistics => {
+                        log::ipc(format_struct(&Message::CompressionStatistics(
+                            context.compression_statistics.snapshot(),
+                        )));
+                    }
+                    Operation::ListKnownPeers => {
+                        log::ipc(format_struct(&Message::KnownPeers(
+                            get_known_peers(&on_disk_tx).await,
+                        )));
+                    }
+                    Operation::PruneKnownPeers {
+                        max_failures,
+                        max_age,
+                    } => {
+                        let seen_before = chrono::Utc::now().timestamp().saturating_sub(max_age);
+                        let deleted =
+                            prune_known_peers(&on_disk_tx, max_failures, seen_before).await;
+                        log::ipc(format_struct(&Message::KnownPeersPruned { deleted }));
+                    }
+                    Operation::ExportBundle { path, since } => {
+                        let context = context.clone();
+                        spawner
+                            .spawn_local_obj(
+                                Box::new(async move {
+                                    match bundle::export(&context, &path, since).await {
+                                        Ok(bundle) => {
+                                            log::ipc(format_struct(&Message::BundleExported {
+                                                path: &path,
+                                                count: bundle.messages.len(),
+                                                watermark: bundle.watermark,
+                                            }))
+                                        }
+                                        Err(error) => {
+                                            log::ipc(format_struct(&Message::BundleFailed {
+                                                path: &path,
+                                                reason: &error.to_string(),
+                                            }))
+                                        }
+                                    }
+                                })
+                                .into(),
+                            )
+                            .unwrap();
+                    }
+                    Operation::ImportBundle { path } => {
+                        let context = context.clone();
+                        spawner
+                            .spawn_local_obj(
+                                Box::new(async move {
+                                    match bundle::import(&context, &path).await {
+                                        Ok(summary) => {
+                                            log::ipc(format_struct(&Message::BundleImported {
+                                                path: &path,
+                                                summary,
+                                            }))
+                                        }
+                                        Err(error) => {
+                                            log::ipc(format_struct(&Message::BundleFailed {
+                                                path: &path,
+                                                reason: &error.to_string(),
+                                            }))
+                                        }
+                                    }
+                                })
+                                .into(),
+                            )
+                            .unwrap();
+                    }
                 }
             }
             Err(error) => {
@@ -0,0 +1,163 @@
+use crate::fragmentation::{self, Config, Fragmented, RealTime};
+use async_std::io;
+use async_std::net::{ToSocketAddrs, UdpSocket};
+use async_std::sync::{channel, Receiver, Sender};
+use async_std::task;
+use futures::future::{self, FutureExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Fits in the smallest MTU IPv6 allows, headers included.
+const MTU: usize = 1200;
+/// Further datagrams are dropped and resent later like lost ones.
+const QUEUE: usize = 256;
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+fn config() -> Config {
+    Config {
+        mtu: MTU,
+        ..Config::default()
+    }
+}
+
+pub struct Listener {
+    incoming: Receiver<(Fragmented, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl Listener {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+fn open(
+    socket: Arc<UdpSocket>,
+    peer: Option<SocketAddr>,
+) -> (Fragmented, Sender<Vec<u8>>, impl Future<Output = ()>) {
+    let (outgoing_tx, outgoing_rx) = channel::<Vec<u8>>(1);
+    let (incoming_tx, incoming_rx) = channel(QUEUE);
+    let (stream, driver) =
+        fragmentation::fragment(outgoing_tx, incoming_rx, config(), RealTime::default());
+    let send = async move {
+        while let Ok(datagram) = outgoing_rx.recv().await {
+            // Failed sends count as lost datagrams.
+            let _ = match peer {
+                Some(peer) => socket.send_to(&datagram, peer).await,
+                None => socket.send(&datagram).await,
+            };
+        }
+    };
+    (stream, incoming_tx, future::join(driver, send).map(drop))
+}
+
+pub async fn dial(address: impl ToSocketAddrs) -> io::Result<(Fragmented, SocketAddr)> {
+    let remote = match address.to_socket_addrs().await?.next() {
+        Some(remote) => remote,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The address didn't resolve",
+            ))
+        }
+    };
+    let local: SocketAddr = match remote {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = Arc::new(UdpSocket::bind(local).await?);
+    socket.connect(remote).await?;
+    let (stream, incoming_tx, driver) = open(socket.clone(), None);
+    let recv = async move {
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            // Errors are mostly ICMP reports of earlier datagrams.
+            if let Ok(length) = socket.recv(&mut buffer).await {
+                let _ = incoming_tx.try_send(buffer[..length].to_vec());
+            }
+        }
+    };
+    task::spawn(async move {
+        futures::pin_mut!(driver);
+        futures::pin_mut!(recv);
+        future::select(driver, recv).await;
+    });
+    Ok((stream, remote))
+}
+
+pub async fn listen(address: SocketAddr) -> io::Result<Listener> {
+    let socket = Arc::new(UdpSocket::bind(address).await?);
+    let local_addr = socket.local_addr()?;
+    let (incoming_tx, incoming) = channel(1);
+    task::spawn(demultiplex(socket, incoming_tx));
+    Ok(Listener {
+        incoming,
+        local_addr,
+    })
+}
+
+pub async fn accept(listener: &Listener) -> Option<(Fragmented, SocketAddr)> {
+    listener.incoming.recv().await.ok()
+}
+
+async fn demultiplex(socket: Arc<UdpSocket>, incoming: Sender<(Fragmented, SocketAddr)>) {
+    let peers: Arc<Mutex<HashMap<SocketAddr, Sender<Vec<u8>>>>> = Default::default();
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    loop {
+        let (length, address) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(_) => continue,
+        };
+        let datagram = buffer[..length].to_vec();
+        let known = peers.lock().unwrap().get(&address).cloned();
+        let peer_tx = match known {
+            Some(peer_tx) => peer_tx,
+            None if fragmentation::opens_stream(&datagram) => {
+                let (stream, peer_tx, driver) = open(socket.clone(), Some(address));
+                peers.lock().unwrap().insert(address, peer_tx.clone());
+                let peers = peers.clone();
+                task::spawn(async move {
+                    driver.await;
+                    peers.lock().unwrap().remove(&address);
+                });
+                incoming.send((stream, address)).await;
+                peer_tx
+            }
+            None => continue,
+        };
+        let _ = peer_tx.try_send(datagram);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn streams_run_over_udp() {
+        task::block_on(async {
+            let listener = listen("127.0.0.1:0".parse().unwrap()).await.unwrap();
+            let address = listener.local_addr();
+            let payload: Vec<u8> = (0..50_000).map(|index| index as u8).collect();
+            let expected = payload.clone();
+            let server = task::spawn(async move {
+                let (mut stream, _) = accept(&listener).await.unwrap();
+                let mut received = vec![0; expected.len()];
+                stream.read_exact(&mut received).await.unwrap();
+                assert_eq!(received, expected);
+                stream.write_all(b"done").await.unwrap();
+                stream.close().await.unwrap();
+            });
+            let (mut stream, remote) = dial(address).await.unwrap();
+            assert_eq!(remote, address);
+            stream.write_all(&payload).await.unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, b"done");
+            server.await;
+        });
+    }
+}
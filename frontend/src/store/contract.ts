@@ -20,14 +20,26 @@ import {
   BackendMessage,
   ProofOfWorkCancelled,
   ProofOfWorkCompleted,
+  SubmitRejected,
   ConnectionEstablishmentFailure,
   ReconcileFailure,
   ConnectionSevered,
+  ConnectionStateChanged,
   ServerListenAddress,
   ClientListenAddress,
   Backend,
   Operation,
   PendingProofOfWorkOperations,
+  PeerReputation,
+  AllowedPeers,
+  CompressionStatistics,
+  Connections,
+  PeerDiscovered,
+  KnownPeers,
+  KnownPeersPruned,
+  BundleExported,
+  BundleImported,
+  BundleFailed,
 } from "./rpc-schema";
 import * as t from "./typecheck";
 import { Buffer } from "buffer";
@@ -126,7 +138,7 @@ const contract = (callback: (event: Event) => void) => {
   const queue: AnswerRequest[] = [];
   const proofOfWorkResultRequests: Map<
     string,
-    (result: "cancelled" | "completed") => void
+    (result: "cancelled" | "completed" | "rejected") => void
   > = new Map();
 
   const parseFrontend = (object: any) => {
@@ -338,6 +350,13 @@ const contract = (callback: (event: Event) => void) => {
         };
       }
 
+      if (SubmitRejected(object)) {
+        return {
+          type: "SubmitRejected" as "SubmitRejected",
+          value: object as SubmitRejected,
+        };
+      }
+
       if (ConnectionEstablishmentFailure(object)) {
         return {
           type: "ConnectionEstablishmentFailure" as "ConnectionEstablishmentFailure",
@@ -359,6 +378,13 @@ const contract = (callback: (event: Event) => void) => {
         };
       }
 
+      if (ConnectionStateChanged(object)) {
+        return {
+          type: "ConnectionStateChanged" as "ConnectionStateChanged",
+          value: object as ConnectionStateChanged,
+        };
+      }
+
       if (ServerListenAddress(object)) {
         return {
           type: "ServerListenAddress" as "ServerListenAddress",
@@ -379,6 +405,76 @@ const contract = (callback: (event: Event) => void) => {
           value: object as PendingProofOfWorkOperations,
         };
       }
+
+      if (PeerReputation(object)) {
+        return {
+          type: "PeerReputation" as "PeerReputation",
+          value: object as PeerReputation,
+        };
+      }
+
+      if (AllowedPeers(object)) {
+        return {
+          type: "AllowedPeers" as "AllowedPeers",
+          value: object as AllowedPeers,
+        };
+      }
+
+      if (CompressionStatistics(object)) {
+        return {
+          type: "CompressionStatistics" as "CompressionStatistics",
+          value: object as CompressionStatistics,
+        };
+      }
+
+      if (Connections(object)) {
+        return {
+          type: "Connections" as "Connections",
+          value: object as Connections,
+        };
+      }
+
+      if (PeerDiscovered(object)) {
+        return {
+          type: "PeerDiscovered" as "PeerDiscovered",
+          value: object as PeerDiscovered,
+        };
+      }
+
+      if (KnownPeers(object)) {
+        return {
+          type: "KnownPeers" as "KnownPeers",
+          value: object as KnownPeers,
+        };
+      }
+
+      if (KnownPeersPruned(object)) {
+        return {
+          type: "KnownPeersPruned" as "KnownPeersPruned",
+          value: object as KnownPeersPruned,
+        };
+      }
+
+      if (BundleExported(object)) {
+        return {
+          type: "BundleExported" as "BundleExported",
+          value: object as BundleExported,
+        };
+      }
+
+      if (BundleImported(object)) {
+        return {
+          type: "BundleImported" as "BundleImported",
+          value: object as BundleImported,
+        };
+      }
+
+      if (BundleFailed(object)) {
+        return {
+          type: "BundleFailed" as "BundleFailed",
+          value: object as BundleFailed,
+        };
+      }
     };
 
     const marshalled = marshal(object);
@@ -412,6 +508,18 @@ const contract = (callback: (event: Event) => void) => {
       }
     }
 
+    if (marshalled.type === "SubmitRejected") {
+      const message = marshalled.value;
+      const request = proofOfWorkResultRequests.get(
+        message.SubmitRejected.in_reply_to
+      );
+      if (request === undefined) {
+        console.log(new Error("This should be unreachable."));
+      } else {
+        request("rejected");
+      }
+    }
+
     if (marshalled.type === "PendingProofOfWorkOperations") {
       const message = marshalled.value;
       const request = pendingOperationsRequestQueue.shift();
@@ -534,7 +642,7 @@ const contract = (callback: (event: Event) => void) => {
     });
 
   const waitOnOperation = (operationId: string) =>
-    new Promise<"completed" | "cancelled">((resolve) => {
+    new Promise<"completed" | "cancelled" | "rejected">((resolve) => {
       proofOfWorkResultRequests.set(operationId, resolve);
     });
 
@@ -554,7 +662,7 @@ const contract = (callback: (event: Event) => void) => {
         },
       })
     );
-    const promise = new Promise<"completed" | "cancelled">((resolve) =>
+    const promise = new Promise<"completed" | "cancelled" | "rejected">((resolve) =>
       proofOfWorkResultRequests.set(operationId, resolve)
     );
 
@@ -275,7 +275,11 @@ const methods = (() => {
             }
 
             methods.waitOnOperation(operation.operationId).then(
-              action((_outcome) => {
+              action((outcome) => {
+                if (outcome === "rejected") {
+                  console.log(new Error("The backend rejected the message."));
+                }
+
                 if (operation.description.type === "renew inbox") {
                   inbox.renewOperationCount--;
                 } else if (operation.description.type === "setup inbox") {
@@ -709,7 +713,11 @@ export const publishPublicHalfEntry = (
       }
 
       promise.then(
-        action((_outcome) => {
+        action((outcome) => {
+          if (outcome === "rejected") {
+            console.log(new Error("The backend rejected the message."));
+          }
+
           if (setupOrRenew === "setup inbox") {
             inbox.setupOperationCount--;
           } else if (setupOrRenew === "renew inbox") {
@@ -898,7 +906,11 @@ export const sendMessage = (
         inbox.sendOperationCount++;
 
         promise.then(
-          action((_outcome) => {
+          action((outcome) => {
+            if (outcome === "rejected") {
+              console.log(new Error("The backend rejected the message."));
+            }
+
             inbox.sendOperationCount--;
 
             inbox.pendingOperations.delete(operationId);
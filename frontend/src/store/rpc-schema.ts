@@ -522,10 +522,37 @@ export type CancelSubmitOperation = {
   };
 };
 
+export const PushOrder = t.refinement(t.String, (string) =>
+  [
+    "InsertionOrder",
+    "NewestFirst",
+    "SoonestExpiringFirst",
+    "SmallestFirst",
+    "HighestProofOfWorkSurplusFirst",
+  ].includes(string)
+);
+
+export type PushOrder =
+  | "InsertionOrder"
+  | "NewestFirst"
+  | "SoonestExpiringFirst"
+  | "SmallestFirst"
+  | "HighestProofOfWorkSurplusFirst";
+
+export const NoisePolicy = t.refinement(t.String, (string) =>
+  ["Disabled", "Enabled", "Required"].includes(string)
+);
+
+export type NoisePolicy = "Disabled" | "Enabled" | "Required";
+
 export const EstablishConnection = t.struct({
   EstablishConnection: t.struct({
     address: t.String,
     operation_id: t.String,
+    push_order: t.maybe(PushOrder),
+    noise: t.maybe(NoisePolicy),
+    socks5_proxy: t.maybe(t.String),
+    persistent: t.Boolean,
   }),
 });
 
@@ -533,6 +560,10 @@ export type EstablishConnection = {
   EstablishConnection: {
     address: string;
     operation_id: string;
+    push_order: PushOrder | null;
+    noise: NoisePolicy | null;
+    socks5_proxy: string | null;
+    persistent: boolean;
   };
 };
 
@@ -540,6 +571,7 @@ export const EstablishReverseConnection = t.struct({
   EstablishReverseConnection: t.struct({
     address: t.String,
     operation_id: t.String,
+    persistent: t.Boolean,
   }),
 });
 
@@ -547,6 +579,19 @@ export type EstablishReverseConnection = {
   EstablishReverseConnection: {
     address: string;
     operation_id: string;
+    persistent: boolean;
+  };
+};
+
+export const CancelConnection = t.struct({
+  CancelConnection: t.struct({
+    to_be_cancelled: t.String,
+  }),
+});
+
+export type CancelConnection = {
+  CancelConnection: {
+    to_be_cancelled: string;
   };
 };
 
@@ -557,13 +602,162 @@ export const DumpPendingProofOfWorkOperations = t.refinement(
 
 export type DumpPendingProofOfWorkOperations = "DumpPendingProofOfWorkOperations";
 
+export const DumpPeerReputation = t.refinement(
+  t.String,
+  (string) => string === "DumpPeerReputation"
+);
+
+export type DumpPeerReputation = "DumpPeerReputation";
+
+export const ClearPeerBan = t.struct({
+  ClearPeerBan: t.struct({
+    peer: t.String,
+  }),
+});
+
+export type ClearPeerBan = {
+  ClearPeerBan: {
+    peer: string;
+  };
+};
+
+export const SetNoisePolicy = t.struct({
+  SetNoisePolicy: t.struct({
+    policy: NoisePolicy,
+  }),
+});
+
+export type SetNoisePolicy = {
+  SetNoisePolicy: {
+    policy: NoisePolicy;
+  };
+};
+
+export const AddAllowedPeer = t.struct({
+  AddAllowedPeer: t.struct({
+    public_key: t.list(t.Number),
+  }),
+});
+
+export type AddAllowedPeer = {
+  AddAllowedPeer: {
+    public_key: number[];
+  };
+};
+
+export const RemoveAllowedPeer = t.struct({
+  RemoveAllowedPeer: t.struct({
+    public_key: t.list(t.Number),
+  }),
+});
+
+export type RemoveAllowedPeer = {
+  RemoveAllowedPeer: {
+    public_key: number[];
+  };
+};
+
+export const ListAllowedPeers = t.refinement(
+  t.String,
+  (string) => string === "ListAllowedPeers"
+);
+
+export type ListAllowedPeers = "ListAllowedPeers";
+
+export const DumpCompressionStatistics = t.refinement(
+  t.String,
+  (string) => string === "DumpCompressionStatistics"
+);
+
+export type DumpCompressionStatistics = "DumpCompressionStatistics";
+
+export const ListConnections = t.refinement(
+  t.String,
+  (string) => string === "ListConnections"
+);
+
+export type ListConnections = "ListConnections";
+
+export const Disconnect = t.struct({
+  Disconnect: t.struct({
+    operation_id: t.String,
+  }),
+});
+
+export type Disconnect = {
+  Disconnect: {
+    operation_id: string;
+  };
+};
+
+export const ListKnownPeers = t.refinement(
+  t.String,
+  (string) => string === "ListKnownPeers"
+);
+
+export type ListKnownPeers = "ListKnownPeers";
+
+export const PruneKnownPeers = t.struct({
+  PruneKnownPeers: t.struct({
+    max_failures: t.Number,
+    max_age: t.Number,
+  }),
+});
+
+export type PruneKnownPeers = {
+  PruneKnownPeers: {
+    max_failures: number;
+    max_age: number;
+  };
+};
+
+export const ExportBundle = t.struct({
+  ExportBundle: t.struct({
+    path: t.String,
+    since: t.Number,
+  }),
+});
+
+export type ExportBundle = {
+  ExportBundle: {
+    path: string;
+    since: number;
+  };
+};
+
+export const ImportBundle = t.struct({
+  ImportBundle: t.struct({
+    path: t.String,
+  }),
+});
+
+export type ImportBundle = {
+  ImportBundle: {
+    path: string;
+  };
+};
+
 export const Operation = t.union([
   Submit,
   Query,
   CancelSubmitOperation,
   EstablishConnection,
   EstablishReverseConnection,
+  CancelConnection,
   DumpPendingProofOfWorkOperations,
+  DumpPeerReputation,
+  ClearPeerBan,
+  SetNoisePolicy,
+  AddAllowedPeer,
+  RemoveAllowedPeer,
+  ListAllowedPeers,
+  DumpCompressionStatistics,
+  ListConnections,
+  Disconnect,
+  ListKnownPeers,
+  PruneKnownPeers,
+  ExportBundle,
+  ImportBundle,
 ]);
 
 export type Operation =
@@ -572,7 +766,21 @@ export type Operation =
   | CancelSubmitOperation
   | EstablishConnection
   | EstablishReverseConnection
-  | DumpPendingProofOfWorkOperations;
+  | CancelConnection
+  | DumpPendingProofOfWorkOperations
+  | DumpPeerReputation
+  | ClearPeerBan
+  | SetNoisePolicy
+  | AddAllowedPeer
+  | RemoveAllowedPeer
+  | ListAllowedPeers
+  | DumpCompressionStatistics
+  | ListConnections
+  | Disconnect
+  | ListKnownPeers
+  | PruneKnownPeers
+  | ExportBundle
+  | ImportBundle;
 
 export const Inventory = t.struct({
   Inventory: t.list(t.list(t.Number)),
@@ -634,6 +842,39 @@ export type ProofOfWorkCompleted = {
   };
 };
 
+export const Violation = t.union([
+  t.struct({
+    PayloadTooLarge: t.struct({ size: t.Number, limit: t.Number }),
+  }),
+  t.struct({
+    TimeToLiveTooLong: t.struct({ time_to_live: t.Number, limit: t.Number }),
+  }),
+  t.struct({
+    TimeToLiveTooShort: t.struct({ time_to_live: t.Number, limit: t.Number }),
+  }),
+  t.refinement(t.String, (string) => string === "QuotaExceeded"),
+]);
+
+export type Violation =
+  | { PayloadTooLarge: { size: number; limit: number } }
+  | { TimeToLiveTooLong: { time_to_live: number; limit: number } }
+  | { TimeToLiveTooShort: { time_to_live: number; limit: number } }
+  | "QuotaExceeded";
+
+export const SubmitRejected = t.struct({
+  SubmitRejected: t.struct({
+    in_reply_to: t.String,
+    reason: Violation,
+  }),
+});
+
+export type SubmitRejected = {
+  SubmitRejected: {
+    in_reply_to: string;
+    reason: Violation;
+  };
+};
+
 export const ConnectionEstablishmentFailure = t.struct({
   ConnectionEstablishmentFailure: t.struct({
     in_reply_to: t.String,
@@ -649,12 +890,14 @@ export type ConnectionEstablishmentFailure = {
 export const ReconcileFailure = t.struct({
   ReconcileFailure: t.struct({
     in_reply_to: t.String,
+    reason: t.String,
   }),
 });
 
 export type ReconcileFailure = {
   ReconcileFailure: {
     in_reply_to: string;
+    reason: string;
   };
 };
 
@@ -670,6 +913,36 @@ export type ConnectionSevered = {
   };
 };
 
+export const ConnectionState = t.union([
+  t.struct({ Connecting: t.struct({ attempt: t.Number }) }),
+  t.refinement(t.String, (string) =>
+    ["Connected", "Cancelled"].includes(string)
+  ),
+  t.struct({ Disconnected: t.struct({ reason: t.String }) }),
+  t.struct({ Reconnecting: t.struct({ delay_milliseconds: t.Number }) }),
+]);
+
+export type ConnectionState =
+  | { Connecting: { attempt: number } }
+  | "Connected"
+  | { Disconnected: { reason: string } }
+  | { Reconnecting: { delay_milliseconds: number } }
+  | "Cancelled";
+
+export const ConnectionStateChanged = t.struct({
+  ConnectionStateChanged: t.struct({
+    in_reply_to: t.String,
+    state: ConnectionState,
+  }),
+});
+
+export type ConnectionStateChanged = {
+  ConnectionStateChanged: {
+    in_reply_to: string;
+    state: ConnectionState;
+  };
+};
+
 export const ServerListenAddress = t.struct({
   ServerListenAddress: t.struct({
     address: t.String,
@@ -710,17 +983,241 @@ export type PendingProofOfWorkOperations = {
   PendingProofOfWorkOperations: ProofOfWorkOperation[];
 };
 
+export const PeerReputationEntry = t.struct({
+  peer: t.String,
+  score: t.Number,
+  invalid_proof_of_work: t.Number,
+  malformed_message: t.Number,
+  expired_message: t.Number,
+  policy_violation: t.Number,
+  banned_until: t.maybe(t.Number),
+});
+
+export type PeerReputationEntry = {
+  peer: string;
+  score: number;
+  invalid_proof_of_work: number;
+  malformed_message: number;
+  expired_message: number;
+  policy_violation: number;
+  banned_until: number | null;
+};
+
+export const PeerReputation = t.struct({
+  PeerReputation: t.list(PeerReputationEntry),
+});
+
+export type PeerReputation = {
+  PeerReputation: PeerReputationEntry[];
+};
+
+export const AllowedPeers = t.struct({
+  AllowedPeers: t.struct({
+    own_public_key: t.list(t.Number),
+    private_network: t.Boolean,
+    allowed: t.list(t.list(t.Number)),
+  }),
+});
+
+export type AllowedPeers = {
+  AllowedPeers: {
+    own_public_key: number[];
+    private_network: boolean;
+    allowed: number[][];
+  };
+};
+
+export const CompressionStatistics = t.struct({
+  CompressionStatistics: t.struct({
+    links: t.Number,
+    uncompressed_sent: t.Number,
+    compressed_sent: t.Number,
+    uncompressed_received: t.Number,
+    compressed_received: t.Number,
+  }),
+});
+
+export type CompressionStatistics = {
+  CompressionStatistics: {
+    links: number;
+    uncompressed_sent: number;
+    compressed_sent: number;
+    uncompressed_received: number;
+    compressed_received: number;
+  };
+};
+
+export const Role = t.refinement(t.String, (string) =>
+  ["Client", "Server"].includes(string)
+);
+
+export type Role = "Client" | "Server";
+
+export const ConnectionInfo = t.struct({
+  id: t.String,
+  address: t.String,
+  inbound: t.Boolean,
+  role: Role,
+  started_at: t.Number,
+  node_id: t.list(t.Number),
+  bytes_sent: t.Number,
+  bytes_received: t.Number,
+  messages_sent: t.Number,
+  messages_received: t.Number,
+});
+
+export type ConnectionInfo = {
+  id: string;
+  address: string;
+  inbound: boolean;
+  role: Role;
+  started_at: number;
+  node_id: number[];
+  bytes_sent: number;
+  bytes_received: number;
+  messages_sent: number;
+  messages_received: number;
+};
+
+export const Connections = t.struct({
+  Connections: t.list(ConnectionInfo),
+});
+
+export type Connections = {
+  Connections: ConnectionInfo[];
+};
+
+export const PeerDiscovered = t.struct({
+  PeerDiscovered: t.struct({
+    node_id: t.list(t.Number),
+    address: t.maybe(t.String),
+    public_key: t.list(t.Number),
+  }),
+});
+
+export type PeerDiscovered = {
+  PeerDiscovered: {
+    node_id: number[];
+    address: string | null;
+    public_key: number[];
+  };
+};
+
+export const KnownPeer = t.struct({
+  address: t.String,
+  last_seen: t.Number,
+  failures: t.Number,
+});
+
+export type KnownPeer = {
+  address: string;
+  last_seen: number;
+  failures: number;
+};
+
+export const KnownPeers = t.struct({
+  KnownPeers: t.list(KnownPeer),
+});
+
+export type KnownPeers = {
+  KnownPeers: KnownPeer[];
+};
+
+export const KnownPeersPruned = t.struct({
+  KnownPeersPruned: t.struct({
+    deleted: t.Number,
+  }),
+});
+
+export type KnownPeersPruned = {
+  KnownPeersPruned: {
+    deleted: number;
+  };
+};
+
+export const BundleExported = t.struct({
+  BundleExported: t.struct({
+    path: t.String,
+    count: t.Number,
+    watermark: t.Number,
+  }),
+});
+
+export type BundleExported = {
+  BundleExported: {
+    path: string;
+    count: number;
+    watermark: number;
+  };
+};
+
+export const ImportSummary = t.struct({
+  imported: t.Number,
+  duplicate: t.Number,
+  expired: t.Number,
+  rejected: t.Number,
+  refused: t.Number,
+});
+
+export type ImportSummary = {
+  imported: number;
+  duplicate: number;
+  expired: number;
+  rejected: number;
+  refused: number;
+};
+
+export const BundleImported = t.struct({
+  BundleImported: t.struct({
+    path: t.String,
+    summary: ImportSummary,
+  }),
+});
+
+export type BundleImported = {
+  BundleImported: {
+    path: string;
+    summary: ImportSummary;
+  };
+};
+
+export const BundleFailed = t.struct({
+  BundleFailed: t.struct({
+    path: t.String,
+    reason: t.String,
+  }),
+});
+
+export type BundleFailed = {
+  BundleFailed: {
+    path: string;
+    reason: string;
+  };
+};
+
 export const Backend = t.union([
   Inventory,
   BackendMessage,
   ProofOfWorkCancelled,
   ProofOfWorkCompleted,
+  SubmitRejected,
   ConnectionEstablishmentFailure,
   ReconcileFailure,
   ConnectionSevered,
+  ConnectionStateChanged,
   ServerListenAddress,
   ClientListenAddress,
   PendingProofOfWorkOperations,
+  PeerReputation,
+  AllowedPeers,
+  CompressionStatistics,
+  Connections,
+  PeerDiscovered,
+  KnownPeers,
+  KnownPeersPruned,
+  BundleExported,
+  BundleImported,
+  BundleFailed,
 ]);
 
 export type Backend =
@@ -728,9 +1225,21 @@ export type Backend =
   | BackendMessage
   | ProofOfWorkCancelled
   | ProofOfWorkCompleted
+  | SubmitRejected
   | ConnectionEstablishmentFailure
   | ReconcileFailure
   | ConnectionSevered
+  | ConnectionStateChanged
   | ServerListenAddress
   | ClientListenAddress
-  | PendingProofOfWorkOperations;
+  | PendingProofOfWorkOperations
+  | PeerReputation
+  | AllowedPeers
+  | CompressionStatistics
+  | Connections
+  | PeerDiscovered
+  | KnownPeers
+  | KnownPeersPruned
+  | BundleExported
+  | BundleImported
+  | BundleFailed;
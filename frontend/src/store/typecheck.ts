@@ -10,6 +10,8 @@ export const list = (predicate: Predicate) => (value: any) =>
 
 export const String = (value: any) => typeof value === "string";
 
+export const Boolean = (value: any) => typeof value === "boolean";
+
 export const maybe = (predicate: Predicate) => (value: any) =>
   value === null || predicate(value);
 